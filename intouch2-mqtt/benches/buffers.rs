@@ -0,0 +1,80 @@
+//! Compare [SyncBuffers] shared by several threads to [Buffers] used by a single task.
+
+#![feature(test)]
+
+extern crate test;
+
+use std::thread;
+
+use intouch2_mqtt::{Buffers, SyncBuffers};
+use test::Bencher;
+
+const THREADS: usize = 4;
+const ROUNDS: usize = 1000;
+
+fn use_buffer(buf: &mut Vec<u8>) {
+    buf.push(1);
+    test::black_box(&buf);
+    buf.clear();
+}
+
+#[bench]
+fn buffers(b: &mut Bencher) {
+    let mut buffers: Buffers<4, Vec<u8>> = Buffers::new();
+    b.iter(|| {
+        for _ in 0..ROUNDS {
+            let mut buf = buffers.take_or(|| Vec::with_capacity(16));
+            use_buffer(&mut buf);
+            buffers.release(buf);
+        }
+    });
+}
+
+#[bench]
+fn sync_buffers(b: &mut Bencher) {
+    let buffers: SyncBuffers<4, Vec<u8>> = SyncBuffers::new();
+    b.iter(|| {
+        for _ in 0..ROUNDS {
+            let mut buf = buffers.take_or(|| Vec::with_capacity(16));
+            use_buffer(&mut buf);
+            buffers.release(buf);
+        }
+    });
+}
+
+/// Every thread has its own [Buffers], which is the baseline for [sync_buffers_contended].
+#[bench]
+fn buffers_per_thread(b: &mut Bencher) {
+    b.iter(|| {
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    let mut buffers: Buffers<4, Vec<u8>> = Buffers::new();
+                    for _ in 0..ROUNDS {
+                        let mut buf = buffers.take_or(|| Vec::with_capacity(16));
+                        use_buffer(&mut buf);
+                        buffers.release(buf);
+                    }
+                });
+            }
+        })
+    });
+}
+
+#[bench]
+fn sync_buffers_contended(b: &mut Bencher) {
+    let buffers: SyncBuffers<4, Vec<u8>> = SyncBuffers::new();
+    b.iter(|| {
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..ROUNDS {
+                        let mut buf = buffers.take_or(|| Vec::with_capacity(16));
+                        use_buffer(&mut buf);
+                        buffers.release(buf);
+                    }
+                });
+            }
+        })
+    });
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponentially growing delays between reconnect attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+    current: Option<Duration>,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, multiplier: f64) -> Self {
+        Self {
+            initial,
+            max,
+            multiplier,
+            jitter: 0.0,
+            current: None,
+        }
+    }
+
+    /// Randomly move each delay by up to `jitter` times the delay, in either direction. This
+    /// keeps clients which lost their connection at the same time from retrying in lockstep.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The time to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = match self.current {
+            None => self.initial,
+            Some(previous) => previous.mul_f64(self.multiplier),
+        }
+        .min(self.max);
+        self.current = Some(delay);
+        if self.jitter > 0.0 {
+            let factor = rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+            delay.mul_f64(1.0 + factor).min(self.max)
+        } else {
+            delay
+        }
+    }
+
+    /// Start over from the initial delay, typically after a successful connection.
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 2.0);
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let mut backoff =
+            Backoff::new(Duration::from_secs(4), Duration::from_secs(6), 1.0).with_jitter(0.25);
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(3), "{delay:?} is too short");
+            assert!(delay <= Duration::from_secs(5), "{delay:?} is too long");
+        }
+    }
+}
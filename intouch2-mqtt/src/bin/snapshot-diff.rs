@@ -0,0 +1,36 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+use intouch2::datas::Snapshot;
+
+/// Print the addresses which differ between two memory snapshots, as written by the
+/// `--memory-snapshot-file` option of the bridge.
+#[derive(Parser)]
+struct Command {
+    /// The snapshot taken before the change.
+    before: PathBuf,
+    /// The snapshot taken after the change.
+    after: PathBuf,
+}
+
+fn load(path: &PathBuf) -> anyhow::Result<Snapshot> {
+    let file = std::fs::read(path).with_context(|| path.display().to_string())?;
+    serde_json::from_slice(&file).with_context(|| path.display().to_string())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Command::parse();
+    let before = load(&args.before)?;
+    let after = load(&args.after)?;
+    let show = |value: Option<u8>| value.map_or_else(|| "-".to_string(), |x| x.to_string());
+    for difference in before.diff(&after) {
+        println!(
+            "{}: {} -> {}",
+            difference.pos,
+            show(difference.old),
+            show(difference.new)
+        );
+    }
+    Ok(())
+}
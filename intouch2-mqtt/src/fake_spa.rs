@@ -0,0 +1,200 @@
+//! A scripted spa for tests, which answers a [SpaConnection](crate::spa::SpaConnection) through a
+//! [SpaPipe] like the port forwarder would.
+
+use std::{borrow::Cow, ops::Range, sync::Arc, time::Duration};
+
+use intouch2::object::{package_data, NetworkPackage, NetworkPackageData, StatusChange};
+use tokio::{
+    sync::Mutex,
+    task::{self, JoinHandle},
+    time,
+};
+
+use crate::{
+    port_forward::{FullPackagePipe, SpaPipe},
+    spa::{SpaConnection, SpaError, SpaIntervals},
+};
+
+pub struct FakeSpa {
+    pub id: &'static [u8],
+    pub name: &'static [u8],
+    pub version: package_data::Version,
+    /// The spa memory, which is served in chunks of at most `chunk_size` bytes.
+    pub memory: Arc<Mutex<Box<[u8]>>>,
+    pub chunk_size: u8,
+    /// The reply to `GetWatercare`.
+    pub watercare_mode: u8,
+    /// The reply to `RequestWatercare`.
+    pub watercare_schedules: Box<[u8]>,
+    /// The reply to `GetChannel`.
+    pub channel: u8,
+    pub signal_strength: u8,
+    /// Answer `SetStatus` with a `PushStatus` of the written memory, two bytes per change, like
+    /// a spa which pushes changes to its clients.
+    pub push_changes: bool,
+    /// Send a `PACKS` after every `Pong`, like a spa which uses it as a keepalive.
+    pub packs_on_ping: bool,
+    /// The sequence number of every `PushStatusAck` received.
+    pub push_status_acks: Arc<Mutex<Vec<u8>>>,
+    /// Every range requested with `RequestStatus`, in the order they were requested.
+    pub status_requests: Arc<Mutex<Vec<Range<usize>>>>,
+}
+
+impl FakeSpa {
+    pub fn new(memory: impl Into<Box<[u8]>>) -> Self {
+        Self {
+            id: b"SPA00:00:00:00:00:00",
+            name: b"Fake spa",
+            version: package_data::Version {
+                en_build: 1,
+                en_major: 2,
+                en_minor: 3,
+                co_build: 4,
+                co_major: 5,
+                co_minor: 6,
+            },
+            memory: Arc::new(Mutex::new(memory.into())),
+            chunk_size: 128,
+            watercare_mode: 0,
+            watercare_schedules: Box::from([]),
+            channel: 0,
+            signal_strength: 0,
+            push_changes: false,
+            packs_on_ping: false,
+            push_status_acks: Default::default(),
+            status_requests: Default::default(),
+        }
+    }
+
+    /// Answer the packages sent through the returned pipe, until the pipe is dropped.
+    pub fn spawn(self) -> (SpaPipe, JoinHandle<()>) {
+        let FullPackagePipe {
+            forwarder: mut pipe,
+            spa,
+        } = FullPackagePipe::new();
+        let job = task::spawn(async move {
+            while let Some(package) = pipe.rx.recv().await {
+                for answer in self.answer(package).await {
+                    // Nobody may be listening yet, just like on the network.
+                    let _ = pipe.tx.send(answer);
+                }
+            }
+        });
+        (spa, job)
+    }
+
+    /// Connect to this spa with the default intervals. The connection isn't initialized, so that
+    /// it can be subscribed to first.
+    pub async fn connect(self) -> Result<(SpaConnection, JoinHandle<()>), SpaError> {
+        let memory_size = self.memory.lock().await.len();
+        let (pipe, job) = self.spawn();
+        let spa = SpaConnection::new(memory_size, pipe, SpaIntervals::default(), &[]).await?;
+        Ok((spa, job))
+    }
+
+    /// Like [Self::connect], but also waits until the spa memory has been downloaded.
+    pub async fn connect_ready(self) -> anyhow::Result<(SpaConnection, JoinHandle<()>)> {
+        let (mut spa, job) = self.connect().await?;
+        spa.init().await?;
+        time::timeout(Duration::from_secs(5), spa.wait_for_valid_data()).await??;
+        Ok((spa, job))
+    }
+
+    async fn answer(&self, package: NetworkPackage<'static>) -> Vec<NetworkPackage<'static>> {
+        let (client, data) = match package {
+            NetworkPackage::Hello(hello) if *hello == *b"1" => {
+                let mut hello = self.id.to_vec();
+                hello.push(b'|');
+                hello.extend_from_slice(self.name);
+                return vec![NetworkPackage::Hello(hello.into())];
+            }
+            NetworkPackage::Hello(_) => return vec![],
+            NetworkPackage::Addressed { src, data, .. } => (src, data),
+        };
+        let answers = match data {
+            NetworkPackageData::GetVersion(_) => vec![self.version.clone().into()],
+            NetworkPackageData::Ping if self.packs_on_ping => {
+                vec![NetworkPackageData::Pong, NetworkPackageData::Packs]
+            }
+            NetworkPackageData::Ping => vec![NetworkPackageData::Pong],
+            NetworkPackageData::PushStatusAck(package_data::PushStatusAck { seq }) => {
+                self.push_status_acks.lock().await.push(seq);
+                vec![]
+            }
+            NetworkPackageData::GetWatercare(_) => vec![package_data::WatercareGet {
+                mode: self.watercare_mode,
+            }
+            .into()],
+            NetworkPackageData::GetChannel(_) => vec![package_data::ChannelCurrent {
+                channel: self.channel,
+                signal_strength: self.signal_strength,
+            }
+            .into()],
+            NetworkPackageData::SetStatus(package_data::SetStatus { pos, data, .. }) => {
+                let mut memory = self.memory.lock().await;
+                let pos = usize::from(pos);
+                if let Some(target) = memory.get_mut(pos..pos + data.len()) {
+                    target.copy_from_slice(&data);
+                }
+                if !self.push_changes {
+                    return vec![];
+                }
+                let changes: Vec<_> = (pos..pos + data.len())
+                    .step_by(2)
+                    .filter_map(|change| {
+                        Some(StatusChange {
+                            change: change.try_into().ok()?,
+                            data: Cow::Owned(memory.get(change..change + 2)?.try_into().ok()?),
+                        })
+                    })
+                    .collect();
+                vec![package_data::PushStatus {
+                    length: changes.len() as u8,
+                    changes: changes.into(),
+                }
+                .into()]
+            }
+            NetworkPackageData::RequestWatercare(_) => vec![package_data::WatercareRequest(
+                Cow::Owned(self.watercare_schedules.clone().into()),
+            )
+            .into()],
+            NetworkPackageData::RequestStatus(package_data::RequestStatus {
+                start,
+                length,
+                ..
+            }) => {
+                let memory = self.memory.lock().await;
+                let start = usize::from(start);
+                self.status_requests
+                    .lock()
+                    .await
+                    .push(start..start + usize::from(length));
+                let Some(requested) = memory.get(start..start + usize::from(length)) else {
+                    return vec![];
+                };
+                requested
+                    .chunks(self.chunk_size.into())
+                    .zip(0u8..)
+                    .map(|(chunk, seq)| {
+                        package_data::Status {
+                            seq,
+                            next: seq + 1,
+                            length: chunk.len() as u8,
+                            data: Cow::Owned(chunk.into()),
+                        }
+                        .into()
+                    })
+                    .collect()
+            }
+            _ => vec![],
+        };
+        answers
+            .into_iter()
+            .map(|data| NetworkPackage::Addressed {
+                src: Some(Cow::Borrowed(self.id)),
+                dst: client.clone(),
+                data,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,161 @@
+use std::{convert::Infallible, fmt::Write as _, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::watch,
+    time::{self, Duration, Instant},
+};
+
+/// How often a connection was lost and found again, and why it was last lost.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct LinkStats {
+    /// The number of times the connection came back after being lost.
+    pub reconnects: u32,
+    /// The error which last interrupted the connection.
+    pub last_error: Option<Arc<str>>,
+}
+
+impl LinkStats {
+    pub fn record_error(&mut self, error: impl std::fmt::Display) {
+        self.last_error = Some(error.to_string().into());
+    }
+}
+
+/// The combined state of the spa and MQTT connections.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HealthState {
+    /// Whether the spa answers pings.
+    pub spa_connected: bool,
+    /// Whether the spa memory has been downloaded since the spa connected.
+    pub state_valid: bool,
+    /// When the spa memory was last updated with valid data.
+    pub last_valid_data: Option<Instant>,
+    /// Whether the MQTT session is connected to the broker.
+    pub mqtt_connected: bool,
+    /// When a publish was last acknowledged by the broker.
+    pub last_publish: Option<Instant>,
+}
+
+impl HealthState {
+    /// Both connections are up, and the spa memory has been downloaded.
+    pub fn is_healthy(&self) -> bool {
+        self.spa_connected && self.state_valid && self.mqtt_connected
+    }
+
+    fn describe(&self) -> String {
+        fn since(instant: Option<Instant>) -> String {
+            match instant {
+                Some(instant) => format!("{}s ago", instant.elapsed().as_secs()),
+                None => "never".into(),
+            }
+        }
+        let mut description = String::new();
+        let _ = writeln!(description, "spa_connected: {}", self.spa_connected);
+        let _ = writeln!(description, "state_valid: {}", self.state_valid);
+        let _ = writeln!(
+            description,
+            "last_valid_data: {}",
+            since(self.last_valid_data)
+        );
+        let _ = writeln!(description, "mqtt_connected: {}", self.mqtt_connected);
+        let _ = writeln!(description, "last_publish: {}", since(self.last_publish));
+        description
+    }
+}
+
+/// Keep `health` up to date with the state of the spa and MQTT connections. This only returns if
+/// one of the connections is dropped.
+pub async fn track(
+    health: watch::Sender<HealthState>,
+    mut spa_connected: watch::Receiver<bool>,
+    mut state_valid: watch::Receiver<bool>,
+    mut last_valid_data: watch::Receiver<Option<Instant>>,
+    mut mqtt_connected: watch::Receiver<bool>,
+    mut last_publish: watch::Receiver<Option<Instant>>,
+) -> Result<Infallible, watch::error::RecvError> {
+    loop {
+        health.send_if_modified(|state| {
+            let new_state = HealthState {
+                spa_connected: *spa_connected.borrow_and_update(),
+                state_valid: *state_valid.borrow_and_update(),
+                last_valid_data: *last_valid_data.borrow_and_update(),
+                mqtt_connected: *mqtt_connected.borrow_and_update(),
+                last_publish: *last_publish.borrow_and_update(),
+            };
+            if *state != new_state {
+                *state = new_state;
+                true
+            } else {
+                false
+            }
+        });
+        select! {
+            result = spa_connected.changed() => result?,
+            result = state_valid.changed() => result?,
+            result = last_valid_data.changed() => result?,
+            result = mqtt_connected.changed() => result?,
+            result = last_publish.changed() => result?,
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, state: HealthState) -> std::io::Result<()> {
+    // The request itself doesn't matter, but it is read so that the client doesn't get a reset
+    // connection before it has sent it.
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request).await?;
+    let status = if state.is_healthy() {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let body = state.describe();
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answer HTTP requests on `listen_addr` with 200 when `health` is healthy, and 503 otherwise.
+pub async fn serve(
+    listen_addr: SocketAddr,
+    health: watch::Receiver<HealthState>,
+) -> std::io::Result<Infallible> {
+    let listener = TcpListener::bind(listen_addr).await?;
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = health.borrow().clone();
+        if let Err(e) = time::timeout(Duration::from_secs(1), respond(stream, state))
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+        {
+            eprintln!("Health check from {peer} failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn healthy_requires_both_connections() {
+        let mut state = HealthState {
+            spa_connected: true,
+            state_valid: true,
+            last_valid_data: Some(Instant::now()),
+            mqtt_connected: true,
+            last_publish: None,
+        };
+        assert!(state.is_healthy());
+        state.mqtt_connected = false;
+        assert!(!state.is_healthy());
+        state.mqtt_connected = true;
+        state.state_valid = false;
+        assert!(!state.is_healthy());
+    }
+}
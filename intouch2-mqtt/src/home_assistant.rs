@@ -10,12 +10,37 @@ pub struct ConfigureDevice {
     pub extra_args: HashMap<&'static str, serde_json::Value>,
 }
 
+/// Tells Home Assistant which integration created the entity.
+#[derive(serde::Serialize)]
+pub struct ConfigureOrigin {
+    pub name: &'static str,
+    pub sw_version: &'static str,
+    pub support_url: &'static str,
+}
+
+impl ConfigureOrigin {
+    pub const THIS: Self = Self {
+        name: env!("CARGO_PKG_NAME"),
+        sw_version: env!("CARGO_PKG_VERSION"),
+        support_url: env!("CARGO_PKG_REPOSITORY"),
+    };
+}
+
 #[derive(serde::Serialize)]
 pub struct ConfigureBase<'a> {
     pub name: &'a str,
     pub unique_id: &'a str,
     pub device: &'a ConfigureDevice,
+    pub origin: &'a ConfigureOrigin,
     pub qos: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<&'a str>,
+    /// Set to `diagnostic` or `config` to categorize the entity in Home Assistant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<&'a str>,
+    /// Seconds without a state update before Home Assistant marks the entity unavailable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire_after: Option<u32>,
 }
 
 #[derive(serde::Serialize)]
@@ -74,6 +99,53 @@ pub struct ConfigureClimate<'a> {
     pub optimistic: bool,
 }
 
+/// A read only on/off state, such as a running heater or a fault.
+#[derive(serde::Serialize)]
+pub struct ConfigureBinarySensor<'a> {
+    #[serde(flatten)]
+    pub base: ConfigureBase<'a>,
+    pub state_topic: &'a str,
+    /// Home Assistant uses `ON` when this isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_on: Option<&'a str>,
+    /// Home Assistant uses `OFF` when this isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_off: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<&'a str>,
+}
+
+/// A spa cover or cover lift, which is opened, closed and stopped with fixed payloads.
+#[derive(serde::Serialize)]
+pub struct ConfigureCover<'a> {
+    #[serde(flatten)]
+    pub base: ConfigureBase<'a>,
+    pub command_topic: &'a str,
+    /// Home Assistant uses `OPEN` when this isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_open: Option<&'a str>,
+    /// Home Assistant uses `CLOSE` when this isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_close: Option<&'a str>,
+    /// Home Assistant uses `STOP` when this isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_stop: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_topic: Option<&'a str>,
+    /// Home Assistant uses `open` when this isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_open: Option<&'a str>,
+    /// Home Assistant uses `closed` when this isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_closed: Option<&'a str>,
+    /// A topic with the position from 0 (closed) to 100 (open), for lifts which report it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<&'a str>,
+    pub optimistic: bool,
+}
+
 #[derive(serde::Serialize)]
 pub struct ConfigureSelect<'a> {
     #[serde(flatten)]
@@ -84,3 +156,101 @@ pub struct ConfigureSelect<'a> {
     pub options: Vec<&'a str>,
     pub optimistic: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn binary_sensor() -> anyhow::Result<()> {
+        let device = ConfigureDevice {
+            identifiers: Box::from([Arc::from("spa0001")]),
+            name: Arc::from("Spa"),
+            sw_version: None,
+            extra_args: Default::default(),
+        };
+        let base = || ConfigureBase {
+            name: "Heater",
+            unique_id: "heater0001",
+            device: &device,
+            origin: &ConfigureOrigin::THIS,
+            qos: 0,
+            icon: None,
+            entity_category: None,
+            expire_after: None,
+        };
+        let sensor = ConfigureBinarySensor {
+            base: base(),
+            state_topic: "spa/binary_sensor/heater0001/1/state",
+            payload_on: Some("1"),
+            payload_off: Some("0"),
+            device_class: Some("running"),
+        };
+        let serialized = serde_json::to_value(&sensor)?;
+        assert_eq!(
+            serialized["state_topic"],
+            "spa/binary_sensor/heater0001/1/state"
+        );
+        assert_eq!(serialized["payload_on"], "1");
+        assert_eq!(serialized["payload_off"], "0");
+        assert_eq!(serialized["device_class"], "running");
+        assert_eq!(serialized["unique_id"], "heater0001");
+        assert_eq!(serialized["device"]["identifiers"], json!(["spa0001"]));
+
+        let defaults = ConfigureBinarySensor {
+            base: base(),
+            state_topic: "spa/binary_sensor/heater0001/1/state",
+            payload_on: None,
+            payload_off: None,
+            device_class: None,
+        };
+        let serialized = serde_json::to_value(&defaults)?;
+        for key in ["payload_on", "payload_off", "device_class", "icon"] {
+            assert!(serialized.get(key).is_none(), "{key} should be left out");
+        }
+        Ok(())
+    }
+    #[test]
+    fn cover() -> anyhow::Result<()> {
+        let device = ConfigureDevice {
+            identifiers: Box::from([Arc::from("spa0001")]),
+            name: Arc::from("Spa"),
+            sw_version: None,
+            extra_args: Default::default(),
+        };
+        let cover = ConfigureCover {
+            base: ConfigureBase {
+                name: "Cover",
+                unique_id: "cover0001",
+                device: &device,
+                origin: &ConfigureOrigin::THIS,
+                qos: 0,
+                icon: None,
+                entity_category: None,
+                expire_after: None,
+            },
+            command_topic: "spa/cover/cover0001/1/set",
+            payload_open: None,
+            payload_close: None,
+            payload_stop: Some("HALT"),
+            state_topic: Some("spa/cover/cover0001/2/state"),
+            state_open: Some("1"),
+            state_closed: Some("0"),
+            position_topic: None,
+            device_class: Some("shade"),
+            optimistic: false,
+        };
+        let serialized = serde_json::to_value(&cover)?;
+        assert_eq!(serialized["command_topic"], "spa/cover/cover0001/1/set");
+        assert_eq!(serialized["payload_stop"], "HALT");
+        assert_eq!(serialized["state_topic"], "spa/cover/cover0001/2/state");
+        assert_eq!(serialized["state_open"], "1");
+        assert_eq!(serialized["state_closed"], "0");
+        assert_eq!(serialized["optimistic"], false);
+        for key in ["payload_open", "payload_close", "position_topic"] {
+            assert!(serialized.get(key).is_none(), "{key} should be left out");
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,22 @@
 #![feature(sync_unsafe_cell)]
 
+pub mod backoff;
+#[cfg(test)]
+mod fake_spa;
+pub mod health;
 pub mod home_assistant;
 pub mod mapping;
 pub mod mqtt_session;
 pub mod port_forward;
 pub mod port_forward_mapping;
 pub mod spa;
+pub mod tasks;
 
 use std::{
     mem::MaybeUninit,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     ops::Deref,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 pub trait WithBuffer {
@@ -75,16 +80,20 @@ impl<const COUNT: usize, T> Buffers<COUNT, T> {
         }
     }
 
-    pub fn take_or(&mut self, create: impl FnOnce() -> T) -> T {
+    fn take(&mut self) -> Option<T> {
         if self.size == 0 {
-            (create)()
+            None
         } else {
             self.size -= 1;
             let buf = std::mem::replace(&mut self.bufs[self.size], MaybeUninit::uninit());
-            unsafe { buf.assume_init() }
+            Some(unsafe { buf.assume_init() })
         }
     }
 
+    pub fn take_or(&mut self, create: impl FnOnce() -> T) -> T {
+        self.take().unwrap_or_else(create)
+    }
+
     pub fn release(&mut self, buf: T) {
         if self.size != COUNT {
             self.bufs[self.size] = MaybeUninit::new(buf);
@@ -98,3 +107,73 @@ impl<const COUNT: usize, T: Default> Buffers<COUNT, T> {
         self.take_or(Default::default)
     }
 }
+
+/// [Buffers] which can be shared between tasks. The buffers are kept behind a [Mutex], which is
+/// only held while a buffer is taken or released. `benches/buffers.rs` compares it to [Buffers].
+pub struct SyncBuffers<const COUNT: usize, T> {
+    inner: Mutex<Buffers<COUNT, T>>,
+}
+
+impl<const COUNT: usize, T> SyncBuffers<COUNT, T> {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Buffers::new()),
+        }
+    }
+
+    pub fn take_or(&self, create: impl FnOnce() -> T) -> T {
+        let buf = self
+            .inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        buf.unwrap_or_else(create)
+    }
+
+    pub fn release(&self, buf: T) {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .release(buf)
+    }
+}
+
+impl<const COUNT: usize, T> Default for SyncBuffers<COUNT, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const COUNT: usize, T: Default> SyncBuffers<COUNT, T> {
+    pub fn get(&self) -> T {
+        self.take_or(Default::default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_buffers_are_shared() {
+        let buffers: Arc<SyncBuffers<4, Vec<u8>>> = Arc::new(SyncBuffers::new());
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let buffers = buffers.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let mut buf = buffers.take_or(|| Vec::with_capacity(16));
+                        assert!(buf.is_empty());
+                        buf.push(1);
+                        buf.clear();
+                        buffers.release(buf);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(buffers.get().capacity(), 16);
+    }
+}
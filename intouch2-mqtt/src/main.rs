@@ -1,21 +1,22 @@
 use anyhow::Context;
 use clap::Parser;
-use intouch2::object::{package_data, NetworkPackageData};
+use intouch2::object::{package_data, NetworkPackageType};
 use intouch2_mqtt::{
+    health::{self, HealthState},
     home_assistant,
-    mapping::{self, Mapping},
-    mqtt_session::{MqttAuth, SessionBuilder as MqttSession},
-    port_forward::{FullPackagePipe, PortForwardBuilder, PortForwardError},
-    spa::{SpaConnection, SpaError},
+    mapping::{self, Mapping, MappingSupervisor},
+    mqtt_session::{AvailabilityPayloads, MqttAuth, SessionBuilder as MqttSession},
+    port_forward::{FullPackagePipe, PackageFilter, PortForwardBuilder, PortForwardError},
+    spa::{
+        address_name, SpaConnection, SpaConnectionBuilder, SpaError, SpaIntervals, TemperatureUnit,
+    },
 };
-use mqttrs::SubscribeTopic;
 use serde_json::json;
 use std::{
     borrow::Cow,
     collections::VecDeque,
-    net::IpAddr,
-    path::PathBuf,
-    pin::pin,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
     sync::{Arc, OnceLock},
     time::Duration,
 };
@@ -25,10 +26,174 @@ use serde::Deserialize;
 use tokio::{
     net::{self},
     select,
+    sync::watch,
     task::JoinSet,
     time::timeout,
 };
 
+/// One or more MQTT topics, separated by ','.
+#[derive(Debug, Clone)]
+struct Topics(Box<[Arc<str>]>);
+
+impl std::str::FromStr for Topics {
+    type Err = std::convert::Infallible;
+
+    fn from_str(topics: &str) -> Result<Self, Self::Err> {
+        Ok(Self(topics.split(',').map(Arc::from).collect()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Topics {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A range of the spa memory, written as "start..end" with the end excluded.
+#[derive(Debug, Clone)]
+struct MemoryRange(std::ops::Range<usize>);
+
+impl std::str::FromStr for MemoryRange {
+    type Err = &'static str;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        const INVALID: &str = "Expected a range such as \"256..258\"";
+        let (start, end) = range.split_once("..").ok_or(INVALID)?;
+        let start: u16 = start.trim().parse().map_err(|_| INVALID)?;
+        let end: u16 = end.trim().parse().map_err(|_| INVALID)?;
+        Ok(Self(start.into()..end.into()))
+    }
+}
+
+impl<'de> Deserialize<'de> for MemoryRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Memory changes in a range of the spa memory, which are published to their own topic. Written
+/// as "start..end=topic".
+#[derive(Debug, Clone)]
+struct MemoryChangesRoute {
+    range: std::ops::Range<usize>,
+    topic: Arc<str>,
+}
+
+impl std::str::FromStr for MemoryChangesRoute {
+    type Err = &'static str;
+
+    fn from_str(route: &str) -> Result<Self, Self::Err> {
+        const INVALID: &str = "Expected a route such as \"256..258=temperatures\"";
+        let (range, topic) = route.split_once('=').ok_or(INVALID)?;
+        let MemoryRange(range) = range.parse().map_err(|_| INVALID)?;
+        let topic = topic.trim();
+        if topic.is_empty() {
+            return Err(INVALID);
+        }
+        Ok(Self {
+            range,
+            topic: topic.into(),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for MemoryChangesRoute {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The topic of each of the first `len` positions of the spa memory. A position is published to
+/// the subtopic of the first route which contains it, or to `topic` itself.
+fn memory_change_topics(
+    topic: &Path,
+    routes: &[MemoryChangesRoute],
+    len: usize,
+) -> Box<[Arc<Path>]> {
+    let topic: Arc<Path> = topic.into();
+    let routes: Box<[(&MemoryChangesRoute, Arc<Path>)]> = routes
+        .iter()
+        .map(|route| (route, topic.join(&*route.topic).into()))
+        .collect();
+    (0..len)
+        .map(|position| {
+            routes
+                .iter()
+                .find(|(route, _)| route.range.contains(&position))
+                .map_or_else(|| topic.clone(), |(_, topic)| topic.clone())
+        })
+        .collect()
+}
+
+/// Group the changed bytes by the topic of their position, in the order the topics first appear.
+fn group_memory_changes<'a>(
+    differences: &[(usize, u8)],
+    topics: &'a [Arc<Path>],
+) -> Vec<(&'a Path, Vec<(usize, u8)>)> {
+    let mut groups: Vec<(&Path, Vec<(usize, u8)>)> = vec![];
+    for &(position, value) in differences {
+        let topic = &*topics[position];
+        match groups.iter_mut().find(|(group, _)| *group == topic) {
+            Some((_, changes)) => changes.push((position, value)),
+            None => groups.push((topic, vec![(position, value)])),
+        }
+    }
+    groups
+}
+
+impl std::ops::Deref for Topics {
+    type Target = [Arc<str>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// The topic a dumped package is published to. Packages with data are serialized as an object
+/// with the name of the package as its only key, and are published to a subtopic with that name.
+/// Packages without data are published to `topic` itself.
+fn package_dump_topic<'a>(topic: &'a Path, package: &serde_json::Value) -> Cow<'a, Path> {
+    match package {
+        serde_json::Value::Object(object) => match object.keys().collect::<Box<_>>()[..] {
+            [struct_name] => Cow::Owned(topic.join(struct_name)),
+            _ => Cow::Borrowed(topic),
+        },
+        _ => Cow::Borrowed(topic),
+    }
+}
+
+/// Lowercase hex, with two digits per byte.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The payload of a package dump or memory change.
+fn dump_payload(value: &serde_json::Value, pretty: bool) -> serde_json::Result<Vec<u8>> {
+    if pretty {
+        serde_json::to_vec_pretty(value)
+    } else {
+        serde_json::to_vec(value)
+    }
+}
+
+/// The changed bytes of one memory change as `{position_name: value}`.
+fn memory_changes_json(
+    differences: &[(usize, u8)],
+    position_names: &[String],
+) -> serde_json::Value {
+    differences
+        .iter()
+        .map(|(position, value)| (position_names[*position].clone(), json!(value)))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
 mod default_values {
     use super::*;
     pub fn spa_name() -> Arc<str> {
@@ -39,6 +204,14 @@ mod default_values {
         10022
     }
 
+    pub fn max_clients() -> usize {
+        10
+    }
+
+    pub fn package_dump_capacity() -> u16 {
+        10
+    }
+
     pub fn udp_timeout() -> u16 {
         300
     }
@@ -47,18 +220,42 @@ mod default_values {
         10
     }
 
-    pub fn discovery_topic() -> Arc<str> {
-        "homeassistant".into()
+    pub fn discovery_topic() -> Topics {
+        Topics(Box::new(["homeassistant".into()]))
     }
 
-    pub fn home_assistant_availability() -> Arc<str> {
-        "homeassistant/status".into()
+    pub fn home_assistant_availability() -> Topics {
+        Topics(Box::new(["homeassistant/status".into()]))
+    }
+
+    pub fn availability_qos() -> u8 {
+        1
+    }
+
+    pub fn payload_available() -> Arc<str> {
+        "online".into()
+    }
+
+    pub fn payload_not_available() -> Arc<str> {
+        "offline".into()
     }
 
     pub fn base_topic() -> Arc<str> {
         "intouch2".into()
     }
 
+    pub fn full_state_interval() -> u32 {
+        1800
+    }
+
+    pub fn watercare_interval() -> u32 {
+        1800
+    }
+
+    pub fn channel_interval() -> u32 {
+        300
+    }
+
     pub fn r#false() -> bool {
         false
     }
@@ -101,8 +298,8 @@ impl<T: Deserialize<'static>> JsonValue<T> {
 #[serde(deny_unknown_fields)]
 struct Command {
     /// The IP and Port of the Spa system.
-    #[arg(long)]
-    spa_target: Arc<str>,
+    #[arg(long, required_unless_present = "list_known_data")]
+    spa_target: Option<Arc<str>>,
 
     /// The name which should be used for the spa in MQTT commands
     #[serde(default = "default_values::spa_name")]
@@ -119,10 +316,45 @@ struct Command {
     #[arg(default_value = "300")]
     spa_udp_timeout: u16,
 
+    /// Timeout before a forwarded client is dropped after it stops sending. Defaults to
+    /// spa_udp_timeout.
+    #[arg(long)]
+    #[serde(default)]
+    spa_client_timeout: Option<u16>,
+
+    /// Timeout before a forwarded client is dropped after the Spa stops replying to it. Defaults
+    /// to spa_udp_timeout.
+    #[arg(long)]
+    #[serde(default)]
+    spa_reply_timeout: Option<u16>,
+
     /// Timeout for the first Hello packet to the Spa.
     #[serde(default = "default_values::handshake_timeout")]
     #[arg(default_value = "10", alias = "handshake-timeout")]
     spa_handshake_timeout: u16,
+
+    /// Seconds between downloads of the full spa memory. Set to 0 to only download it when the
+    /// spa connects, for spas which reliably push all changes.
+    #[serde(default = "default_values::full_state_interval")]
+    #[arg(long, default_value = "1800")]
+    full_state_interval: u32,
+
+    /// Seconds between requests for the current watercare mode.
+    #[serde(default = "default_values::watercare_interval")]
+    #[arg(long, default_value = "1800", value_parser = clap::value_parser!(u32).range(1..))]
+    watercare_interval: u32,
+
+    /// Seconds between requests for the radio channel and signal strength of the spa.
+    #[serde(default = "default_values::channel_interval")]
+    #[arg(long, default_value = "300", value_parser = clap::value_parser!(u32).range(1..))]
+    channel_interval: u32,
+
+    /// Ranges of the spa memory to download before the rest of it, such as "256..258", so that
+    /// the entities using them get their state sooner after startup.
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default)]
+    spa_priority_ranges: Vec<MemoryRange>,
+
     #[serde(default = "default_values::r#false")]
     #[arg(short, long)]
     verbose: bool,
@@ -141,6 +373,23 @@ struct Command {
     #[arg(default_value = "10022", alias = "forward-port")]
     spa_forward_listen_port: u16,
 
+    /// The local address used to talk to the Spa, such as "0.0.0.0:10022", for Spas or firewalls
+    /// which require a fixed source port. Defaults to an ephemeral port.
+    #[arg(long)]
+    #[serde(default)]
+    spa_source_addr: Option<SocketAddr>,
+
+    /// The maximum number of clients forwarded to the Spa at the same time.
+    #[serde(default = "default_values::max_clients")]
+    #[arg(long, default_value = "10")]
+    spa_forward_max_clients: usize,
+
+    /// Limit the traffic sent by the forward to this many bytes per second, to test how the Spa
+    /// behaves on a slow connection.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    #[serde(default)]
+    spa_forward_throttle: Option<u32>,
+
     /// The MQTT server address and port number
     #[arg(long)]
     mqtt_target: Option<Arc<str>>,
@@ -161,13 +410,17 @@ struct Command {
     )]
     mqtt_password: Option<Arc<str>>,
 
+    /// The discovery prefixes of the Home Assistant instances, separated by ','. Entity
+    /// configurations are published to each of them.
     #[serde(default = "default_values::discovery_topic")]
     #[arg(default_value = "homeassistant")]
-    mqtt_discovery_topic: Arc<str>,
+    mqtt_discovery_topic: Topics,
 
+    /// The status topics of the Home Assistant instances, separated by ','. The configuration is
+    /// sent again when any of them reports "online".
     #[serde(default = "default_values::home_assistant_availability")]
     #[arg(default_value = "homeassistant/status")]
-    mqtt_home_assistant_status_topic: Arc<str>,
+    mqtt_home_assistant_status_topic: Topics,
 
     #[serde(default = "default_values::base_topic")]
     #[arg(default_value = "intouch2")]
@@ -179,27 +432,154 @@ struct Command {
     #[serde(default)]
     mqtt_availability_topic: Option<Arc<str>>,
 
+    /// The QoS of the availability messages sent to mqtt_availability_topic.
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u8).range(0..=2))]
+    #[serde(default = "default_values::availability_qos")]
+    mqtt_availability_qos: u8,
+
+    /// The payload sent to mqtt_availability_topic when connected, which is also the payload Home
+    /// Assistant is expected to send to mqtt_home_assistant_status_topic when it comes online.
+    #[arg(long, default_value = "online")]
+    #[serde(default = "default_values::payload_available")]
+    mqtt_payload_available: Arc<str>,
+
+    /// The last will sent to mqtt_availability_topic when the connection is lost.
+    #[arg(long, default_value = "offline")]
+    #[serde(default = "default_values::payload_not_available")]
+    mqtt_payload_not_available: Arc<str>,
+
     /// The amount of time to sleep after sending configure packages before sending the state
     /// packages.
     #[arg(long, default_value = "1.0")]
     #[serde(default = "default_values::configure_sleep_duration")]
     sleep_after_mqtt_configuration: f32,
 
+    /// The MQTT QoS of the entity configurations sent to Home Assistant. Defaults to the QoS of
+    /// each entity.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=2))]
+    #[serde(default)]
+    mqtt_config_qos: Option<u8>,
+
+    /// Seconds between sending the entity configurations to Home Assistant again, in case it
+    /// missed them. They are otherwise only sent again when Home Assistant reports that it's
+    /// online.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    #[serde(default)]
+    mqtt_rediscovery_interval: Option<u32>,
+
     /// Set this to dump memory changes to the specified MQTT topic as
     /// "{mqtt_base_topic}/{package_dump_mqtt_topic}/{client_id}".
     #[arg(long)]
     package_dump_mqtt_topic: Option<Arc<str>>,
 
+    /// Only dump these package types to package_dump_mqtt_topic, such as "SetStatus" or
+    /// "PushStatus". All packages except pings are dumped if this is empty.
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default)]
+    package_dump_types: Vec<Arc<str>>,
+
+    /// Include the raw bytes of each package in package_dump_mqtt_topic as a hex string, next to
+    /// the decoded data.
+    #[serde(default = "default_values::r#false")]
+    #[arg(long)]
+    package_dump_raw: bool,
+
+    /// How many packages are buffered for package_dump_mqtt_topic while the MQTT connection is
+    /// busy. Packages beyond this are skipped and logged.
+    #[serde(default = "default_values::package_dump_capacity")]
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u16).range(1..))]
+    package_dump_capacity: u16,
+
+    /// Pretty print the JSON published to package_dump_mqtt_topic and memory_changes_mqtt_topic.
+    #[serde(default = "default_values::r#false")]
+    #[arg(long)]
+    dump_json_pretty: bool,
+
     /// Set this to dump memory changes to the specified MQTT topic as
-    /// "{mqtt_base_topic}/{memory_changes_mqtt_topic}/{changed_address}".
+    /// "{mqtt_base_topic}/{memory_changes_mqtt_topic}/{changed_address}", see also
+    /// memory_changes_routes.
     #[arg(long)]
     memory_changes_mqtt_topic: Option<Arc<str>>,
 
+    /// Name the memory_changes_mqtt_topic topics after the value at the changed address when its
+    /// position is known, such as "secondary_color_type" instead of "608".
+    #[serde(default = "default_values::r#false")]
+    #[arg(long)]
+    memory_changes_named: bool,
+
+    /// Publish all bytes changed at once as a single JSON object such as `{"608": 3, "609": 0}`
+    /// to "{mqtt_base_topic}/{memory_changes_mqtt_topic}", instead of one message per changed
+    /// byte. Changes in memory_changes_routes are published as one object per route.
+    #[serde(default = "default_values::r#false")]
+    #[arg(long)]
+    memory_changes_batched: bool,
+
+    /// Publish the memory_changes_mqtt_topic messages as retained, so that clients connecting
+    /// later receive the last value of each topic.
+    #[serde(default = "default_values::r#false")]
+    #[arg(long)]
+    memory_changes_retain: bool,
+
+    /// Publish the changes in some ranges of the spa memory to their own subtopics of
+    /// memory_changes_mqtt_topic, such as "256..258=temperatures". A change is published to the
+    /// first route containing it, and changes outside of all routes are published to
+    /// memory_changes_mqtt_topic itself.
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default)]
+    memory_changes_routes: Vec<MemoryChangesRoute>,
+
+    /// Write the spa memory to this file as JSON every time it changes. Copy the file before and
+    /// after changing a setting in the spa app, and compare the copies with `snapshot-diff` to
+    /// find where the setting is stored.
+    #[arg(long)]
+    #[serde(default)]
+    memory_snapshot_file: Option<PathBuf>,
+
+    /// The address of the temperature unit flag in the spa memory. Climate and temperature sensor
+    /// entities get their unit from this flag.
+    #[arg(long)]
+    #[serde(default)]
+    temperature_unit_addr: Option<u16>,
+
+    /// The temperature unit to use when temperature_unit_addr isn't set.
+    #[arg(long, value_enum)]
+    #[serde(default)]
+    temperature_unit: Option<TemperatureUnit>,
+
+    /// Log the commands from Home Assistant instead of sending them to the spa.
+    #[serde(default = "default_values::r#false")]
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Answer HTTP health checks on this address, such as "0.0.0.0:8080". The answer is 200 when
+    /// both the spa and MQTT are connected, and 503 otherwise.
+    #[arg(long)]
+    #[serde(default)]
+    health_listen: Option<SocketAddr>,
+
+    /// Print the values with a known position in the spa memory, as a starting point for
+    /// entities_json, and exit.
+    #[serde(default = "default_values::r#false")]
+    #[arg(long)]
+    list_known_data: bool,
+
     #[arg(skip)]
     #[serde(rename = "entities_json", default)]
     entities: Vec<JsonValue<mapping::GenericMapping>>,
+
+    /// Warn about unknown options in the config file instead of refusing to start. Command line
+    /// arguments are always strict.
+    // Only read from the raw config by [Command::from_config], this field lets it pass
+    // deny_unknown_fields.
+    #[allow(dead_code)]
+    #[arg(skip)]
+    #[serde(default)]
+    ignore_unknown_config: bool,
 }
 
+/// Options which can only be set in the config file, since they have no command line argument.
+const CONFIG_FILE_OPTIONS: &[&str] = &["entities_json", "ignore_unknown_config"];
+
 impl Command {
     fn get() -> &'static Command {
         static ARGS: OnceLock<Command> = OnceLock::new();
@@ -209,17 +589,11 @@ impl Command {
                 if let Ok(config_file) = std::fs::read(config_file) {
                     let loaded_config = Box::new(config_file);
                     let json = loaded_config.leak();
-                    match serde_json::from_slice::<Command>(json) {
+                    match Self::from_config(json) {
                         Ok(mut config) => {
                             return {
-                                for entity in config.entities.iter_mut() {
-                                    if let Err(err) = entity.leaking_parse() {
-                                        eprintln!("Could not parse entity json: {err}");
-                                        if let Some(cause) = err.source() {
-                                            eprintln!("{cause}");
-                                        }
-                                        std::process::exit(1);
-                                    }
+                                if !config.check_entities() {
+                                    std::process::exit(1);
                                 }
                                 config
                             }
@@ -234,6 +608,55 @@ impl Command {
             Command::parse()
         })
     }
+
+    /// Parse the config file. If it sets `ignore_unknown_config`, unknown options are reported
+    /// and skipped instead of failing the parse.
+    fn from_config(json: &'static [u8]) -> Result<Command, serde_json::Error> {
+        let mut options: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(json)?;
+        if options.get("ignore_unknown_config") != Some(&serde_json::Value::Bool(true)) {
+            return serde_json::from_slice(json);
+        }
+        let command = <Command as clap::CommandFactory>::command();
+        options.retain(|key, _| {
+            let known = CONFIG_FILE_OPTIONS.contains(&key.as_str())
+                || command
+                    .get_arguments()
+                    .any(|arg| arg.get_id() == key.as_str());
+            if !known {
+                eprintln!("Ignoring unknown config option {key}");
+            }
+            known
+        });
+        serde_json::from_value(serde_json::Value::Object(options))
+    }
+
+    /// Parse and validate all entities, and report every problem found. Returns false if any
+    /// entity is invalid.
+    fn check_entities(&mut self) -> bool {
+        let mut valid = true;
+        for (index, entity) in self.entities.iter_mut().enumerate() {
+            let JsonValue::Raw(raw_value) = &*entity else {
+                continue;
+            };
+            let name = serde_json::from_str::<serde_json::Value>(raw_value)
+                .ok()
+                .and_then(|value| value.get("name")?.as_str().map(String::from))
+                .unwrap_or_else(|| format!("#{index}"));
+            if let Err(err) = entity.leaking_parse() {
+                eprintln!("Could not parse entity {name}: {err}");
+                if let Some(cause) = err.source() {
+                    eprintln!("{cause}");
+                }
+                valid = false;
+                continue;
+            }
+            for error in entity.unwrap().validate(self.spa_memory_size) {
+                eprintln!("Invalid entity {name}: {error}");
+                valid = false;
+            }
+        }
+        valid
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -259,6 +682,19 @@ pub enum Error {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Command::get();
+    if args.list_known_data {
+        for known in intouch2::known_datas::known_datas() {
+            let position = known.position;
+            println!(
+                "{}: position {position} (0x{position:x}), length {}",
+                known.name, known.length
+            );
+        }
+        return Ok(());
+    }
+    let Some(spa_target) = &args.spa_target else {
+        return Err(Error::InvalidArguments("spa_target is required"))?;
+    };
     let mut mqtt = if let Some(target) = &args.mqtt_target {
         let mut mqtt_addrs = net::lookup_host(target.as_ref()).await?;
         let mqtt_addr = if let Some(addr) = mqtt_addrs.next() {
@@ -284,23 +720,36 @@ async fn main() -> anyhow::Result<()> {
         });
         let session = MqttSession {
             base_topic: args.mqtt_base_topic.clone(),
-            discovery_topic: args.mqtt_discovery_topic.clone(),
+            discovery_topics: args.mqtt_discovery_topic.iter().cloned().collect(),
             availability_topic: mqtt_availability,
+            availability_qos: match args.mqtt_availability_qos {
+                0 => mqttrs::QoS::AtMostOnce,
+                1 => mqttrs::QoS::AtLeastOnce,
+                _ => mqttrs::QoS::ExactlyOnce,
+            },
+            availability_payloads: AvailabilityPayloads {
+                available: args.mqtt_payload_available.clone(),
+                not_available: args.mqtt_payload_not_available.clone(),
+            },
             target: mqtt_addr,
             publish_retries: 30,
             publish_timeout: Duration::from_secs(5),
             auth,
             keep_alive: 30,
+            connect_timeout: Duration::from_secs(10),
+            clean_session: true,
+            publish_store: Default::default(),
+            state_cache: None,
         };
         Some(session.connect().await?)
     } else {
         None
     };
-    let mut spa_addrs = net::lookup_host(args.spa_target.as_ref()).await?;
+    let mut spa_addrs = net::lookup_host(spa_target.as_ref()).await?;
     let spa_addr = if let Some(addr) = spa_addrs.next() {
         Ok(addr)
     } else {
-        Err(Error::NoDnsMatch(args.spa_target.clone()))
+        Err(Error::NoDnsMatch(spa_target.clone()))
     }?;
     println!("Spa addr: {spa_addr}");
     let spa_pipe = FullPackagePipe::new();
@@ -311,10 +760,25 @@ async fn main() -> anyhow::Result<()> {
     let mut forward_builder = PortForwardBuilder {
         listen_addr: forward_addr,
         target_addr: spa_addr,
+        source_addr: args.spa_source_addr,
         handshake_timeout: Duration::from_secs(args.spa_handshake_timeout.into()),
-        udp_timeout: Duration::from_secs(args.spa_udp_timeout.into()),
+        client_timeout: Duration::from_secs(
+            args.spa_client_timeout
+                .unwrap_or(args.spa_udp_timeout)
+                .into(),
+        ),
+        reply_timeout: Duration::from_secs(
+            args.spa_reply_timeout
+                .unwrap_or(args.spa_udp_timeout)
+                .into(),
+        ),
+        max_clients: args.spa_forward_max_clients,
+        throttle: args.spa_forward_throttle,
         verbose: args.verbose,
         package_dump_pipe: None,
+        package_dump_filter: Default::default(),
+        package_dump_capacity: args.package_dump_capacity.into(),
+        unknown_package_pipe: None,
         dump_traffic: args.dump_traffic,
         local_connection: args.spa_memory_size.map(|_| spa_pipe.forwarder),
     };
@@ -332,15 +796,34 @@ async fn main() -> anyhow::Result<()> {
         (Some(mqtt), Some(dump_topic)) => {
             let mut mqtt_sender = mqtt.sender();
             let topic = PathBuf::from(args.mqtt_base_topic.as_ref()).join(dump_topic.as_ref());
+            let filter = if args.package_dump_types.is_empty() {
+                PackageFilter::Exclude(Box::from([
+                    NetworkPackageType::Ping,
+                    NetworkPackageType::Pong,
+                ]))
+            } else {
+                PackageFilter::Include(
+                    args.package_dump_types
+                        .iter()
+                        .map(|package_type| {
+                            package_type.parse().map_err(|_| {
+                                Error::InvalidArguments(
+                                    "Unknown package type in package_dump_types",
+                                )
+                            })
+                        })
+                        .collect::<Result<_, _>>()?,
+                )
+            };
+            forward_builder.package_dump_filter = filter;
             let mut package_pipe = forward_builder.dump_packages();
+            let include_raw = args.package_dump_raw;
             join_set.spawn(async move {
                 let mut recent_packages = VecDeque::with_capacity(10);
                 loop {
-                    let (direction, package) = package_pipe.recv().await?;
-                    match package {
-                        NetworkPackageData::Ping | NetworkPackageData::Pong => continue,
-                        _ => (),
-                    }
+                    let Some((direction, package)) = package_pipe.next().await else {
+                        Err(Error::PortForwardClosed)?
+                    };
                     if recent_packages.contains(&package) {
                         continue;
                     }
@@ -348,19 +831,13 @@ async fn main() -> anyhow::Result<()> {
                         recent_packages.pop_back();
                     }
                     let package_object = serde_json::to_value(&package)?;
-                    let topic = match &package_object {
-                        serde_json::Value::Object(object) => {
-                            match object.keys().collect::<Box<_>>()[..] {
-                                [struct_name] => Cow::Owned(topic.join(struct_name)),
-                                _ => Cow::Borrowed(&topic),
-                            }
-                        }
-                        _ => Cow::Borrowed(&topic),
-                    };
+                    let topic = package_dump_topic(&topic, &package_object);
                     let topic = topic.to_string_lossy();
-                    let key = serde_json::to_vec(
-                        &json!({ "direction": direction, "data": package_object }),
-                    )?;
+                    let mut dump = json!({ "direction": direction, "data": package_object });
+                    if include_raw {
+                        dump["raw"] = hex(&package.compose()).into();
+                    }
+                    let key = dump_payload(&dump, args.dump_json_pretty)?;
                     recent_packages.push_front(package);
                     let package = mqttrs::Packet::Publish(mqttrs::Publish {
                         dup: false,
@@ -382,14 +859,28 @@ async fn main() -> anyhow::Result<()> {
         Err(Error::PortForwardClosed)?
     });
     let mut spa = if let Some(memory_size) = args.spa_memory_size {
+        let builder = SpaConnectionBuilder {
+            memory_size,
+            intervals: SpaIntervals {
+                full_state_download: (args.full_state_interval != 0)
+                    .then(|| Duration::from_secs(args.full_state_interval.into())),
+                watercare: Duration::from_secs(args.watercare_interval.into()),
+                channel: Duration::from_secs(args.channel_interval.into()),
+            },
+            priority_ranges: args
+                .spa_priority_ranges
+                .iter()
+                .map(|MemoryRange(range)| range.clone())
+                .collect(),
+            temperature_unit_addr: args.temperature_unit_addr,
+            dry_run: args.dry_run,
+            verbose: args.verbose,
+        };
         join_set.spawn(async move {
             Ok(JoinResult::SpaConnected(
-                timeout(
-                    Duration::from_secs(5),
-                    SpaConnection::new(memory_size, spa_pipe.spa),
-                )
-                .await
-                .map_err(|_| Error::NoReplyFromSpa)??,
+                timeout(Duration::from_secs(5), builder.connect(spa_pipe.spa))
+                    .await
+                    .map_err(|_| Error::NoReplyFromSpa)??,
             ))
         });
         let Some(reply) = join_set.join_next().await else {
@@ -401,8 +892,33 @@ async fn main() -> anyhow::Result<()> {
     } else {
         None
     };
+    if args.health_listen.is_some() && (mqtt.is_none() || spa.is_none()) {
+        return Err(Error::InvalidArguments(
+            "health_listen requires both mqtt and spa_memory_size to be set",
+        ))?;
+    }
     match (mqtt, &mut spa, &args.memory_changes_mqtt_topic) {
         (Some(mut mqtt), Some(ref mut spa), memory_change_topic) => {
+            if let Some(listen_addr) = args.health_listen {
+                let (health_sender, health) = watch::channel(HealthState::default());
+                let spa_connected = spa.subscribe_connected();
+                let state_valid = spa.subscribe_state_valid();
+                let last_valid_data = spa.subscribe_last_update();
+                let mqtt_connected = mqtt.subscribe_connected();
+                let last_publish = mqtt.subscribe_last_publish();
+                join_set.spawn(async move {
+                    match health::track(
+                        health_sender,
+                        spa_connected,
+                        state_valid,
+                        last_valid_data,
+                        mqtt_connected,
+                        last_publish,
+                    )
+                    .await? {}
+                });
+                join_set.spawn(async move { match health::serve(listen_addr, health).await? {} });
+            }
             let (spa_name, spa_version) = {
                 let spa_name = String::from_utf8_lossy(spa.name()).to_string();
                 let spa_version = {
@@ -446,8 +962,20 @@ async fn main() -> anyhow::Result<()> {
                 let mut mqtt_sender = mqtt.sender();
                 let len = spa.len().await;
                 let mut spa_data = spa.subscribe(0..len).await;
-                let memory_change_topic =
-                    PathBuf::from(args.mqtt_base_topic.as_ref()).join(memory_change_topic.as_ref());
+                let memory_change_topics = memory_change_topics(
+                    &PathBuf::from(args.mqtt_base_topic.as_ref())
+                        .join(memory_change_topic.as_ref()),
+                    &args.memory_changes_routes,
+                    len,
+                );
+                let position_names: Box<[String]> = (0..len)
+                    .map(|position| {
+                        Some(position)
+                            .filter(|_| args.memory_changes_named)
+                            .and_then(address_name)
+                            .unwrap_or_else(|| format!("{position}"))
+                    })
+                    .collect();
                 join_set.spawn(async move {
                     let mut previous: Box<[u8]> = Box::from(spa_data.borrow_and_update().as_ref());
 
@@ -464,19 +992,41 @@ async fn main() -> anyhow::Result<()> {
                             }
                             previous = data.as_ref().into();
                         }
-                        for (position, value) in differences.iter() {
-                            let payload = format!("{value}");
-                            let topic_name = memory_change_topic.join(format!("{position}"));
-                            let package = mqttrs::Packet::Publish(mqttrs::Publish {
-                                dup: false,
-                                qospid: mqttrs::QosPid::AtMostOnce,
-                                retain: false,
-                                topic_name: topic_name
-                                    .to_str()
-                                    .expect("All paths will be valid UTF-8"),
-                                payload: payload.as_bytes(),
-                            });
-                            mqtt_sender.send(&package).await?;
+                        if args.memory_changes_batched {
+                            for (topic, changes) in
+                                group_memory_changes(&differences, &memory_change_topics)
+                            {
+                                let payload = dump_payload(
+                                    &memory_changes_json(&changes, &position_names),
+                                    args.dump_json_pretty,
+                                )?;
+                                let package = mqttrs::Packet::Publish(mqttrs::Publish {
+                                    dup: false,
+                                    qospid: mqttrs::QosPid::AtMostOnce,
+                                    retain: args.memory_changes_retain,
+                                    topic_name: topic
+                                        .to_str()
+                                        .expect("All paths will be valid UTF-8"),
+                                    payload: &payload,
+                                });
+                                mqtt_sender.send(&package).await?;
+                            }
+                        } else {
+                            for (position, value) in differences.iter() {
+                                let payload = format!("{value}");
+                                let topic_name = memory_change_topics[*position]
+                                    .join(&position_names[*position]);
+                                let package = mqttrs::Packet::Publish(mqttrs::Publish {
+                                    dup: false,
+                                    qospid: mqttrs::QosPid::AtMostOnce,
+                                    retain: args.memory_changes_retain,
+                                    topic_name: topic_name
+                                        .to_str()
+                                        .expect("All paths will be valid UTF-8"),
+                                    payload: payload.as_bytes(),
+                                });
+                                mqtt_sender.send(&package).await?;
+                            }
                         }
                         #[cfg(debug_assertions)]
                         if args.verbose {
@@ -495,72 +1045,31 @@ async fn main() -> anyhow::Result<()> {
                 sw_version: Some(spa_version.into()),
                 extra_args: Default::default(),
             })?;
+            mapping.set_default_temperature_unit(args.temperature_unit);
+            mapping.set_config_qos(args.mqtt_config_qos);
+            let supervisor = MappingSupervisor {
+                mapping,
+                entities: args
+                    .entities
+                    .iter()
+                    .map(|entity| entity.unwrap().clone())
+                    .collect(),
+                status_topics: args
+                    .mqtt_home_assistant_status_topic
+                    .iter()
+                    .cloned()
+                    .collect(),
+                online_payload: args.mqtt_payload_available.clone(),
+                configure_delay: Duration::from_secs_f32(args.sleep_after_mqtt_configuration),
+                rediscovery_interval: args
+                    .mqtt_rediscovery_interval
+                    .map(|interval| Duration::from_secs(interval.into())),
+                verbose: args.verbose,
+            };
             let spa = spa.clone();
             join_set.spawn(async move {
-                let mut mqtt_subscription = mqtt.subscribe();
-                mqtt.mqtt_subscribe(&vec![SubscribeTopic {
-                    topic_path: args.mqtt_home_assistant_status_topic.to_string(),
-                    qos: mqttrs::QoS::AtMostOnce,
-                }])
-                .await?;
-                'send_config: loop {
-                    if args.verbose {
-                        eprintln!("Configuring device mapping");
-                    }
-                    {
-                        for entity in &args.entities {
-                            mapping
-                                .add_generic(entity.unwrap().clone(), &*spa, &mut mqtt)
-                                .await?;
-                        }
-                    }
-                    let mut timeout = pin!(tokio::time::sleep_until(tokio::time::Instant::now() + Duration::from_secs_f32(args.sleep_after_mqtt_configuration)));
-                    loop {
-                        select! {
-                            _ = &mut timeout => {
-                                break
-                            }
-                            spa_result = spa.tick() => {
-                                let _: () = spa_result?;
-                            }
-                            mqtt_result = mqtt.tick() => {
-                                let _: () = mqtt_result?;
-                            }
-                        }
-                    }
-                    if args.verbose {
-                        eprintln!("Waiting for all states to be sent before notifying online");
-                    }
-                    mapping.start(&mut mqtt).await?;
-                    if args.verbose {
-                        eprintln!("Notifying online");
-                    }
-                    mqtt.notify_online().await?;
-                    loop {
-                        select! {
-                            mapping_result = mapping.tick() => {
-                                let _: () = mapping_result?;
-                            }
-                            mqtt_result = mqtt.tick() => {
-                                let _: () = mqtt_result?;
-                            }
-                            mqtt_package = mqtt_subscription.recv() => {
-                                match mqtt_package?.packet() {
-                                    mqttrs::Packet::Publish(mqttrs::Publish { dup: false, topic_name, payload, .. })
-                                        if *topic_name == args.mqtt_home_assistant_status_topic.as_ref() && payload == b"online" => {
-                                            if args.verbose {
-                                                eprintln!("Got online from home assistant. Restarting mapping.");
-                                            }
-                                            mapping.reset().await;
-                                            continue 'send_config;
-                                    }
-                                    _ => (),
-
-                                }
-                            }
-                        }
-                    }
-                }
+                let never = supervisor.run(&spa, &mut mqtt).await?;
+                match never {}
             });
         }
         (None, _, Some(_)) | (_, None, Some(_)) => {
@@ -571,6 +1080,18 @@ async fn main() -> anyhow::Result<()> {
         (_, _, None) => (),
     }
     if let Some(spa) = spa {
+        if let Some(snapshot_file) = args.memory_snapshot_file.clone() {
+            let spa = spa.clone();
+            join_set.spawn(async move {
+                spa.on_ready().await?;
+                let mut memory = spa.subscribe(0..spa.len().await).await;
+                loop {
+                    let snapshot = serde_json::to_vec(&spa.snapshot().await)?;
+                    tokio::fs::write(&snapshot_file, snapshot).await?;
+                    memory.changed().await?;
+                }
+            });
+        }
         join_set.spawn(async move {
             loop {
                 spa.tick().await?;
@@ -582,3 +1103,118 @@ async fn main() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use intouch2::object::{package_data, NetworkPackageData};
+
+    use super::*;
+
+    #[test]
+    fn memory_changes_routes() -> anyhow::Result<()> {
+        let routes: Vec<MemoryChangesRoute> = ["1..3=first", "2..5=second"]
+            .iter()
+            .map(|route| route.parse())
+            .collect::<Result<_, _>>()
+            .map_err(anyhow::Error::msg)?;
+        assert!("1..3".parse::<MemoryChangesRoute>().is_err());
+        assert!("1..3=".parse::<MemoryChangesRoute>().is_err());
+        let topics = memory_change_topics(Path::new("intouch2/changes"), &routes, 6);
+        let topics: Vec<_> = topics.iter().map(|topic| topic.to_str().unwrap()).collect();
+        assert_eq!(
+            topics,
+            [
+                "intouch2/changes",
+                "intouch2/changes/first",
+                "intouch2/changes/first",
+                "intouch2/changes/second",
+                "intouch2/changes/second",
+                "intouch2/changes",
+            ]
+        );
+
+        let topics = memory_change_topics(Path::new("changes"), &routes, 6);
+        let groups = group_memory_changes(&[(0, 1), (2, 2), (3, 3), (4, 4), (5, 5)], &topics);
+        assert_eq!(
+            groups,
+            [
+                (Path::new("changes"), vec![(0, 1), (5, 5)]),
+                (Path::new("changes/first"), vec![(2, 2)]),
+                (Path::new("changes/second"), vec![(3, 3), (4, 4)]),
+            ]
+        );
+        assert!(group_memory_changes(&[], &topics).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn topics_from_options() -> anyhow::Result<()> {
+        let Topics(topics) = serde_json::from_str(r#""homeassistant,other/homeassistant""#)?;
+        assert_eq!(
+            &*topics,
+            [Arc::from("homeassistant"), "other/homeassistant".into()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pretty_dump_payload() -> anyhow::Result<()> {
+        let value = json!({"direction": "From", "data": "Ping"});
+        let compact = dump_payload(&value, false)?;
+        let pretty = dump_payload(&value, true)?;
+        assert!(!compact.contains(&b'\n'));
+        assert!(pretty.contains(&b'\n'));
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&compact)?,
+            serde_json::from_slice::<serde_json::Value>(&pretty)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn batched_memory_changes() {
+        let names = ["0".into(), "primary_color".into(), "2".into()];
+        assert_eq!(
+            memory_changes_json(&[(0, 5), (1, 255)], &names),
+            json!({"0": 5, "primary_color": 255}),
+        );
+        assert_eq!(memory_changes_json(&[], &names), json!({}));
+    }
+
+    #[test]
+    fn raw_package_hex() {
+        let package =
+            NetworkPackageData::from(package_data::Unknown(Cow::Borrowed(b"NEW\x00\xff")));
+        assert_eq!(hex(&package.compose()), "4e455700ff");
+    }
+
+    #[test]
+    fn package_dump_topics() -> anyhow::Result<()> {
+        let topic = Path::new("intouch2/packages");
+        for (package, expected) in [
+            (NetworkPackageData::Ping, "intouch2/packages"),
+            (
+                package_data::SetStatus::new(1, 2, 3, 4, 5, 6, Cow::Borrowed(&[7]))?.into(),
+                "intouch2/packages/SetStatus",
+            ),
+            (
+                package_data::Version {
+                    en_build: 1,
+                    en_major: 2,
+                    en_minor: 3,
+                    co_build: 4,
+                    co_major: 5,
+                    co_minor: 6,
+                }
+                .into(),
+                "intouch2/packages/Version",
+            ),
+        ] {
+            let package = serde_json::to_value(&package)?;
+            assert_eq!(package_dump_topic(topic, &package), Path::new(expected));
+        }
+        Ok(())
+    }
+}
@@ -1,24 +1,31 @@
 use std::{
     collections::HashMap,
+    fmt::Write,
     future::Future,
     mem,
+    num::NonZeroU32,
     path::Path,
     pin::{pin, Pin},
     sync::Arc,
 };
 
-use mqttrs::{Packet, Publish, QoS, QosPid, SubscribeTopic};
+use intouch2::{
+    known_datas::{KeyCode, KnownData, PrimaryColor, SecondaryColor, SecondaryColorType},
+    object::{package_data, DEFAULT_SET_STATUS_FRAMING},
+};
+use mqttrs::{Packet, Pid, Publish, QoS, QosPid, SubscribeTopic};
 use serde::Deserialize;
 use tokio::{
     select,
     sync::{self, mpsc, watch, Mutex, OwnedMutexGuard},
-    task::JoinSet,
 };
 
 use crate::{
+    health::LinkStats,
     home_assistant,
-    mqtt_session::{MqttError, Session as MqttSession, Topic},
-    spa::{SpaCommand, SpaConnection, SpaError},
+    mqtt_session::{MqttError, Session as MqttSession, Topic, TopicGenerator},
+    spa::{SpaCommand, SpaConnection, SpaError, TemperatureUnit, WatercareState},
+    tasks::SupervisedTasks,
 };
 
 #[derive(Deserialize)]
@@ -87,15 +94,30 @@ pub enum MappingError {
 
 pub struct Mapping {
     device: home_assistant::ConfigureDevice,
-    jobs: JoinSet<Result<(), MappingError>>,
+    jobs: SupervisedTasks<(), MappingError>,
     uninitialized: Vec<Arc<Mutex<()>>>,
     active: sync::watch::Sender<bool>,
+    default_temperature_unit: Option<TemperatureUnit>,
+    topics: Option<TopicGenerator>,
+    config_qos: Option<u8>,
 }
 
 #[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq, serde::Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum SpecialMode<T> {
     WatercareMode,
+    /// The firmware versions of the spa, from [SpaConnection::version_json]. This is meant for
+    /// `json_attributes_topic`.
+    Version,
+    /// The reconnect count and last error of the spa connection, from
+    /// [SpaConnection::subscribe_link_stats].
+    SpaLink,
+    /// The radio channel of the spa, with the raw signal strength as well as the signal quality
+    /// in percent.
+    Radio,
+    /// The active watercare mode together with the rules of every watercare mode, from
+    /// [SpaConnection::subscribe_watercare_state]. This is meant for `json_attributes_topic`.
+    WatercareState,
     #[serde(untagged)]
     Multiple(Box<[T]>),
 }
@@ -103,9 +125,38 @@ pub enum SpecialMode<T> {
 #[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq, serde::Serialize)]
 #[serde(untagged)]
 pub enum MappingType {
-    U8 { u8_addr: u16 },
-    U16 { u16_addr: u16 },
-    Array { addr: u16, len: u16 },
+    U8 {
+        u8_addr: u16,
+        /// Request the value from the spa every `poll` seconds, for values which the spa doesn't
+        /// push when they change. A `poll` of 0 is rejected.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        poll: Option<NonZeroU32>,
+    },
+    U16 {
+        u16_addr: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        poll: Option<NonZeroU32>,
+    },
+    /// Like `U16`, but little endian.
+    U16Le {
+        u16_le_addr: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        poll: Option<NonZeroU32>,
+    },
+    /// A single bit of the byte at `bit_addr`, where bit 0 is the least significant one. The
+    /// value is a boolean, which fits binary sensors.
+    Bit {
+        bit_addr: u16,
+        bit: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        poll: Option<NonZeroU32>,
+    },
+    Array {
+        addr: u16,
+        len: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        poll: Option<NonZeroU32>,
+    },
     Special(SpecialMode<MappingType>),
 }
 
@@ -160,6 +211,21 @@ impl GenericWatchMap<serde_json::Value> for WatchMap<mpsc::Receiver<()>, (), ser
     }
 }
 
+/// A value which never changes, such as the firmware version of the spa.
+pub struct ConstantMap<T>(T);
+
+impl<T: Send> GenericWatchMap<T> for ConstantMap<T> {
+    fn changed<'a>(
+        &'a mut self,
+    ) -> Pin<Box<dyn Future<Output = Result<(), MappingError>> + 'a + Send>> {
+        Box::pin(std::future::pending())
+    }
+
+    fn borrow_and_update(&mut self) -> &T {
+        &self.0
+    }
+}
+
 impl<W, I, T> WatchMap<W, I, T> {
     pub fn new<F: 'static + Send + FnMut(&I) -> T>(watch: W, map: F) -> Self {
         Self {
@@ -174,7 +240,7 @@ impl MappingType {
     pub fn subscribe<'a, T: Send + 'static>(
         &'a self,
         spa: &'a SpaConnection,
-        jobs: &'a mut JoinSet<Result<T, MappingError>>,
+        jobs: &'a mut SupervisedTasks<T, MappingError>,
     ) -> Pin<
         Box<
             dyn Future<Output = Result<Box<dyn GenericWatchMap<serde_json::Value>>, MappingError>>
@@ -188,30 +254,38 @@ impl MappingType {
             ) -> Box<dyn GenericWatchMap<serde_json::Value>> {
                 Box::new(x)
             }
+            if let (Some(range), Some(interval)) = (self.range(), self.poll_interval()) {
+                let poll = spa.poll_range(range, interval);
+                jobs.spawn(async move {
+                    poll.await?;
+                    Err(MappingError::ChannelClosed("Spa memory poll"))
+                });
+            }
             match self {
                 MappingType::Special(SpecialMode::Multiple(children)) => {
-                    let (tx, rx) = mpsc::channel(children.len());
+                    // Every child is subscribed once, since subscribing also starts its poll job.
                     let mut subscriptions = Vec::with_capacity(children.len());
                     for child in children.iter() {
                         subscriptions.push(child.subscribe(spa, jobs).await?);
                     }
-                    for sub in children.to_owned().into_iter() {
-                        let mut subscriber = sub.subscribe(spa, jobs).await?;
+                    let values = subscriptions
+                        .iter_mut()
+                        .map(|x| x.borrow_and_update().to_owned())
+                        .collect();
+                    let (tx, rx) = watch::channel(values);
+                    let tx = Arc::new(tx);
+                    for (index, mut subscriber) in subscriptions.into_iter().enumerate() {
                         let tx = tx.clone();
                         jobs.spawn(async move {
                             loop {
                                 subscriber.changed().await?;
-                                _ = tx.send(()).await;
+                                let value = subscriber.borrow_and_update().to_owned();
+                                tx.send_modify(|values: &mut Vec<_>| values[index] = value);
                             }
                         });
                     }
-                    let map = WatchMap::new(rx, move |_: &()| {
-                        serde_json::Value::Array(
-                            subscriptions
-                                .iter_mut()
-                                .map(|x| x.borrow_and_update().to_owned())
-                                .collect(),
-                        )
+                    let map = WatchMap::new(rx, |values: &Vec<serde_json::Value>| {
+                        serde_json::Value::Array(values.clone())
                     });
                     Ok(to_return(map))
                 }
@@ -223,25 +297,68 @@ impl MappingType {
                     });
                     Ok(to_return(map))
                 }
-                value @ MappingType::U8 { .. } => {
-                    let subscribe = spa.subscribe(value.range().expect("U8 has a range")).await;
-                    let map = WatchMap::new(subscribe, |valid_data: &Box<[u8]>| {
-                        let array: &[u8; 1] = valid_data
-                            .as_ref()
-                            .try_into()
-                            .expect("This value will always be 1 byte");
-                        serde_json::Value::Number(array[0].into())
+                MappingType::Special(SpecialMode::Version) => {
+                    Ok(to_return(ConstantMap(spa.version_json())))
+                }
+                MappingType::Special(SpecialMode::Radio) => {
+                    let map = WatchMap::new(
+                        spa.subscribe_channel(),
+                        |current: &Option<package_data::ChannelCurrent>| match current {
+                            Some(current) => serde_json::json!({
+                                "channel": current.channel,
+                                "signal_strength": current.signal_strength,
+                                "signal_quality": current.signal_quality(),
+                            }),
+                            None => serde_json::Value::Null,
+                        },
+                    );
+                    Ok(to_return(map))
+                }
+                MappingType::Special(SpecialMode::WatercareState) => {
+                    let map =
+                        WatchMap::new(spa.subscribe_watercare_state(), |state: &WatercareState| {
+                            serde_json::to_value(state).unwrap_or(serde_json::Value::Null)
+                        });
+                    Ok(to_return(map))
+                }
+                MappingType::Special(SpecialMode::SpaLink) => {
+                    let map = WatchMap::new(spa.subscribe_link_stats(), |stats: &LinkStats| {
+                        serde_json::to_value(stats).unwrap_or(serde_json::Value::Null)
                     });
                     Ok(to_return(map))
                 }
-                value @ MappingType::U16 { .. } => {
-                    let subscribe = spa.subscribe(value.range().expect("U16 has a range")).await;
-                    let map = WatchMap::new(subscribe, |valid_data: &Box<[u8]>| {
-                        let array: &[u8; 2] = valid_data
-                            .as_ref()
-                            .try_into()
-                            .expect("This value will always be 2 bytes");
-                        serde_json::Value::Number(u16::from_be_bytes(*array).into())
+                MappingType::U8 { u8_addr, .. } => {
+                    let subscribe = spa.subscribe_u8((*u8_addr).into()).await;
+                    let map = WatchMap::new(subscribe, |x: &Option<u8>| {
+                        x.map(|valid_data| serde_json::Value::Number(valid_data.into()))
+                            .unwrap_or(serde_json::Value::Null)
+                    });
+                    Ok(to_return(map))
+                }
+                MappingType::U16 { u16_addr, .. } => {
+                    let subscribe = spa.subscribe_u16((*u16_addr).into()).await;
+                    let map = WatchMap::new(subscribe, |x: &Option<u16>| {
+                        x.map(|valid_data| serde_json::Value::Number(valid_data.into()))
+                            .unwrap_or(serde_json::Value::Null)
+                    });
+                    Ok(to_return(map))
+                }
+                MappingType::U16Le { u16_le_addr, .. } => {
+                    let subscribe = spa.subscribe_u16_le((*u16_le_addr).into()).await;
+                    let map = WatchMap::new(subscribe, |x: &Option<u16>| {
+                        x.map(|valid_data| serde_json::Value::Number(valid_data.into()))
+                            .unwrap_or(serde_json::Value::Null)
+                    });
+                    Ok(to_return(map))
+                }
+                MappingType::Bit { bit_addr, bit, .. } => {
+                    let subscribe = spa.subscribe_u8((*bit_addr).into()).await;
+                    let mask = 1u8.checked_shl((*bit).into());
+                    let map = WatchMap::new(subscribe, move |x: &Option<u8>| match (x, mask) {
+                        (Some(valid_data), Some(mask)) => {
+                            serde_json::Value::Bool(valid_data & mask != 0)
+                        }
+                        _ => serde_json::Value::Null,
                     });
                     Ok(to_return(map))
                 }
@@ -264,24 +381,298 @@ impl MappingType {
     }
 }
 
+/// A light zone of the spa, for spas with more than one zone of lights.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorZone {
+    Primary,
+    Secondary,
+}
+
+/// The light zones where the position of the color type is known.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorTypeZone {
+    Secondary,
+}
+
+/// The [QosPid] of an MQTT QoS level, with a new packet id from `next_pid` when one is needed.
+fn qos_pid(qos: u8, next_pid: impl FnOnce() -> Pid) -> QosPid {
+    match qos {
+        1 => QosPid::AtLeastOnce(next_pid()),
+        2 => QosPid::ExactlyOnce(next_pid()),
+        _ => QosPid::AtMostOnce,
+    }
+}
+
+/// How long a command waits for the spa to report the value it wrote.
+const COMMAND_CONFIRMATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Wait for `memory` to contain `written`. Returns `false` if it doesn't within `timeout`.
+async fn confirm_write(
+    memory: &mut watch::Receiver<Box<[u8]>>,
+    written: &[u8],
+    timeout: std::time::Duration,
+) -> Result<bool, watch::error::RecvError> {
+    match tokio::time::timeout(timeout, memory.wait_for(|current| **current == *written)).await {
+        Ok(confirmed) => confirmed.map(|_| true),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Wait until a state should be published again, which is when `state` changes. With a
+/// `republish_interval`, an unchanged state is also published again after that interval, but only
+/// while `state_valid` is true, so that Home Assistant expires it when the spa goes offline.
+async fn next_state_publish<T>(
+    state: &mut dyn GenericWatchMap<T>,
+    republish_interval: Option<std::time::Duration>,
+    state_valid: &mut watch::Receiver<bool>,
+) -> Result<(), MappingError> {
+    let Some(interval) = republish_interval else {
+        return state.changed().await;
+    };
+    if let Ok(changed) = tokio::time::timeout(interval, state.changed()).await {
+        return changed;
+    }
+    select! {
+        changed = state.changed() => changed,
+        valid = state_valid.wait_for(|valid| *valid) => Ok(valid.map(drop)?),
+    }
+}
+
+/// Run `command` for a payload published to `topic`. Problems with the payload or the spa's
+/// answer are logged, only failures of the spa connection itself are returned.
+async fn run_command(
+    command: &CommandMappingType,
+    topic: &str,
+    payload: &[u8],
+    spa_sender: &mpsc::Sender<SpaCommand>,
+    written_memory: &HashMap<std::ops::Range<usize>, watch::Receiver<Box<[u8]>>>,
+    units: TemperatureUnits,
+) -> Result<(), MappingError> {
+    match command {
+        CommandMappingType::Special(SpecialMode::WatercareMode) => {
+            let Ok(valid_str) = std::str::from_utf8(payload) else {
+                eprintln!("Invalid payload from MQTT: {payload:?}");
+                return Ok(());
+            };
+            let Ok(mode) = valid_str.parse() else {
+                eprintln!("Invalid payload from MQTT: {valid_str}");
+                return Ok(());
+            };
+            spa_sender
+                .send(SpaCommand::SetWatercare {
+                    mode,
+                    response: None,
+                })
+                .await?;
+        }
+        CommandMappingType::KeyPress { pack_type, key } => {
+            spa_sender
+                .send(SpaCommand::KeyPress {
+                    pack_type: *pack_type,
+                    key: key.code(),
+                    response: None,
+                })
+                .await?;
+        }
+        CommandMappingType::SetStatus {
+            config_version,
+            log_version,
+            pack_type,
+            framing,
+            value,
+            data,
+        } => {
+            let range = data.range();
+            let parsed = match value {
+                Some(value) => {
+                    serde_json::to_vec(value).and_then(|value| data.parse_with_units(&value, units))
+                }
+                None => data.parse_with_units(payload, units),
+            };
+            let payload = match parsed {
+                Ok(data) => data,
+                Err(e) => {
+                    eprintln!("Invalid data from MQTT: {e}");
+                    return Ok(());
+                }
+            };
+            let (response, result) = sync::oneshot::channel();
+            spa_sender
+                .send(SpaCommand::SetStatus {
+                    config_version: *config_version,
+                    log_version: *log_version,
+                    pack_type: *pack_type,
+                    framing: framing.unwrap_or(DEFAULT_SET_STATUS_FRAMING),
+                    pos: range.start,
+                    data: (*payload).into(),
+                    response: Some(response),
+                })
+                .await?;
+            if let Err(e) = result
+                .await
+                .map_err(|_| MappingError::ChannelClosed("Spa command response"))?
+            {
+                eprintln!("Command from {topic} failed: {e}");
+            } else if let Some(memory) = written_memory.get(&(range.start.into()..range.end.into()))
+            {
+                // The state is published from the spa memory either way, so the confirmation is
+                // only logged, without holding up the commands after this one.
+                let mut memory = memory.clone();
+                let topic = topic.to_owned();
+                tokio::spawn(async move {
+                    if let Ok(false) =
+                        confirm_write(&mut memory, &payload, COMMAND_CONFIRMATION_TIMEOUT).await
+                    {
+                        eprintln!("The spa didn't confirm the command from {topic}");
+                    }
+                });
+            }
+        }
+        CommandMappingType::Payloads { payloads } => {
+            let command = std::str::from_utf8(payload)
+                .ok()
+                .and_then(|payload| payloads.get(payload));
+            let Some(command) = command else {
+                eprintln!("Unknown payload from MQTT on {topic}: {payload:?}");
+                return Ok(());
+            };
+            Box::pin(run_command(
+                command,
+                topic,
+                payload,
+                spa_sender,
+                written_memory,
+                units,
+            ))
+            .await?;
+        }
+        CommandMappingType::Special(
+            SpecialMode::Multiple(_)
+            | SpecialMode::Version
+            | SpecialMode::SpaLink
+            | SpecialMode::Radio
+            | SpecialMode::WatercareState,
+        ) => (),
+    }
+    Ok(())
+}
+
+fn known_range<T: KnownData>() -> std::ops::Range<u16> {
+    T::POSITION..T::POSITION + T::LENGTH
+}
+
 #[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq, serde::Serialize)]
 #[serde(untagged)]
 pub enum CommandStatusType {
-    U8 { u8_addr: u16 },
-    U16 { u16_addr: u16 },
-    Array { addr: u16, len: u16 },
+    U8 {
+        u8_addr: u16,
+    },
+    U16 {
+        u16_addr: u16,
+    },
+    /// Like `U16`, but little endian.
+    U16Le {
+        u16_le_addr: u16,
+    },
+    Array {
+        addr: u16,
+        len: u16,
+    },
+    /// The red, green and blue values of a light zone, written as an array like `Array`.
+    Color {
+        color: ColorZone,
+    },
+    /// The color type of a light zone, written as a number like `U8`.
+    ColorType {
+        color_type: ColorTypeZone,
+    },
+    /// A temperature, such as the target of a climate entity, written as a `U16` in the unit of
+    /// the spa. The payload is converted from the `temperature_unit` of the entity when it
+    /// differs from the unit of the spa, and rounded to whole degrees.
+    Temperature {
+        temperature_addr: u16,
+    },
+}
+
+/// The unit of the temperatures published to a command topic, and the unit of the spa they are
+/// written to. Temperatures are written as is when either is unknown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TemperatureUnits {
+    pub home_assistant: Option<TemperatureUnit>,
+    pub spa: Option<TemperatureUnit>,
+}
+
+impl TemperatureUnits {
+    fn to_spa(self, temperature: f64) -> f64 {
+        match (self.home_assistant, self.spa) {
+            (Some(from), Some(to)) => from.convert(temperature, to),
+            _ => temperature,
+        }
+    }
 }
 
 impl CommandStatusType {
+    /// The longest payload [Self::parse] accepts. Each byte of the range is at most 3 digits and
+    /// a comma, and the rest is room for brackets and whitespace.
+    pub fn max_payload_len(&self) -> usize {
+        const SLACK: usize = 64;
+        self.range().len() * 4 + SLACK
+    }
+
+    /// Parse a JSON payload into the bytes to write at [Self::range]. Arrays which don't have
+    /// the length of the range are rejected, and so are payloads longer than
+    /// [Self::max_payload_len], before anything is allocated for them.
     pub fn parse(&self, payload: &[u8]) -> Result<Box<[u8]>, serde_json::error::Error> {
+        self.parse_with_units(payload, TemperatureUnits::default())
+    }
+
+    /// Like [Self::parse], but converts [Self::Temperature] payloads with `units`.
+    pub fn parse_with_units(
+        &self,
+        payload: &[u8],
+        units: TemperatureUnits,
+    ) -> Result<Box<[u8]>, serde_json::error::Error> {
+        let max_len = self.max_payload_len();
+        if payload.len() > max_len {
+            return Err(serde::de::Error::custom(format!(
+                "The payload is {} bytes, but at most {max_len} bytes are expected",
+                payload.len()
+            )));
+        }
         match self {
-            CommandStatusType::U8 { .. } => {
+            CommandStatusType::U8 { .. } | CommandStatusType::ColorType { .. } => {
                 Ok(Box::from(&[serde_json::from_slice::<u8>(payload)?][..]))
             }
             CommandStatusType::U16 { .. } => Ok(Box::from(
                 serde_json::from_slice::<u16>(payload)?.to_be_bytes(),
             )),
-            CommandStatusType::Array { .. } => Ok(serde_json::from_slice::<Box<[u8]>>(payload)?),
+            CommandStatusType::U16Le { .. } => Ok(Box::from(
+                serde_json::from_slice::<u16>(payload)?.to_le_bytes(),
+            )),
+            CommandStatusType::Temperature { .. } => {
+                let temperature = units
+                    .to_spa(serde_json::from_slice::<f64>(payload)?)
+                    .round();
+                if !(0.0..=f64::from(u16::MAX)).contains(&temperature) {
+                    return Err(serde::de::Error::custom(format!(
+                        "The temperature {temperature} can't be written to the spa"
+                    )));
+                }
+                Ok(Box::from((temperature as u16).to_be_bytes()))
+            }
+            CommandStatusType::Array { .. } | CommandStatusType::Color { .. } => {
+                let data = serde_json::from_slice::<Box<[u8]>>(payload)?;
+                let expected = self.range().len();
+                if data.len() != expected {
+                    return Err(serde::de::Error::invalid_length(
+                        data.len(),
+                        &format!("an array of {expected} bytes").as_str(),
+                    ));
+                }
+                Ok(data)
+            }
         }
     }
 
@@ -289,7 +680,20 @@ impl CommandStatusType {
         match self {
             CommandStatusType::U8 { u8_addr } => *u8_addr..u8_addr + 1,
             CommandStatusType::U16 { u16_addr } => *u16_addr..u16_addr + 2,
+            CommandStatusType::U16Le { u16_le_addr } => *u16_le_addr..u16_le_addr + 2,
+            CommandStatusType::Temperature { temperature_addr } => {
+                *temperature_addr..temperature_addr + 2
+            }
             CommandStatusType::Array { addr, len } => *addr..addr + len,
+            CommandStatusType::Color {
+                color: ColorZone::Primary,
+            } => known_range::<PrimaryColor>(),
+            CommandStatusType::Color {
+                color: ColorZone::Secondary,
+            } => known_range::<SecondaryColor>(),
+            CommandStatusType::ColorType {
+                color_type: ColorTypeZone::Secondary,
+            } => known_range::<SecondaryColorType>(),
         }
     }
 }
@@ -301,36 +705,146 @@ pub enum CommandMappingType {
         config_version: u8,
         log_version: u8,
         pack_type: u8,
+        /// The byte between the length and the config version in the `SetStatus` package, for
+        /// packs which don't use [DEFAULT_SET_STATUS_FRAMING].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        framing: Option<u8>,
+        /// Write this value instead of the published payload, for commands which always write
+        /// the same thing, such as the entries of [Self::Payloads].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<serde_json::Value>,
         #[serde(flatten)]
         data: CommandStatusType,
     },
+    /// Press a key on the keypad whenever anything is published to the command topic, which is
+    /// what Home Assistant buttons do.
+    KeyPress {
+        pack_type: u8,
+        key: Key,
+    },
+    /// Run a different command for each payload, such as the open, close and stop payloads of a
+    /// Home Assistant cover. Payloads which aren't listed are ignored.
+    Payloads {
+        payloads: HashMap<String, CommandMappingType>,
+    },
     Special(SpecialMode<CommandMappingType>),
 }
 
+/// A keypad key, either by name or by its raw code for keys which aren't named in [KeyCode].
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(untagged)]
+pub enum Key {
+    Named(KeyCode),
+    Code(u8),
+}
+
+impl Key {
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Named(key) => key.code(),
+            Self::Code(code) => code,
+        }
+    }
+}
+
 impl MappingType {
     pub fn range(&self) -> Option<std::ops::Range<usize>> {
         let start = match self {
-            Self::U8 { u8_addr: start }
-            | Self::U16 { u16_addr: start }
+            Self::U8 { u8_addr: start, .. }
+            | Self::U16 {
+                u16_addr: start, ..
+            }
+            | Self::U16Le {
+                u16_le_addr: start, ..
+            }
+            | Self::Bit {
+                bit_addr: start, ..
+            }
             | Self::Array { addr: start, .. } => usize::from(*start),
             Self::Special(_) => return None,
         };
         let len = match self {
-            Self::U8 { .. } => 1,
-            Self::U16 { .. } => 2,
+            Self::U8 { .. } | Self::Bit { .. } => 1,
+            Self::U16 { .. } | Self::U16Le { .. } => 2,
             Self::Array { len, .. } => usize::from(*len),
             Self::Special(_) => unreachable!(),
         };
         let end = start + len;
         Some(start..end)
     }
+
+    pub fn poll_interval(&self) -> Option<std::time::Duration> {
+        match self {
+            Self::U8 { poll, .. }
+            | Self::U16 { poll, .. }
+            | Self::U16Le { poll, .. }
+            | Self::Bit { poll, .. }
+            | Self::Array { poll, .. } => {
+                poll.map(|seconds| std::time::Duration::from_secs(seconds.get().into()))
+            }
+            Self::Special(_) => None,
+        }
+    }
+}
+
+/// How a state value is presented, applied to the value right before it is published. Values
+/// which are `null`, because the spa hasn't reported them yet, are published as is.
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct StateTransform {
+    /// Round numbers to this many decimals.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decimals: Option<u8>,
+    /// Publish the value as a string, with `{value}` replaced by the value, such as
+    /// `{value} °C`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<Box<str>>,
+}
+
+impl StateTransform {
+    pub fn apply(&self, value: &serde_json::Value) -> serde_json::Value {
+        let value = match (value, self.decimals) {
+            (serde_json::Value::Null, _) => return serde_json::Value::Null,
+            (serde_json::Value::Number(number), Some(decimals)) => {
+                match number.as_f64().map(|number| round(number, decimals)) {
+                    Some(rounded) if decimals == 0 && rounded.abs() < i64::MAX as f64 => {
+                        serde_json::Value::Number((rounded as i64).into())
+                    }
+                    Some(rounded) => serde_json::Number::from_f64(rounded)
+                        .map_or_else(|| value.clone(), serde_json::Value::Number),
+                    None => value.clone(),
+                }
+            }
+            _ => value.clone(),
+        };
+        match &self.template {
+            Some(template) => {
+                let value = match value {
+                    serde_json::Value::String(value) => value,
+                    value => value.to_string(),
+                };
+                serde_json::Value::String(template.replace("{value}", &value))
+            }
+            None => value,
+        }
+    }
+}
+
+fn round(value: f64, decimals: u8) -> f64 {
+    let factor = 10f64.powi(decimals.into());
+    (value * factor).round() / factor
 }
 
 #[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq, serde::Serialize)]
 #[serde(untagged)]
 pub enum MqttType {
-    State { state: MappingType },
-    Command { command: CommandMappingType },
+    State {
+        state: MappingType,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transform: Option<StateTransform>,
+    },
+    Command {
+        command: CommandMappingType,
+    },
     Value(serde_json::Value),
 }
 
@@ -342,10 +856,181 @@ pub struct GenericMapping {
     pub unique_id: &'static str,
     #[serde(default)]
     pub qos: u8,
+    #[serde(default)]
+    pub icon: Option<&'static str>,
+    #[serde(default)]
+    pub entity_category: Option<&'static str>,
+    /// Mark the entity unavailable if its state isn't published for this many seconds. The state
+    /// is republished at half this interval while the spa memory is valid, so it expires once the
+    /// spa stops answering.
+    #[serde(default)]
+    pub expire_after: Option<u32>,
     #[serde(flatten)]
     pub mqtt_values: HashMap<&'static str, MqttType>,
 }
 
+/// The Home Assistant MQTT platforms which an entity `type` can be.
+pub const HOME_ASSISTANT_PLATFORMS: &[&str] = &[
+    "alarm_control_panel",
+    "binary_sensor",
+    "button",
+    "camera",
+    "climate",
+    "cover",
+    "device_tracker",
+    "event",
+    "fan",
+    "humidifier",
+    "image",
+    "lawn_mower",
+    "light",
+    "lock",
+    "notify",
+    "number",
+    "scene",
+    "select",
+    "sensor",
+    "siren",
+    "switch",
+    "tag",
+    "text",
+    "update",
+    "vacuum",
+    "valve",
+    "water_heater",
+];
+
+/// A problem with an entity configuration, found by [GenericMapping::validate].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("{0:?} is not a known Home Assistant MQTT platform")]
+    UnknownPlatform(&'static str),
+    #[error("{key} uses {range:?}, which is outside of the spa memory size {memory_size}")]
+    OutOfRange {
+        key: &'static str,
+        range: std::ops::Range<usize>,
+        memory_size: usize,
+    },
+    #[error("{key} is a command without {}", missing.join(", "))]
+    MissingCommandVersion {
+        key: &'static str,
+        missing: Vec<&'static str>,
+    },
+    #[error("{key} is not a valid {kind}: {value}")]
+    Invalid {
+        key: &'static str,
+        kind: &'static str,
+        value: String,
+    },
+}
+
+impl MappingType {
+    fn ranges(&self, ranges: &mut Vec<std::ops::Range<usize>>) {
+        match self {
+            Self::Special(SpecialMode::Multiple(mappings)) => {
+                mappings.iter().for_each(|mapping| mapping.ranges(ranges))
+            }
+            Self::Special(
+                SpecialMode::WatercareMode
+                | SpecialMode::Version
+                | SpecialMode::SpaLink
+                | SpecialMode::Radio
+                | SpecialMode::WatercareState,
+            ) => (),
+            _ => ranges.extend(self.range()),
+        }
+    }
+}
+
+impl CommandMappingType {
+    fn ranges(&self, ranges: &mut Vec<std::ops::Range<usize>>) {
+        match self {
+            Self::SetStatus { data, .. } => {
+                let range = data.range();
+                ranges.push(range.start.into()..range.end.into())
+            }
+            Self::Special(SpecialMode::Multiple(commands)) => {
+                commands.iter().for_each(|command| command.ranges(ranges))
+            }
+            Self::Payloads { payloads } => {
+                payloads.values().for_each(|command| command.ranges(ranges))
+            }
+            Self::KeyPress { .. }
+            | Self::Special(
+                SpecialMode::WatercareMode
+                | SpecialMode::Version
+                | SpecialMode::SpaLink
+                | SpecialMode::Radio
+                | SpecialMode::WatercareState,
+            ) => (),
+        }
+    }
+}
+
+impl GenericMapping {
+    /// Check the entity for problems which would otherwise only show up once it's used, such as
+    /// addresses outside of the spa memory. All problems are returned, sorted by key.
+    pub fn validate(&self, memory_size: Option<usize>) -> Vec<ConfigError> {
+        let mut errors = vec![];
+        if !HOME_ASSISTANT_PLATFORMS.contains(&self.mqtt_type) {
+            errors.push(ConfigError::UnknownPlatform(self.mqtt_type));
+        }
+        let mut keys: Vec<_> = self.mqtt_values.keys().copied().collect();
+        keys.sort_unstable();
+        for key in keys {
+            let mut ranges = vec![];
+            match &self.mqtt_values[key] {
+                MqttType::State { state, .. } => state.ranges(&mut ranges),
+                MqttType::Command { command } => command.ranges(&mut ranges),
+                // Anything which failed to parse as a state or command ends up as a plain value,
+                // which would be sent to Home Assistant as is.
+                MqttType::Value(serde_json::Value::Object(object))
+                    if object.len() == 1
+                        || (object.len() == 2 && object.contains_key("transform")) =>
+                {
+                    if let Some(serde_json::Value::Object(command)) = object.get("command") {
+                        let missing: Vec<_> = ["config_version", "log_version", "pack_type"]
+                            .into_iter()
+                            .filter(|field| {
+                                !command.contains_key("payloads") && !command.contains_key(*field)
+                            })
+                            .collect();
+                        errors.push(if missing.is_empty() {
+                            ConfigError::Invalid {
+                                key,
+                                kind: "command",
+                                value: serde_json::Value::Object(command.clone()).to_string(),
+                            }
+                        } else {
+                            ConfigError::MissingCommandVersion { key, missing }
+                        });
+                    } else if let Some(state) = object.get("state") {
+                        errors.push(ConfigError::Invalid {
+                            key,
+                            kind: "state",
+                            value: state.to_string(),
+                        });
+                    }
+                }
+                MqttType::Value(_) => (),
+            }
+            if let Some(memory_size) = memory_size {
+                errors.extend(
+                    ranges
+                        .into_iter()
+                        .filter(|range| range.end > memory_size)
+                        .map(|range| ConfigError::OutOfRange {
+                            key,
+                            range,
+                            memory_size,
+                        }),
+                );
+            }
+        }
+        errors
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -387,6 +1072,8 @@ mod tests {
                 config_version: 1,
                 log_version: 2,
                 pack_type: 3,
+                framing: None,
+                value: None,
                 data: super::CommandStatusType::U8 { u8_addr: 4 },
             },
         };
@@ -400,6 +1087,782 @@ mod tests {
         assert!(matches!(parsed, super::MqttType::Command { .. }));
         Ok(())
     }
+    #[test]
+    fn validate() -> anyhow::Result<()> {
+        use super::ConfigError;
+        let mapping: super::GenericMapping = serde_json::from_str(
+            r#"{"type": "light", "name": "Light", "unique_id": "light0001", "state_topic": {"state": {"u8_addr": 100}}, "command_topic": {"command": {"config_version": 1, "log_version": 2, "pack_type": 3, "u16_addr": 100}}}"#,
+        )?;
+        assert_eq!(mapping.validate(Some(102)), vec![]);
+        assert_eq!(
+            mapping.validate(Some(101)),
+            vec![ConfigError::OutOfRange {
+                key: "command_topic",
+                range: 100..102,
+                memory_size: 101
+            }]
+        );
+        assert_eq!(mapping.validate(None), vec![]);
+        let mapping: super::GenericMapping = serde_json::from_str(
+            r#"{"type": "lamp", "name": "Light", "unique_id": "light0001", "state_topic": {"state": {"u8_addr": "100"}}, "command_topic": {"command": {"log_version": 2, "u8_addr": 100}}}"#,
+        )?;
+        assert_eq!(
+            mapping.validate(Some(10)),
+            vec![
+                ConfigError::UnknownPlatform("lamp"),
+                ConfigError::MissingCommandVersion {
+                    key: "command_topic",
+                    missing: vec!["config_version", "pack_type"]
+                },
+                ConfigError::Invalid {
+                    key: "state_topic",
+                    kind: "state",
+                    value: r#"{"u8_addr":"100"}"#.into()
+                },
+            ]
+        );
+        Ok(())
+    }
+    #[test]
+    fn validate_zero_poll() -> anyhow::Result<()> {
+        use super::ConfigError;
+        let mapping: super::GenericMapping = serde_json::from_str(
+            r#"{"type": "sensor", "name": "Sensor", "unique_id": "sensor0001", "state_topic": {"state": {"u8_addr": 100, "poll": 0}}}"#,
+        )?;
+        assert_eq!(
+            mapping.validate(None),
+            vec![ConfigError::Invalid {
+                key: "state_topic",
+                kind: "state",
+                value: r#"{"poll":0,"u8_addr":100}"#.into()
+            }]
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn write_confirmed_by_spa() -> anyhow::Result<()> {
+        use std::time::Duration;
+        let (memory, mut subscription) = tokio::sync::watch::channel(Box::from([0u8, 0]));
+        let confirmed = super::confirm_write(&mut subscription, &[1, 2], Duration::from_secs(5));
+        let spa = async {
+            tokio::task::yield_now().await;
+            memory.send_replace(Box::from([1, 0]));
+            memory.send_replace(Box::from([1, 2]));
+        };
+        let (confirmed, ()) = tokio::join!(confirmed, spa);
+        assert!(confirmed?);
+        assert!(
+            !super::confirm_write(&mut subscription, &[3, 4], Duration::from_millis(10)).await?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn qos_pid() {
+        use mqttrs::{Pid, QosPid};
+        let pid = Pid::new();
+        assert_eq!(super::qos_pid(0, || unreachable!()), QosPid::AtMostOnce);
+        assert_eq!(super::qos_pid(1, || pid), QosPid::AtLeastOnce(pid));
+        assert_eq!(super::qos_pid(2, || pid), QosPid::ExactlyOnce(pid));
+    }
+    #[test]
+    fn supervisor_online_message() -> anyhow::Result<()> {
+        use mqttrs::{Packet, Publish, QosPid};
+        let supervisor = super::MappingSupervisor {
+            mapping: super::Mapping::new(crate::home_assistant::ConfigureDevice {
+                identifiers: Box::new(["spa".into()]),
+                name: "Spa".into(),
+                sw_version: None,
+                extra_args: Default::default(),
+            })?,
+            entities: Box::new([]),
+            status_topics: Box::new(["homeassistant/status".into(), "other/status".into()]),
+            online_payload: "up".into(),
+            configure_delay: std::time::Duration::ZERO,
+            rediscovery_interval: None,
+            verbose: false,
+        };
+        let publish = |topic_name, payload, dup| {
+            Packet::Publish(Publish {
+                dup,
+                qospid: QosPid::AtMostOnce,
+                retain: false,
+                topic_name,
+                payload,
+            })
+        };
+        assert!(supervisor.is_online_message(&publish("other/status", b"up", false)));
+        assert!(!supervisor.is_online_message(&publish("other/status", b"online", false)));
+        assert!(!supervisor.is_online_message(&publish("other/status", b"up", true)));
+        assert!(!supervisor.is_online_message(&publish("intouch2/status", b"up", false)));
+        assert!(!supervisor.is_online_message(&Packet::Pingresp));
+        Ok(())
+    }
+    #[test]
+    fn parse_u8_command() -> anyhow::Result<()> {
+        let command = super::CommandStatusType::U8 { u8_addr: 10 };
+        assert_eq!(command.range(), 10..11);
+        assert_eq!(&*command.parse(b"200")?, &[200]);
+        assert!(command.parse(b"256").is_err());
+        Ok(())
+    }
+    #[test]
+    fn parse_u16_command() -> anyhow::Result<()> {
+        let command = super::CommandStatusType::U16 { u16_addr: 10 };
+        assert_eq!(command.range(), 10..12);
+        let data = command.parse(b"4660")?;
+        assert_eq!(&*data, &[0x12, 0x34]);
+        assert_eq!(u16::from_be_bytes([data[0], data[1]]), 4660);
+        Ok(())
+    }
+    #[test]
+    fn little_endian_u16() -> anyhow::Result<()> {
+        let command = super::CommandStatusType::U16Le { u16_le_addr: 10 };
+        assert_eq!(command.range(), 10..12);
+        assert_eq!(&*command.parse(b"4660")?, &[0x34, 0x12]);
+        let big_endian = super::CommandStatusType::U16 { u16_addr: 10 };
+        assert_eq!(&*big_endian.parse(b"4660")?, &[0x12, 0x34]);
+
+        let parsed: super::MqttType = serde_json::from_str(r#"{"state":{"u16_le_addr":10}}"#)?;
+        let super::MqttType::State { state, .. } = parsed else {
+            panic!("Expected a state, got {parsed:?}");
+        };
+        assert_eq!(
+            state,
+            super::MappingType::U16Le {
+                u16_le_addr: 10,
+                poll: None
+            }
+        );
+        assert_eq!(state.range(), Some(10..12));
+        Ok(())
+    }
+    #[test]
+    fn parse_array_command_rejects_wrong_length() -> anyhow::Result<()> {
+        let command = super::CommandStatusType::Array { addr: 10, len: 3 };
+        assert_eq!(command.range(), 10..13);
+        assert_eq!(&*command.parse(b"[1, 2, 3]")?, &[1, 2, 3]);
+        assert!(command.parse(b"[1, 2]").is_err());
+        assert!(command.parse(b"[1, 2, 3, 4]").is_err());
+        Ok(())
+    }
+    #[test]
+    fn parse_oversized_command() -> anyhow::Result<()> {
+        let command = super::CommandStatusType::Array { addr: 10, len: 3 };
+        let padded = format!("[255,{}255,255]", " ".repeat(40));
+        assert_eq!(&*command.parse(padded.as_bytes())?, &[255, 255, 255]);
+        let oversized = format!("[{}]", vec!["1"; 10_000].join(","));
+        let error = command
+            .parse(oversized.as_bytes())
+            .expect_err("Oversized payloads should be rejected");
+        assert!(error.to_string().contains("at most"), "{error}");
+        let u8_command = super::CommandStatusType::U8 { u8_addr: 10 };
+        assert!(u8_command.parse(" ".repeat(1000).as_bytes()).is_err());
+        Ok(())
+    }
+    #[test]
+    fn temperature_command() -> anyhow::Result<()> {
+        use super::TemperatureUnits;
+        use crate::spa::TemperatureUnit::{Celsius, Fahrenheit};
+        let parsed: super::MqttType = serde_json::from_str(
+            r#"{"command":{"config_version":1,"log_version":2,"pack_type":3,"temperature_addr":10}}"#,
+        )?;
+        let super::MqttType::Command {
+            command: super::CommandMappingType::SetStatus { data: command, .. },
+        } = parsed
+        else {
+            panic!("Expected a SetStatus command, got {parsed:?}");
+        };
+        assert_eq!(command.range(), 10..12);
+        let units = |home_assistant, spa| TemperatureUnits {
+            home_assistant: Some(home_assistant),
+            spa: Some(spa),
+        };
+        assert_eq!(
+            &*command.parse_with_units(b"40", units(Celsius, Fahrenheit))?,
+            &104u16.to_be_bytes()
+        );
+        assert_eq!(
+            &*command.parse_with_units(b"104", units(Fahrenheit, Celsius))?,
+            &40u16.to_be_bytes()
+        );
+        assert_eq!(
+            &*command.parse_with_units(b"38.6", units(Celsius, Celsius))?,
+            &39u16.to_be_bytes()
+        );
+        assert_eq!(&*command.parse(b"100")?, &100u16.to_be_bytes());
+        assert!(command
+            .parse_with_units(b"-40", units(Celsius, Fahrenheit))
+            .is_err());
+        assert!(command.parse(b"\"warm\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_malformed_command() {
+        let commands = [
+            super::CommandStatusType::U8 { u8_addr: 10 },
+            super::CommandStatusType::U16 { u16_addr: 10 },
+            super::CommandStatusType::Array { addr: 10, len: 1 },
+        ];
+        for command in commands {
+            for payload in [&b"on"[..], b"", b"[1,", b"\"1\""] {
+                command
+                    .parse(payload)
+                    .expect_err("Malformed payloads should be rejected");
+            }
+        }
+    }
+
+    #[test]
+    fn secondary_color_command() -> anyhow::Result<()> {
+        let parsed: super::MqttType = serde_json::from_str(
+            r#"{"command":{"config_version":1,"log_version":2,"pack_type":3,"color":"secondary"}}"#,
+        )?;
+        let super::MqttType::Command {
+            command: super::CommandMappingType::SetStatus { data, .. },
+        } = parsed
+        else {
+            panic!("Expected a SetStatus command, got {parsed:?}");
+        };
+        assert_eq!(data.range(), 0x263..0x266);
+        assert_eq!(&*data.parse(b"[255, 128, 0]")?, &[255, 128, 0]);
+        let color_type = super::CommandStatusType::ColorType {
+            color_type: super::ColorTypeZone::Secondary,
+        };
+        assert_eq!(color_type.range(), 0x260..0x261);
+        assert_eq!(&*color_type.parse(b"2")?, &[2]);
+        Ok(())
+    }
+    #[test]
+    fn color_command_extremes() -> anyhow::Result<()> {
+        for color in [super::ColorZone::Primary, super::ColorZone::Secondary] {
+            let command = super::CommandStatusType::Color { color };
+            assert_eq!(&*command.parse(b"[255, 255, 255]")?, &[255, 255, 255]);
+            assert_eq!(&*command.parse(b"[0, 0, 0]")?, &[0, 0, 0]);
+            assert!(command.parse(b"[256, 0, 0]").is_err());
+        }
+        Ok(())
+    }
+    #[test]
+    fn version_state() -> anyhow::Result<()> {
+        let mapping: super::GenericMapping = serde_json::from_str(
+            r#"{"type": "sensor", "name": "Firmware", "unique_id": "firmware0001", "entity_category": "diagnostic",
+                "json_attributes_topic": {"state": "version"}}"#,
+        )?;
+        assert_eq!(
+            mapping.mqtt_values["json_attributes_topic"],
+            super::MqttType::State {
+                state: super::MappingType::Special(super::SpecialMode::Version),
+                transform: None,
+            }
+        );
+        assert_eq!(mapping.validate(Some(1)), vec![]);
+        Ok(())
+    }
+    #[test]
+    fn watercare_state() -> anyhow::Result<()> {
+        let mapping: super::GenericMapping = serde_json::from_str(
+            r#"{"type": "sensor", "name": "Watercare", "unique_id": "watercare0001",
+                "state_topic": {"state": "watercare_mode"},
+                "json_attributes_topic": {"state": "watercare_state"}}"#,
+        )?;
+        assert_eq!(
+            mapping.mqtt_values["json_attributes_topic"],
+            super::MqttType::State {
+                state: super::MappingType::Special(super::SpecialMode::WatercareState),
+                transform: None,
+            }
+        );
+        assert_eq!(mapping.validate(Some(1)), vec![]);
+        Ok(())
+    }
+    #[test]
+    fn key_press_command() -> anyhow::Result<()> {
+        for (json, code) in [
+            (r#"{"command":{"pack_type":10,"key":"light"}}"#, 16),
+            (r#"{"command":{"pack_type":10,"key":"temp_down"}}"#, 22),
+            (r#"{"command":{"pack_type":10,"key":42}}"#, 42),
+        ] {
+            let parsed: super::MqttType = serde_json::from_str(json)?;
+            let super::MqttType::Command {
+                command: super::CommandMappingType::KeyPress { pack_type, key },
+            } = parsed
+            else {
+                panic!("Expected a KeyPress command, got {parsed:?}");
+            };
+            assert_eq!(pack_type, 10);
+            assert_eq!(key.code(), code);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn state_republished_while_valid() -> anyhow::Result<()> {
+        use super::{next_state_publish, GenericWatchMap};
+        use std::{pin::pin, time::Duration};
+        use tokio::{sync::watch, time::timeout};
+        let interval = Some(Duration::from_millis(50));
+        let (state_sender, state) = watch::channel(0);
+        let mut state = super::WatchMap {
+            watch: state,
+            map: Box::new(|value: &u8| *value),
+            value: None,
+        };
+        let (valid_sender, mut valid) = watch::channel(true);
+        timeout(
+            Duration::from_secs(5),
+            next_state_publish(&mut state, interval, &mut valid),
+        )
+        .await??;
+
+        valid_sender.send(false)?;
+        {
+            // Unchanged states aren't republished while the spa memory isn't valid.
+            let mut publish = pin!(next_state_publish(&mut state, interval, &mut valid));
+            assert!(timeout(Duration::from_millis(200), &mut publish)
+                .await
+                .is_err());
+            valid_sender.send(true)?;
+            timeout(Duration::from_secs(5), &mut publish).await??;
+        }
+
+        valid_sender.send(false)?;
+        state_sender.send(1)?;
+        timeout(
+            Duration::from_secs(5),
+            next_state_publish(&mut state, interval, &mut valid),
+        )
+        .await??;
+        assert_eq!(*state.borrow_and_update(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cover_payload_commands() -> anyhow::Result<()> {
+        use crate::spa::SpaCommand;
+        let mapping: super::GenericMapping = serde_json::from_str(
+            r#"{"type": "cover", "name": "Cover", "unique_id": "cover0001",
+                "state_topic": {"state": {"u8_addr": 20}}, "state_open": "1", "state_closed": "0",
+                "command_topic": {"command": {"payloads": {
+                    "OPEN": {"pack_type": 10, "key": 42},
+                    "CLOSE": {"config_version": 1, "log_version": 2, "pack_type": 3, "u8_addr": 21, "value": 2}
+                }}}}"#,
+        )?;
+        assert_eq!(mapping.validate(Some(22)), vec![]);
+        assert_eq!(
+            mapping.validate(Some(21)),
+            vec![super::ConfigError::OutOfRange {
+                key: "command_topic",
+                range: 21..22,
+                memory_size: 21
+            }]
+        );
+        let super::MqttType::Command { command } = &mapping.mqtt_values["command_topic"] else {
+            panic!("Expected a command");
+        };
+        let (sender, mut commands) = tokio::sync::mpsc::channel(1);
+        let written_memory = Default::default();
+        let topic = "spa/cover/cover0001/1/set";
+        super::run_command(
+            command,
+            topic,
+            b"OPEN",
+            &sender,
+            &written_memory,
+            Default::default(),
+        )
+        .await?;
+        let Some(SpaCommand::KeyPress {
+            pack_type: 10,
+            key: 42,
+            ..
+        }) = commands.recv().await
+        else {
+            panic!("Expected a key press");
+        };
+        let spa = async {
+            let Some(SpaCommand::SetStatus {
+                pos,
+                data,
+                response,
+                ..
+            }) = commands.recv().await
+            else {
+                panic!("Expected a SetStatus");
+            };
+            let _ = response
+                .expect("SetStatus should wait for a response")
+                .send(Ok(()));
+            (pos, data)
+        };
+        let (sent, (pos, data)) = tokio::join!(
+            super::run_command(
+                command,
+                topic,
+                b"CLOSE",
+                &sender,
+                &written_memory,
+                Default::default()
+            ),
+            spa
+        );
+        sent?;
+        assert_eq!((pos, &*data), (21, &[2][..]));
+        super::run_command(
+            command,
+            topic,
+            b"STOP",
+            &sender,
+            &written_memory,
+            Default::default(),
+        )
+        .await?;
+        assert!(commands.try_recv().is_err());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn watercare_mode_command() -> anyhow::Result<()> {
+        use crate::spa::SpaCommand;
+        let command: super::CommandMappingType = serde_json::from_str(r#""watercare_mode""#)?;
+        assert_eq!(
+            command,
+            super::CommandMappingType::Special(super::SpecialMode::WatercareMode)
+        );
+        let (sender, mut commands) = tokio::sync::mpsc::channel(1);
+        let written_memory = Default::default();
+        let topic = "spa/select/watercare0001/set";
+        super::run_command(
+            &command,
+            topic,
+            b"2",
+            &sender,
+            &written_memory,
+            Default::default(),
+        )
+        .await?;
+        let Some(SpaCommand::SetWatercare {
+            mode: 2,
+            response: None,
+        }) = commands.try_recv().ok()
+        else {
+            panic!("Expected a SetWatercare to mode 2");
+        };
+        for payload in [&b"eco"[..], b"", b"256", b"\xff"] {
+            super::run_command(
+                &command,
+                topic,
+                payload,
+                &sender,
+                &written_memory,
+                Default::default(),
+            )
+            .await?;
+            assert!(
+                commands.try_recv().is_err(),
+                "{payload:?} should be rejected"
+            );
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn unconfirmed_write_doesnt_delay_commands() -> anyhow::Result<()> {
+        use crate::spa::SpaCommand;
+        use std::{collections::HashMap, time::Duration};
+        use tokio::{sync::watch, time::timeout};
+        let command: super::CommandMappingType = serde_json::from_str(
+            r#"{"config_version":1,"log_version":2,"pack_type":3,"u8_addr":10}"#,
+        )?;
+        let (sender, mut spa_commands) = tokio::sync::mpsc::channel(1);
+        // The spa never reports the written values.
+        let (_memory, subscription) = watch::channel(Box::from([0u8]));
+        let written_memory = HashMap::from([(10..11, subscription)]);
+        let topic = "spa/number/number0001/set";
+        let spa = async {
+            let mut written = vec![];
+            while let Some(SpaCommand::SetStatus { data, response, .. }) = spa_commands.recv().await
+            {
+                written.push(data[0]);
+                let _ = response
+                    .expect("SetStatus should wait for a response")
+                    .send(Ok(()));
+                if written.len() == 2 {
+                    break;
+                }
+            }
+            written
+        };
+        let commands = async {
+            for payload in [b"1", b"2"] {
+                super::run_command(
+                    &command,
+                    topic,
+                    payload,
+                    &sender,
+                    &written_memory,
+                    Default::default(),
+                )
+                .await?;
+            }
+            anyhow::Ok(())
+        };
+        let (sent, written) = timeout(Duration::from_secs(1), async {
+            tokio::join!(commands, spa)
+        })
+        .await?;
+        sent?;
+        assert_eq!(written, [1, 2]);
+        Ok(())
+    }
+    #[test]
+    fn with_icon_and_category() -> anyhow::Result<()> {
+        let mapping: super::GenericMapping = serde_json::from_str(
+            r#"{"type": "sensor", "name": "Signal", "unique_id": "signal0001", "icon": "mdi:wifi", "entity_category": "diagnostic"}"#,
+        )?;
+        assert_eq!(mapping.icon, Some("mdi:wifi"));
+        assert_eq!(mapping.entity_category, Some("diagnostic"));
+        assert!(mapping.mqtt_values.is_empty());
+        let device = crate::home_assistant::ConfigureDevice {
+            identifiers: Box::from([]),
+            name: "Spa".into(),
+            sw_version: None,
+            extra_args: Default::default(),
+        };
+        let base = crate::home_assistant::ConfigureBase {
+            name: mapping.name,
+            unique_id: mapping.unique_id,
+            device: &device,
+            origin: &crate::home_assistant::ConfigureOrigin::THIS,
+            qos: 0,
+            icon: None,
+            entity_category: mapping.entity_category,
+            expire_after: mapping.expire_after,
+        };
+        assert_eq!(
+            serde_json::to_value(&base)?,
+            serde_json::json!({
+                "name": "Signal",
+                "unique_id": "signal0001",
+                "device": {"identifiers": [], "name": "Spa"},
+                "origin": {
+                    "name": "intouch2-mqtt",
+                    "sw_version": env!("CARGO_PKG_VERSION"),
+                    "support_url": "https://github.com/Ofenhed/intouch2-mqtt",
+                },
+                "qos": 0,
+                "entity_category": "diagnostic",
+            })
+        );
+        Ok(())
+    }
+    #[test]
+    fn state_with_poll() -> anyhow::Result<()> {
+        let parsed: super::MqttType =
+            serde_json::from_str(r#"{"state": {"u8_addr": 100, "poll": 10}}"#)?;
+        let super::MqttType::State { state, .. } = parsed else {
+            panic!("Expected a state mapping, got {parsed:?}");
+        };
+        assert_eq!(state.range(), Some(100..101));
+        assert_eq!(
+            state.poll_interval(),
+            Some(std::time::Duration::from_secs(10))
+        );
+        let parsed: super::MqttType =
+            serde_json::from_str(r#"{"state": {"u8_addr": 100, "poll": 0}}"#)?;
+        assert!(matches!(parsed, super::MqttType::Value(_)));
+        let serialized = serde_json::to_string(&super::MqttType::State {
+            state: super::MappingType::U16 {
+                u16_addr: 4,
+                poll: None,
+            },
+            transform: None,
+        })?;
+        assert_eq!(serialized, r#"{"state":{"u16_addr":4}}"#);
+        Ok(())
+    }
+    #[test]
+    fn state_transform() -> anyhow::Result<()> {
+        use serde_json::json;
+        let parsed: super::MqttType = serde_json::from_str(
+            r#"{"state": {"u16_addr": 100}, "transform": {"decimals": 1, "template": "{value} °C"}}"#,
+        )?;
+        let super::MqttType::State {
+            transform: Some(transform),
+            ..
+        } = parsed
+        else {
+            panic!("Expected a state with a transform, got {parsed:?}");
+        };
+        assert_eq!(transform.apply(&json!(37.46)), json!("37.5 °C"));
+        assert_eq!(transform.apply(&json!(null)), json!(null));
+
+        let rounded = super::StateTransform {
+            decimals: Some(0),
+            template: None,
+        };
+        assert_eq!(rounded.apply(&json!(37.5)), json!(38));
+        assert_eq!(rounded.apply(&json!("on")), json!("on"));
+
+        let templated = super::StateTransform {
+            decimals: None,
+            template: Some("mode {value}".into()),
+        };
+        assert_eq!(templated.apply(&json!("eco")), json!("mode eco"));
+        assert_eq!(templated.apply(&json!(2)), json!("mode 2"));
+        Ok(())
+    }
+    #[test]
+    fn with_expire_after() -> anyhow::Result<()> {
+        let mapping: super::GenericMapping = serde_json::from_str(
+            r#"{"type": "sensor", "name": "Water", "unique_id": "water0001", "expire_after": 600, "state_topic": {"state": {"u8_addr": 100}}}"#,
+        )?;
+        assert_eq!(mapping.expire_after, Some(600));
+        assert!(!mapping.mqtt_values.contains_key("expire_after"));
+        Ok(())
+    }
+    #[test]
+    fn bit_state() -> anyhow::Result<()> {
+        let parsed: super::MqttType =
+            serde_json::from_str(r#"{"state":{"bit_addr":100,"bit":3}}"#)?;
+        let super::MqttType::State { state, .. } = parsed else {
+            panic!("Expected a state, got {parsed:?}");
+        };
+        assert_eq!(
+            state,
+            super::MappingType::Bit {
+                bit_addr: 100,
+                bit: 3,
+                poll: None
+            }
+        );
+        assert_eq!(state.range(), Some(100..101));
+        Ok(())
+    }
+    #[test]
+    fn binary_payloads() -> anyhow::Result<()> {
+        use serde_json::json;
+        let mapping: super::GenericMapping = serde_json::from_str(
+            r#"{"type": "binary_sensor", "name": "Heater", "unique_id": "heater0001", "device_class": "running", "payload_on": "1"}"#,
+        )?;
+        let payloads = super::BinaryPayloads::new(&mapping.mqtt_values);
+        assert_eq!(payloads.payload(&json!(true)), Some("1"));
+        assert_eq!(payloads.payload(&json!(2)), Some("1"));
+        assert_eq!(payloads.payload(&json!(0)), Some("OFF"));
+        assert_eq!(payloads.payload(&json!(null)), None);
+        Ok(())
+    }
+    #[test]
+    fn temperature_unit() {
+        use crate::spa::TemperatureUnit;
+        use serde_json::json;
+        let mut climate = std::collections::HashMap::new();
+        super::insert_temperature_unit(&mut climate, "climate", TemperatureUnit::Fahrenheit);
+        assert_eq!(climate.get("temperature_unit"), Some(&json!("F")));
+
+        let mut sensor = std::collections::HashMap::from([
+            ("device_class", json!("temperature")),
+            ("unit_of_measurement", json!("K")),
+        ]);
+        super::insert_temperature_unit(&mut sensor, "sensor", TemperatureUnit::Celsius);
+        assert_eq!(sensor.get("unit_of_measurement"), Some(&json!("K")));
+
+        let mut light = std::collections::HashMap::new();
+        super::insert_temperature_unit(&mut light, "light", TemperatureUnit::Celsius);
+        assert!(light.is_empty());
+    }
+    #[test]
+    fn optimistic() {
+        use serde_json::json;
+        let mut light = std::collections::HashMap::from([("command_topic", json!("set"))]);
+        super::insert_optimistic(&mut light, "light");
+        assert_eq!(light.get("optimistic"), Some(&json!(true)));
+
+        let mut fan = std::collections::HashMap::from([("state_topic", json!("state"))]);
+        super::insert_optimistic(&mut fan, "fan");
+        assert_eq!(fan.get("optimistic"), Some(&json!(false)));
+
+        let mut climate =
+            std::collections::HashMap::from([("temperature_state_topic", json!("state"))]);
+        super::insert_optimistic(&mut climate, "climate");
+        assert_eq!(climate.get("optimistic"), Some(&json!(false)));
+
+        let mut select = std::collections::HashMap::from([
+            ("state_topic", json!("state")),
+            ("optimistic", json!(true)),
+        ]);
+        super::insert_optimistic(&mut select, "select");
+        assert_eq!(select.get("optimistic"), Some(&json!(true)));
+
+        let mut sensor = std::collections::HashMap::new();
+        super::insert_optimistic(&mut sensor, "sensor");
+        assert!(sensor.is_empty());
+    }
+}
+
+/// The payloads a binary sensor publishes for its on and off states. Home Assistant compares
+/// the state with these, so they are published as is instead of as JSON.
+#[derive(Debug, Clone)]
+struct BinaryPayloads {
+    on: Arc<str>,
+    off: Arc<str>,
+}
+
+impl BinaryPayloads {
+    /// Use `payload_on` and `payload_off` from the mapping, or Home Assistant's `ON` and `OFF`.
+    fn new(mqtt_values: &HashMap<&str, MqttType>) -> Self {
+        let payload = |key, default| match mqtt_values.get(key) {
+            Some(MqttType::Value(serde_json::Value::String(payload))) => {
+                Arc::from(payload.as_str())
+            }
+            _ => Arc::from(default),
+        };
+        Self {
+            on: payload("payload_on", "ON"),
+            off: payload("payload_off", "OFF"),
+        }
+    }
+
+    /// Booleans and numbers are on when they are `true` or non-zero. Other values have no
+    /// payload.
+    fn payload(&self, value: &serde_json::Value) -> Option<&str> {
+        let on = match value {
+            serde_json::Value::Bool(on) => *on,
+            serde_json::Value::Number(number) => number.as_f64() != Some(0.0),
+            _ => return None,
+        };
+        Some(if on { &self.on } else { &self.off })
+    }
+}
+
+/// Add the unit of `unit` to climate and temperature sensor configurations, unless the mapping
+/// already sets it.
+fn insert_temperature_unit(
+    args: &mut HashMap<&str, serde_json::Value>,
+    mqtt_type: &str,
+    unit: TemperatureUnit,
+) {
+    let (key, value) = match mqtt_type {
+        "climate" => ("temperature_unit", unit.home_assistant_unit()),
+        "sensor"
+            if args.get("device_class")
+                == Some(&serde_json::Value::String("temperature".into())) =>
+        {
+            ("unit_of_measurement", unit.unit_of_measurement())
+        }
+        _ => return,
+    };
+    args.entry(key).or_insert_with(|| value.into());
+}
+
+/// Make lights, fans, climates and selects without state feedback optimistic, so that Home
+/// Assistant shows the last command as their state, unless the mapping sets `optimistic` itself.
+fn insert_optimistic(args: &mut HashMap<&str, serde_json::Value>, mqtt_type: &str) {
+    let has_state = match mqtt_type {
+        "light" | "fan" | "select" => args.contains_key("state_topic"),
+        // A climate reports its state through several topics, such as `mode_state_topic`.
+        "climate" => args.keys().any(|key| key.ends_with("state_topic")),
+        _ => return,
+    };
+    args.entry("optimistic").or_insert((!has_state).into());
 }
 
 impl GenericMapping {
@@ -411,7 +1874,6 @@ impl GenericMapping {
 impl Mapping {
     pub async fn reset(&mut self) {
         self.jobs.shutdown().await;
-        self.jobs = JoinSet::new();
         self.uninitialized = vec![];
         self.active.send_replace(false);
     }
@@ -445,27 +1907,35 @@ impl Mapping {
         spa: &SpaConnection,
         mqtt: &mut MqttSession,
     ) -> Result<(), MappingError> {
-        let config_topic = mqtt.topic(&mapping.mqtt_type, &mapping.unique_id, Topic::Config);
+        let topics = self
+            .topics
+            .clone()
+            .unwrap_or_else(|| mqtt.topic_generator());
+        let config_topics = topics.config_topics(&mapping.mqtt_type, &mapping.unique_id);
         let mut counter = 0;
-        let topics = mqtt.topic_generator();
         let GenericMapping {
             mqtt_type,
             name: mqtt_name,
             unique_id,
             mqtt_values,
             qos,
+            icon,
+            entity_category,
+            expire_after,
         } = mapping;
-        let mut next_topic = |topic: Topic| {
+        let binary_payloads =
+            (mqtt_type == "binary_sensor").then(|| BinaryPayloads::new(&mqtt_values));
+        let (mut name_buffer, mut topic_buffer) = (String::new(), String::new());
+        let mut next_topic = |topic: Topic| -> Arc<str> {
             counter += 1;
-            topics.topic(&mqtt_type, &format!("{unique_id}/{counter}"), topic)
+            name_buffer.clear();
+            write!(name_buffer, "{unique_id}/{counter}").expect("Writing to a String can't fail");
+            topics.write_topic(&mut topic_buffer, &mqtt_type, &name_buffer, topic);
+            Arc::from(topic_buffer.as_str())
         };
         let next_qos = {
             let publisher = mqtt.publisher();
-            move || match qos {
-                1 => QosPid::AtLeastOnce(publisher.next_pid()),
-                2 => QosPid::ExactlyOnce(publisher.next_pid()),
-                _ => QosPid::AtMostOnce,
-            }
+            move || qos_pid(qos, || publisher.next_pid())
         };
 
         let device = self.device.clone();
@@ -475,17 +1945,30 @@ impl Mapping {
                     name: &mqtt_name,
                     unique_id: &unique_id,
                     device: &device,
+                    origin: &home_assistant::ConfigureOrigin::THIS,
                     qos,
+                    icon,
+                    entity_category,
+                    expire_after,
                 },
                 args: Default::default(),
             };
+            // Temperatures from Home Assistant are in the unit of the entity, which is the unit of
+            // the spa unless the mapping sets another one.
+            let home_assistant_unit = match mqtt_values.get("temperature_unit") {
+                Some(MqttType::Value(serde_json::Value::String(unit))) => {
+                    TemperatureUnit::from_home_assistant_unit(unit)
+                }
+                _ => None,
+            };
             for (key, value) in &mqtt_values {
                 match value {
-                    MqttType::State { state } => {
+                    MqttType::State { state, transform } => {
                         let topic = next_topic(Topic::State);
                         {
                             let topic = topic.clone();
                             let state = state.clone();
+                            let transform = transform.clone();
                             let mut sender = mqtt.publisher();
                             let mut data_subscription =
                                 state.subscribe(&spa, &mut self.jobs).await?;
@@ -497,6 +1980,10 @@ impl Mapping {
                                 .push(OwnedMutexGuard::mutex(&mutex).clone());
                             let mut first_state_sent = Some(mutex);
                             let next_qos = next_qos.clone();
+                            let binary_payloads = binary_payloads.clone();
+                            let republish_interval = expire_after
+                                .map(|seconds| std::time::Duration::from_secs(seconds.into()) / 2);
+                            let mut state_valid = spa.subscribe_state_valid();
                             self.jobs.spawn(async move {
                                 loop {
                                     if *initialized.borrow_and_update() {
@@ -510,103 +1997,124 @@ impl Mapping {
                                 }
                                 loop {
                                     let reported_value = data_subscription.borrow_and_update();
-                                    let payload = serde_json::to_vec(&reported_value)?;
+                                    let transformed;
+                                    let reported_value = match &transform {
+                                        Some(transform) => {
+                                            transformed = transform.apply(reported_value);
+                                            &transformed
+                                        }
+                                        None => reported_value,
+                                    };
+                                    let payload = match binary_payloads
+                                        .as_ref()
+                                        .and_then(|payloads| payloads.payload(reported_value))
+                                    {
+                                        Some(payload) => Vec::from(payload.as_bytes()),
+                                        None => serde_json::to_vec(&reported_value)?,
+                                    };
                                     sender
-                                        .publish(Path::new(&topic), next_qos(), payload)
+                                        .publish(Path::new(&*topic), next_qos(), payload)
                                         .await?;
                                     let lock: Option<OwnedMutexGuard<()>> =
                                         mem::take(&mut first_state_sent);
                                     drop(lock);
-                                    data_subscription.changed().await?;
+                                    next_state_publish(
+                                        &mut *data_subscription,
+                                        republish_interval,
+                                        &mut state_valid,
+                                    )
+                                    .await?;
                                 }
                             });
                         }
-                        config.args.insert(key.as_ref(), topic.into())
+                        config.args.insert(key.as_ref(), (*topic).into())
                     }
                     MqttType::Command { command } => {
                         let topic = next_topic(Topic::Set);
                         mqtt.mqtt_subscribe(vec![SubscribeTopic {
-                            topic_path: topic.clone(),
+                            topic_path: topic.to_string(),
                             qos: QoS::AtMostOnce,
                         }])
                         .await?;
-                        let mut receiver = mqtt.subscribe();
+                        let mut receiver = mqtt.subscribe_topic(&*topic);
                         let spa_sender = spa.sender();
+                        // The state topics are only updated from the spa memory, so a write is
+                        // confirmed once the spa reports the new value.
+                        let mut ranges = vec![];
+                        command.ranges(&mut ranges);
+                        let mut written_memory = HashMap::new();
+                        for range in ranges {
+                            let memory = spa.subscribe(range.clone()).await;
+                            written_memory.insert(range, memory);
+                        }
+                        let spa_unit = spa.subscribe_temperature_unit().await;
+                        let default_temperature_unit = self.default_temperature_unit;
                         {
                             let topic = topic.clone();
                             let command = command.clone();
                             self.jobs.spawn(async move {
                                 loop {
-                                    match (&command, &receiver.recv().await?.packet()) {
-                                        (
-                                            CommandMappingType::Special(SpecialMode::WatercareMode),
-                                            Packet::Publish(Publish {
-                                                dup: false,
-                                                topic_name,
-                                                payload,
-                                                ..
-                                            }),
-                                        ) if topic_name == &&topic => {
-                                            let Ok(valid_str) =
-                                                String::from_utf8(Vec::from(*payload))
-                                            else {
-                                                eprintln!("Invalid payload from MQTT: {payload:?}");
-                                                continue;
-                                            };
-                                            let Ok(mode) = valid_str.parse() else {
-                                                eprintln!("Invalid payload from MQTT: {valid_str}");
-                                                continue;
+                                    if let Packet::Publish(Publish {
+                                        dup: false,
+                                        topic_name,
+                                        payload,
+                                        ..
+                                    }) = &receiver.recv().await?.packet()
+                                    {
+                                        if **topic_name == *topic {
+                                            let spa = spa_unit
+                                                .as_ref()
+                                                .and_then(|unit| *unit.borrow())
+                                                .map(TemperatureUnit::from_flag)
+                                                .or(default_temperature_unit);
+                                            let units = TemperatureUnits {
+                                                home_assistant: home_assistant_unit.or(spa),
+                                                spa,
                                             };
-                                            spa_sender.send(SpaCommand::SetWatercare(mode)).await?;
-                                        }
-                                        (
-                                            CommandMappingType::SetStatus { config_version, log_version, pack_type, data },
-                                            Packet::Publish(Publish {
-                                                dup: false,
-                                                topic_name,
+                                            run_command(
+                                                &command,
+                                                &topic,
                                                 payload,
-                                                ..
-                                            }),
-                                        ) if topic_name == &topic => {
-                                            let range = data.range();
-                                            let payload = match data.parse(payload) {
-                                                Ok(data) => data,
-                                                Err(e) => {
-                                                    eprintln!("Invalid data from MQTT: {e}");
-                                                    continue;
-                                                }
-                                            };
-                                            if range.len() != payload.len() {
-                                                eprintln!("Data does not match size constraint of {len}: {payload:?}", len = range.len());
-                                                continue;
-                                            }
-                                            spa_sender.send(SpaCommand::SetStatus {
-                                                config_version: *config_version, log_version: *log_version, pack_type: *pack_type, pos: range.start, data: (*payload).into(),
-                                            }).await?;
+                                                &spa_sender,
+                                                &written_memory,
+                                                units,
+                                            )
+                                            .await?;
                                         }
-                                        _ => (),
-                                    };
+                                    }
                                 }
                             });
                         }
-                        config.args.insert(key.as_ref(), topic.into())
+                        config.args.insert(key.as_ref(), (*topic).into())
                     }
                     MqttType::Value(value) => config.args.insert(key.as_ref(), value.clone()),
                 };
             }
-            serde_json::to_vec(&config)?
+            let temperature_unit = spa
+                .temperature_unit()
+                .await
+                .or(self.default_temperature_unit);
+            if let Some(unit) = temperature_unit {
+                insert_temperature_unit(&mut config.args, mqtt_type, unit);
+            }
+            insert_optimistic(&mut config.args, mqtt_type);
+            Arc::<[u8]>::from(serde_json::to_vec(&config)?)
         };
         let mut publisher = mqtt.publisher();
-        let mut publish =
-            pin!(publisher.publish(Path::new(&config_topic), next_qos(), json_config,));
-        loop {
-            select! {
-                publish_result = &mut publish => {
-                    publish_result?;
-                    break
-                }
-                mqtt_result = mqtt.tick() => {
-                    mqtt_result?
+        let config_qos = self.config_qos.unwrap_or(qos);
+        for config_topic in config_topics.iter() {
+            let qos = qos_pid(config_qos, || publisher.next_pid());
+            let mut publish =
+                pin!(publisher.publish(Path::new(&**config_topic), qos, json_config.clone(),));
+            loop {
+                select! {
+                    publish_result = &mut publish => {
+                        publish_result?;
+                        break
+                    }
+                    mqtt_result = mqtt.tick() => {
+                        mqtt_result?
+                    }
                 }
             }
         }
@@ -617,7 +2125,7 @@ impl Mapping {
         select! {
             join_result = self.jobs.join_next() => {
                 if let Some(join_result) = join_result {
-                    join_result??;
+                    join_result?;
                 }
             }
             _ = tokio::time::sleep(tokio::time::Duration::from_millis(1000)), if self.jobs.is_empty() => {},
@@ -628,12 +2136,143 @@ impl Mapping {
 
 impl Mapping {
     pub fn new(device: home_assistant::ConfigureDevice) -> Result<Self, MappingError> {
-        let jobs = JoinSet::new();
+        let jobs = SupervisedTasks::new();
         Ok(Self {
             jobs,
             device,
             uninitialized: vec![],
             active: sync::watch::Sender::new(false),
+            default_temperature_unit: None,
+            topics: None,
+            config_qos: None,
         })
     }
+
+    /// Generate the topics of the entities with `topics` instead of the generator of the session,
+    /// such as one with the base topic of a specific spa.
+    pub fn set_topic_generator(&mut self, topics: TopicGenerator) {
+        self.topics = Some(topics);
+    }
+
+    /// Publish the entity configurations with this QoS instead of the QoS of each entity, so that
+    /// the retained configurations are delivered reliably even when the states aren't.
+    pub fn set_config_qos(&mut self, qos: Option<u8>) {
+        self.config_qos = qos;
+    }
+
+    /// The temperature unit used for climate and temperature sensor entities when the spa
+    /// doesn't report its unit.
+    pub fn set_default_temperature_unit(&mut self, unit: Option<TemperatureUnit>) {
+        self.default_temperature_unit = unit;
+    }
+}
+
+/// Keeps the entities of a [Mapping] configured in Home Assistant. The configuration is sent
+/// again whenever Home Assistant reports `online` on one of the status topics, such as after
+/// Home Assistant has been restarted.
+pub struct MappingSupervisor {
+    pub mapping: Mapping,
+    pub entities: Box<[GenericMapping]>,
+    pub status_topics: Box<[Arc<str>]>,
+    /// The payload Home Assistant publishes to the status topics when it comes online.
+    pub online_payload: Arc<str>,
+    /// How long to wait after sending the configuration before sending the states, to give Home
+    /// Assistant time to subscribe to the state topics.
+    pub configure_delay: std::time::Duration,
+    /// Configure the mapping again this often, even if Home Assistant doesn't report `online`.
+    pub rediscovery_interval: Option<std::time::Duration>,
+    pub verbose: bool,
+}
+
+impl MappingSupervisor {
+    fn is_online_message(&self, packet: &Packet) -> bool {
+        match packet {
+            Packet::Publish(Publish {
+                dup: false,
+                topic_name,
+                payload,
+                ..
+            }) => {
+                *payload == self.online_payload.as_bytes()
+                    && self
+                        .status_topics
+                        .iter()
+                        .any(|topic| *topic_name == topic.as_ref())
+            }
+            _ => false,
+        }
+    }
+
+    /// Subscribe to the status topics, and keep the mapping configured until an error occurs.
+    pub async fn run(
+        mut self,
+        spa: &SpaConnection,
+        mqtt: &mut MqttSession,
+    ) -> Result<std::convert::Infallible, MappingError> {
+        let mut status = mqtt.subscribe_topics(self.status_topics.iter().cloned());
+        let subscriptions: Vec<_> = self
+            .status_topics
+            .iter()
+            .map(|topic| SubscribeTopic {
+                topic_path: topic.to_string(),
+                qos: QoS::AtMostOnce,
+            })
+            .collect();
+        mqtt.mqtt_subscribe(&subscriptions).await?;
+        loop {
+            if self.verbose {
+                eprintln!("Configuring device mapping");
+            }
+            for entity in self.entities.iter() {
+                self.mapping.add_generic(entity.clone(), spa, mqtt).await?;
+            }
+            let mut delay = pin!(tokio::time::sleep(self.configure_delay));
+            loop {
+                select! {
+                    _ = &mut delay => break,
+                    spa_result = spa.tick() => {
+                        let _: () = spa_result?;
+                    }
+                    mqtt_result = mqtt.tick() => {
+                        let _: () = mqtt_result?;
+                    }
+                }
+            }
+            if self.verbose {
+                eprintln!("Waiting for all states to be sent before notifying online");
+            }
+            self.mapping.start(mqtt).await?;
+            if self.verbose {
+                eprintln!("Notifying online");
+            }
+            mqtt.notify_online().await?;
+            let rediscovery_interval = self.rediscovery_interval;
+            let mut rediscovery = pin!(async move {
+                match rediscovery_interval {
+                    Some(interval) => tokio::time::sleep(interval).await,
+                    None => std::future::pending().await,
+                }
+            });
+            let reason = loop {
+                select! {
+                    mapping_result = self.mapping.tick() => {
+                        let _: () = mapping_result?;
+                    }
+                    mqtt_result = mqtt.tick() => {
+                        let _: () = mqtt_result?;
+                    }
+                    packet = status.recv() => {
+                        if self.is_online_message(packet?.packet()) {
+                            break "Got online from home assistant";
+                        }
+                    }
+                    _ = &mut rediscovery => break "Rediscovery interval elapsed",
+                }
+            };
+            if self.verbose {
+                eprintln!("{reason}. Restarting mapping.");
+            }
+            self.mapping.reset().await;
+        }
+    }
 }
@@ -1,10 +1,11 @@
 use mqttrs::*;
 use std::{
+    collections::{HashMap, HashSet},
     net::SocketAddr,
     path::Path,
     pin::{pin, Pin},
     sync::{
-        atomic::{AtomicU16, Ordering},
+        atomic::{AtomicBool, AtomicU16, Ordering},
         Arc,
     },
 };
@@ -13,13 +14,33 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpSocket, TcpStream},
     select,
-    sync::{self, broadcast, mpsc},
-    task::JoinSet,
+    sync::{self, broadcast, mpsc, watch},
     time,
 };
 
+use crate::{health::LinkStats, tasks::SupervisedTasks, SyncBuffers};
+
 const CLIENT_ID: &str = "spa_client";
 
+/// The initial size of the buffers packets are encoded into.
+const ENCODE_BUFFER_SIZE: usize = 4096;
+
+/// The largest packet MQTT allows, which is the largest remaining length plus the fixed header.
+const MAX_PACKET_SIZE: usize = 268_435_455 + 5;
+
+/// Encode `packet` into `buffer`, and grow the buffer until the packet fits.
+fn encode_growing(packet: &Packet, buffer: &mut Vec<u8>) -> Result<usize, mqttrs::Error> {
+    loop {
+        match encode_slice(packet, buffer) {
+            Err(mqttrs::Error::WriteZero) if buffer.len() < MAX_PACKET_SIZE => {
+                let new_len = (buffer.len() * 2).clamp(ENCODE_BUFFER_SIZE, MAX_PACKET_SIZE);
+                buffer.resize(new_len, 0);
+            }
+            result => return result,
+        }
+    }
+}
+
 pub enum MqttAuth<'a> {
     Simple {
         username: &'a str,
@@ -28,15 +49,51 @@ pub enum MqttAuth<'a> {
     None,
 }
 
+/// The payloads published to the availability topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvailabilityPayloads {
+    /// Published by [Session::notify_online]. Home Assistant uses `online` by default.
+    pub available: Arc<str>,
+    /// The last will, which the broker publishes when the connection is lost. Home Assistant
+    /// uses `offline` by default.
+    pub not_available: Arc<str>,
+}
+
+impl Default for AvailabilityPayloads {
+    fn default() -> Self {
+        Self {
+            available: "online".into(),
+            not_available: "offline".into(),
+        }
+    }
+}
+
 pub struct SessionBuilder<'a> {
-    pub discovery_topic: Arc<str>,
+    /// The discovery prefixes of the Home Assistant instances. Entity configurations are
+    /// published to each of them.
+    pub discovery_topics: Box<[Arc<str>]>,
     pub availability_topic: Option<Arc<str>>,
+    /// The QoS of the `online` publish from [Session::notify_online], and of the `offline` last
+    /// will.
+    pub availability_qos: QoS,
+    pub availability_payloads: AvailabilityPayloads,
     pub base_topic: Arc<str>,
     pub target: SocketAddr,
     pub auth: MqttAuth<'a>,
     pub keep_alive: u16,
+    /// How long to wait for the TCP connection to the broker and its `Connack`.
+    pub connect_timeout: time::Duration,
+    /// Ask the broker to drop any previous session state for our client id. When this is false,
+    /// the broker may resume the earlier session, see [Session::session_present].
+    pub clean_session: bool,
     pub publish_retries: u8,
     pub publish_timeout: time::Duration,
+    /// Publishes which were interrupted by a disconnect are resent when the same store is given
+    /// to the next session.
+    pub publish_store: Arc<PublishStore>,
+    /// Remember the last payload of each state topic, and publish them again when the same cache
+    /// is given to the next session.
+    pub state_cache: Option<Arc<StateCache>>,
 }
 
 #[derive(Debug)]
@@ -99,25 +156,774 @@ mod test {
         assert_eq!(packet2.packet(), &packet1_original);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn connack_session_present() -> anyhow::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        let broker = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buffer = [0; 512];
+            let len = stream.read(&mut buffer).await?;
+            assert!(matches!(
+                mqttrs::decode_slice(&buffer[..len])?,
+                Some(mqttrs::Packet::Connect(mqttrs::Connect {
+                    clean_session: false,
+                    ..
+                }))
+            ));
+            let connack = mqttrs::Packet::Connack(mqttrs::Connack {
+                session_present: true,
+                code: mqttrs::ConnectReturnCode::Accepted,
+            });
+            let len = mqttrs::encode_slice(&connack, &mut buffer)?;
+            stream.write_all(&buffer[..len]).await?;
+            anyhow::Ok(stream)
+        });
+        let session = super::SessionBuilder {
+            clean_session: false,
+            ..test_session(target)
+        }
+        .connect()
+        .await?;
+        let _stream = broker.await??;
+        assert!(session.session_present());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_timeout() -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        // Accept the connection, but never answer the Connect.
+        let broker = tokio::spawn(async move { anyhow::Ok(listener.accept().await?) });
+        let timeout = std::time::Duration::from_millis(100);
+        let result = super::SessionBuilder {
+            connect_timeout: timeout,
+            ..test_session(target)
+        }
+        .connect()
+        .await;
+        assert!(matches!(result, Err(super::MqttError::ConnectTimeout(t)) if t == timeout));
+        let _stream = broker.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notify_online_at_most_once() -> anyhow::Result<()> {
+        use mqttrs::{Packet, QosPid};
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        let (published, mut published_rx) = tokio::sync::oneshot::channel();
+        let broker = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut pending = vec![];
+            let will_qos = next_packet(&mut stream, &mut pending, |packet| match packet {
+                Packet::Connect(connect) => Some(connect.last_will.as_ref().map(|will| will.qos)),
+                _ => None,
+            })
+            .await?;
+            assert_eq!(will_qos, Some(mqttrs::QoS::AtMostOnce));
+            let mut buffer = [0; 512];
+            let connack = Packet::Connack(mqttrs::Connack {
+                session_present: false,
+                code: mqttrs::ConnectReturnCode::Accepted,
+            });
+            let len = mqttrs::encode_slice(&connack, &mut buffer)?;
+            stream.write_all(&buffer[..len]).await?;
+            let qospid = next_packet(&mut stream, &mut pending, |packet| match packet {
+                Packet::Publish(publish) if publish.payload == b"online" => {
+                    assert_eq!(publish.topic_name, "intouch2/available");
+                    Some(publish.qospid)
+                }
+                _ => None,
+            })
+            .await?;
+            let _ = published.send(qospid);
+            // Keep the connection open, since nothing is acknowledged for QoS 0.
+            anyhow::Ok(stream)
+        });
+        let mut session = super::SessionBuilder {
+            availability_topic: Some("intouch2/available".into()),
+            availability_qos: mqttrs::QoS::AtMostOnce,
+            ..test_session(target)
+        }
+        .connect()
+        .await?;
+        tokio::time::timeout(std::time::Duration::from_secs(5), session.notify_online()).await??;
+        let qospid = loop {
+            tokio::select! {
+                qospid = &mut published_rx => break qospid?,
+                tick = session.tick() => tick?,
+            }
+        };
+        assert_eq!(qospid, QosPid::AtMostOnce);
+        let _stream = broker.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn state_republished_after_reconnect() -> anyhow::Result<()> {
+        use mqttrs::{Packet, QosPid};
+        use std::{path::Path, sync::Arc, time::Duration};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        let (republished, republished_rx) = tokio::sync::oneshot::channel();
+        let broker = tokio::spawn(async move {
+            let mut streams = vec![];
+            for _ in 0..2 {
+                streams.push(accept_connect(&listener, false).await?);
+            }
+            let (stream, pending) = &mut streams[1];
+            let publish = next_packet(stream, pending, |packet| match packet {
+                Packet::Publish(publish) => Some((
+                    publish.topic_name.to_string(),
+                    publish.payload.to_vec(),
+                    publish.qospid,
+                )),
+                _ => None,
+            })
+            .await?;
+            let _ = republished.send(publish);
+            anyhow::Ok(streams)
+        });
+        let cache = Arc::new(super::StateCache::default());
+        let builder = || super::SessionBuilder {
+            publish_timeout: Duration::from_secs(10),
+            state_cache: Some(cache.clone()),
+            ..test_session(target)
+        };
+
+        let mut session = builder().connect().await?;
+        let mut publisher = session.publisher();
+        let publishes = async {
+            for (topic, payload) in [
+                ("intouch2/sensor/temp/1/state", b"1"),
+                ("intouch2/sensor/temp/1/state", b"2"),
+                ("intouch2/sensor/temp/2/set", b"3"),
+            ] {
+                publisher
+                    .publish(Path::new(topic), QosPid::AtMostOnce, *payload)
+                    .await?;
+            }
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                result = publishes => result,
+                result = async { loop { session.tick().await? } } => {
+                    let _: Result<(), super::MqttError> = result;
+                    anyhow::bail!("Session failed: {result:?}")
+                }
+            }
+        })
+        .await??;
+        drop(session);
+        assert_eq!(cache.len(), 1);
+
+        let mut session = builder().connect().await?;
+        let mut republished_rx = std::pin::pin!(republished_rx);
+        let publish = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    publish = &mut republished_rx => break anyhow::Ok(publish?),
+                    tick = session.tick() => tick?,
+                }
+            }
+        })
+        .await??;
+        assert_eq!(
+            publish,
+            (
+                "intouch2/sensor/temp/1/state".to_string(),
+                b"2".to_vec(),
+                QosPid::AtMostOnce
+            )
+        );
+        drop(session);
+        let _streams = broker.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inbound_publish_acknowledged() -> anyhow::Result<()> {
+        use mqttrs::{Packet, Pid, QosPid};
+        use std::time::Duration;
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        let broker = tokio::spawn(async move {
+            let (mut stream, mut pending) = accept_connect(&listener, false).await?;
+            let mut buffer = [0; 512];
+            let mut send = async |stream: &mut tokio::net::TcpStream, packet: Packet| {
+                let len = mqttrs::encode_slice(&packet, &mut buffer)?;
+                stream.write_all(&buffer[..len]).await?;
+                anyhow::Ok(())
+            };
+            let publish = |qospid, dup| {
+                Packet::Publish(mqttrs::Publish {
+                    dup,
+                    qospid,
+                    retain: false,
+                    topic_name: "intouch2/light/1/set",
+                    payload: b"ON",
+                })
+            };
+            // The session reads one packet at a time, so wait until it's done connecting.
+            next_packet(&mut stream, &mut pending, |packet| {
+                matches!(packet, Packet::Pingreq).then_some(())
+            })
+            .await?;
+            let at_least_once = Pid::try_from(7)?;
+            send(
+                &mut stream,
+                publish(QosPid::AtLeastOnce(at_least_once), false),
+            )
+            .await?;
+            let acknowledged = next_packet(&mut stream, &mut pending, |packet| match packet {
+                Packet::Puback(pid) => Some(*pid),
+                _ => None,
+            })
+            .await?;
+            assert_eq!(acknowledged, at_least_once);
+            let exactly_once = Pid::try_from(8)?;
+            for dup in [false, true] {
+                send(&mut stream, publish(QosPid::ExactlyOnce(exactly_once), dup)).await?;
+                let received = next_packet(&mut stream, &mut pending, |packet| match packet {
+                    Packet::Pubrec(pid) => Some(*pid),
+                    _ => None,
+                })
+                .await?;
+                assert_eq!(received, exactly_once);
+            }
+            send(&mut stream, Packet::Pubrel(exactly_once)).await?;
+            let completed = next_packet(&mut stream, &mut pending, |packet| match packet {
+                Packet::Pubcomp(pid) => Some(*pid),
+                _ => None,
+            })
+            .await?;
+            assert_eq!(completed, exactly_once);
+            anyhow::Ok(stream)
+        });
+        let mut session = test_session(target).connect().await?;
+        let mut subscription = session.subscribe_topic("intouch2/light/+/set");
+        let mut broker = std::pin::pin!(broker);
+        let stream = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    stream = &mut broker => break stream?,
+                    tick = session.tick() => tick?,
+                }
+            }
+        })
+        .await??;
+        let mut delivered = vec![];
+        while let Ok(packet) = subscription.try_recv() {
+            if let Packet::Publish(publish) = packet.packet() {
+                delivered.push(publish.qospid);
+            }
+        }
+        assert_eq!(
+            delivered,
+            [
+                QosPid::AtLeastOnce(Pid::try_from(7)?),
+                QosPid::ExactlyOnce(Pid::try_from(8)?)
+            ]
+        );
+        drop(stream);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn received_packets_outlive_the_read_buffer() -> anyhow::Result<()> {
+        use mqttrs::{Packet, QosPid};
+        use std::time::Duration;
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        let (send_publish, mut publishes) =
+            tokio::sync::mpsc::channel::<(&'static str, &'static [u8])>(1);
+        let broker = tokio::spawn(async move {
+            let (mut stream, _) = accept_connect(&listener, false).await?;
+            let mut buffer = [0; 512];
+            // Each publish is sent on its own, since the session reads one packet at a time.
+            while let Some((topic_name, payload)) = publishes.recv().await {
+                let publish = Packet::Publish(mqttrs::Publish {
+                    dup: false,
+                    qospid: QosPid::AtMostOnce,
+                    retain: false,
+                    topic_name,
+                    payload,
+                });
+                let len = mqttrs::encode_slice(&publish, &mut buffer)?;
+                stream.write_all(&buffer[..len]).await?;
+            }
+            anyhow::Ok(stream)
+        });
+        let mut session = test_session(target).connect().await?;
+        let mut subscription = session.subscribe();
+        let mut receive = async |topic_name, payload| {
+            send_publish.send((topic_name, payload)).await?;
+            let packet = tokio::time::timeout(Duration::from_secs(5), session.recv()).await??;
+            anyhow::Ok(packet)
+        };
+        let first = receive("intouch2/first", b"1").await?;
+        // The second packet is longer, so it overwrites all of the first one in the read buffer.
+        let second = receive(
+            "intouch2/second/with/a/longer/topic",
+            b"a payload which is longer than the first one",
+        )
+        .await?;
+        let broadcast_first = subscription.try_recv()?;
+        let broadcast_second = subscription.try_recv()?;
+        assert!(std::sync::Arc::ptr_eq(&first, &broadcast_first));
+        assert!(std::sync::Arc::ptr_eq(&second, &broadcast_second));
+        drop(session);
+        drop(send_publish);
+        let expected_publish = |topic_name, payload| {
+            Packet::Publish(mqttrs::Publish {
+                dup: false,
+                qospid: QosPid::AtMostOnce,
+                retain: false,
+                topic_name,
+                payload,
+            })
+        };
+        assert_eq!(
+            broadcast_first.packet(),
+            &expected_publish("intouch2/first", b"1")
+        );
+        assert_eq!(
+            broadcast_second.packet(),
+            &expected_publish(
+                "intouch2/second/with/a/longer/topic",
+                b"a payload which is longer than the first one"
+            )
+        );
+        drop(broker.await??);
+        Ok(())
+    }
+
+    #[test]
+    fn next_pid_skips_in_flight() {
+        use mqttrs::{Pid, QosPid};
+        let pids = super::AtomicPid::default();
+        let free = Pid::try_from(1234).unwrap();
+        let in_flight = |pid: Pid| super::InFlightPublish {
+            topic: std::path::Path::new("spa/state").into(),
+            payload: std::sync::Arc::from(&[][..]),
+            qospid: QosPid::AtLeastOnce(pid),
+        };
+        {
+            let mut live = pids.in_flight();
+            for pid in (1..=u16::MAX).filter_map(|pid| Pid::try_from(pid).ok()) {
+                if pid != free {
+                    live.insert(pid, in_flight(pid));
+                }
+            }
+        }
+        assert_eq!(pids.next_pid(), free);
+        // The counter moves on, so the free pid is found again after wrapping around.
+        assert_eq!(pids.next_pid(), free);
+        pids.in_flight().insert(free, in_flight(free));
+        // With every pid in flight, one of them has to be reused rather than waiting forever.
+        pids.next_pid();
+        pids.in_flight().remove(&Pid::try_from(10).unwrap());
+        assert_eq!(pids.next_pid(), Pid::try_from(10).unwrap());
+    }
+
+    #[tokio::test]
+    async fn publish_larger_than_encode_buffer() -> anyhow::Result<()> {
+        use mqttrs::{Packet, QosPid};
+        use std::{path::Path, time::Duration};
+        use tokio::io::AsyncWriteExt;
+        const PAYLOAD_LEN: usize = 3 * super::ENCODE_BUFFER_SIZE;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        let broker = tokio::spawn(async move {
+            let (mut stream, mut pending) = accept_connect(&listener, false).await?;
+            let mut buffer = [0; 512];
+            let mut received = vec![];
+            while received.len() < 2 {
+                let (qospid, payload_len) =
+                    next_packet(&mut stream, &mut pending, |packet| match packet {
+                        Packet::Publish(publish) => Some((publish.qospid, publish.payload.len())),
+                        _ => None,
+                    })
+                    .await?;
+                if let QosPid::AtLeastOnce(pid) = qospid {
+                    let len = mqttrs::encode_slice(&Packet::Puback(pid), &mut buffer)?;
+                    stream.write_all(&buffer[..len]).await?;
+                }
+                received.push(payload_len);
+            }
+            assert_eq!(received, [PAYLOAD_LEN, PAYLOAD_LEN]);
+            anyhow::Ok(stream)
+        });
+        let mut session = super::SessionBuilder {
+            publish_timeout: Duration::from_secs(5),
+            ..test_session(target)
+        }
+        .connect()
+        .await?;
+        let mut publisher = session.publisher();
+        let payload: std::sync::Arc<[u8]> = vec![b'x'; PAYLOAD_LEN].into();
+        let publish = async {
+            // The session encodes publishes without acknowledgement itself, and the others are
+            // encoded by a PacketSender.
+            publisher
+                .publish(
+                    Path::new("intouch2/large/1/config"),
+                    QosPid::AtMostOnce,
+                    payload.clone(),
+                )
+                .await?;
+            let pid = publisher.next_pid();
+            publisher
+                .publish(
+                    Path::new("intouch2/large/2/config"),
+                    QosPid::AtLeastOnce(pid),
+                    payload.clone(),
+                )
+                .await
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                result = publish => result,
+                result = async { loop { session.tick().await? } } => result,
+            }
+        })
+        .await??;
+        let _stream = tokio::time::timeout(Duration::from_secs(5), broker).await???;
+        Ok(())
+    }
+
+    /// A session connecting to the broker at `target`, with the settings shared by the tests.
+    fn test_session(target: std::net::SocketAddr) -> super::SessionBuilder<'static> {
+        super::SessionBuilder {
+            discovery_topics: Box::new(["homeassistant".into()]),
+            availability_topic: None,
+            availability_qos: mqttrs::QoS::AtLeastOnce,
+            availability_payloads: Default::default(),
+            base_topic: "intouch2".into(),
+            target,
+            auth: super::MqttAuth::None,
+            keep_alive: 30,
+            connect_timeout: std::time::Duration::from_secs(10),
+            clean_session: true,
+            publish_retries: 1,
+            publish_timeout: std::time::Duration::from_secs(1),
+            publish_store: Default::default(),
+            state_cache: None,
+        }
+    }
+
+    /// Accept the next client on `listener` and answer its Connect. Returns the stream, and what
+    /// was read from it after the Connect.
+    async fn accept_connect(
+        listener: &tokio::net::TcpListener,
+        session_present: bool,
+    ) -> anyhow::Result<(tokio::net::TcpStream, Vec<u8>)> {
+        use tokio::io::AsyncWriteExt;
+        let (mut stream, _) = listener.accept().await?;
+        let mut pending = vec![];
+        next_packet(&mut stream, &mut pending, |packet| {
+            matches!(packet, mqttrs::Packet::Connect(_)).then_some(())
+        })
+        .await?;
+        let mut buffer = [0; 512];
+        let connack = mqttrs::Packet::Connack(mqttrs::Connack {
+            session_present,
+            code: mqttrs::ConnectReturnCode::Accepted,
+        });
+        let len = mqttrs::encode_slice(&connack, &mut buffer)?;
+        stream.write_all(&buffer[..len]).await?;
+        Ok((stream, pending))
+    }
+
+    /// Read packets from `stream` until `filter` accepts one.
+    async fn next_packet<T>(
+        stream: &mut tokio::net::TcpStream,
+        pending: &mut Vec<u8>,
+        filter: impl Fn(&mqttrs::Packet) -> Option<T>,
+    ) -> anyhow::Result<T> {
+        use tokio::io::AsyncReadExt;
+        let mut buffer = [0; 512];
+        loop {
+            if let Some(packet) = mqttrs::decode_slice(pending)? {
+                let len = mqttrs::encode_slice(&packet, &mut vec![0; pending.len()])?;
+                let accepted = filter(&packet);
+                pending.drain(..len);
+                match accepted {
+                    Some(accepted) => return Ok(accepted),
+                    None => continue,
+                }
+            }
+            let len = stream.read(&mut buffer).await?;
+            anyhow::ensure!(len != 0, "Client disconnected");
+            pending.extend_from_slice(&buffer[..len]);
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_options_override_session() -> anyhow::Result<()> {
+        use mqttrs::{Packet, QosPid};
+        use std::{path::Path, time::Duration};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        let (resent, resent_rx) = tokio::sync::oneshot::channel();
+        let broker = tokio::spawn(async move {
+            let (mut stream, mut pending) = accept_connect(&listener, false).await?;
+            // Never acknowledge the publish, and wait for it to be resent.
+            next_packet(&mut stream, &mut pending, |packet| match packet {
+                Packet::Publish(publish) if publish.dup => Some(()),
+                _ => None,
+            })
+            .await?;
+            let _ = resent.send(());
+            anyhow::Ok(stream)
+        });
+        let mut session = super::SessionBuilder {
+            publish_timeout: Duration::from_secs(600),
+            ..test_session(target)
+        }
+        .connect()
+        .await?;
+        let mut publisher = session.publisher();
+        let pid = publisher.next_pid();
+        let publish = publisher.publish_with(
+            Path::new("intouch2/sensor/temp/1/state"),
+            QosPid::AtLeastOnce(pid),
+            *b"1",
+            super::PublishOptions {
+                retries: Some(2),
+                timeout: Some(Duration::from_millis(400)),
+            },
+        );
+        let result = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                result = publish => result,
+                result = async { loop { session.tick().await? } } => result,
+            }
+        })
+        .await?;
+        assert!(matches!(result, Err(super::MqttError::PublishTimeout)));
+        resent_rx.await?;
+        let _stream = broker.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn publish_resent_after_reconnect() -> anyhow::Result<()> {
+        use mqttrs::{Packet, QosPid};
+        use std::{sync::Arc, time::Duration};
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        let broker = tokio::spawn(async move {
+            let mut publishes = vec![];
+            let mut streams = vec![];
+            for acknowledge in [false, true] {
+                let (mut stream, mut pending) = accept_connect(&listener, true).await?;
+                let mut buffer = [0; 512];
+                let (dup, qospid) = next_packet(&mut stream, &mut pending, |packet| match packet {
+                    Packet::Publish(publish) => Some((publish.dup, publish.qospid)),
+                    _ => None,
+                })
+                .await?;
+                publishes.push((dup, qospid));
+                if acknowledge {
+                    let QosPid::AtLeastOnce(pid) = qospid else {
+                        anyhow::bail!("Unexpected QoS {qospid:?}");
+                    };
+                    let len = mqttrs::encode_slice(&Packet::Puback(pid), &mut buffer)?;
+                    stream.write_all(&buffer[..len]).await?;
+                    // Keep the connection open until the client has read the acknowledgement.
+                    streams.push(stream);
+                }
+            }
+            anyhow::Ok((publishes, streams))
+        });
+        let store = Arc::new(super::PublishStore::default());
+        let builder = || super::SessionBuilder {
+            clean_session: false,
+            publish_timeout: Duration::from_secs(10),
+            publish_store: store.clone(),
+            ..test_session(target)
+        };
+
+        let mut session = builder().connect().await?;
+        let mut publisher = session.publisher();
+        let pid = session.next_pid();
+        tokio::select! {
+            result = publisher.publish(std::path::Path::new("intouch2/test"), QosPid::AtLeastOnce(pid), *b"1") => {
+                anyhow::bail!("Publish finished without an acknowledgement: {result:?}")
+            }
+            result = async { loop { session.tick().await? } } => {
+                let _: Result<(), super::MqttError> = result;
+            }
+        }
+        drop(session);
+        assert_eq!(store.len(), 1);
+        let link_stats = store.link_stats();
+        assert_eq!(link_stats.reconnects, 0);
+        assert!(link_stats.last_error.is_some());
+
+        let mut session = builder().connect().await?;
+        assert_eq!(store.link_stats().reconnects, 1);
+        let acknowledged = async {
+            while !store.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                () = acknowledged => anyhow::Ok(()),
+                result = async { loop { session.tick().await? } } => {
+                    let _: Result<(), super::MqttError> = result;
+                    anyhow::bail!("Session failed: {result:?}")
+                }
+            }
+        })
+        .await??;
+        drop(session);
+        let (publishes, _streams) = broker.await??;
+        assert_eq!(
+            publishes,
+            [
+                (false, QosPid::AtLeastOnce(pid)),
+                (true, QosPid::AtLeastOnce(pid))
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn topic_matches() {
+        use super::topic_matches;
+        assert!(topic_matches(
+            "intouch2/light/1/set",
+            "intouch2/light/1/set"
+        ));
+        assert!(!topic_matches(
+            "intouch2/light/1/set",
+            "intouch2/light/2/set"
+        ));
+        assert!(topic_matches("intouch2/+/1/set", "intouch2/light/1/set"));
+        assert!(!topic_matches("intouch2/+", "intouch2/light/1"));
+        assert!(topic_matches("intouch2/#", "intouch2/light/1/set"));
+        assert!(topic_matches("intouch2/#", "intouch2"));
+        assert!(!topic_matches("intouch2/light", "intouch2"));
+        assert!(topic_matches("#", "homeassistant/status"));
+    }
+
+    #[test]
+    fn topic_generator() {
+        use super::Topic;
+        let topics = super::TopicGenerator {
+            discovery_topics: std::sync::Arc::new([
+                std::path::Path::new("homeassistant/").into(),
+                std::path::Path::new("other").into(),
+            ]),
+            base_topic: std::path::Path::new("intouch2").into(),
+        };
+        assert_eq!(
+            topics.topic("light", "lamp/1", Topic::Config),
+            "homeassistant/light/lamp/1/config"
+        );
+        assert_eq!(
+            topics
+                .config_topics("light", "lamp/1")
+                .iter()
+                .map(|topic| &**topic)
+                .collect::<Vec<_>>(),
+            [
+                "homeassistant/light/lamp/1/config",
+                "other/light/lamp/1/config"
+            ]
+        );
+        assert_eq!(
+            &*topics.topic_arc("light", "lamp/1", Topic::State),
+            "intouch2/light/lamp/1/state"
+        );
+        let mut buffer = String::from("leftovers");
+        topics.write_topic(&mut buffer, "sensor", "temp", Topic::None);
+        assert_eq!(buffer, "intouch2/sensor/temp");
+        let topics = topics
+            .with_base_topic("spa2")
+            .with_discovery_topics(["discovery2"]);
+        assert_eq!(
+            topics.topic("light", "lamp/1", Topic::State),
+            "spa2/light/lamp/1/state"
+        );
+        assert_eq!(
+            &*topics.config_topics("light", "lamp/1"),
+            [std::sync::Arc::from("discovery2/light/lamp/1/config")]
+        );
+    }
+}
+
+/// Whether `topic` matches the MQTT topic filter `filter`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        match (filter_level, topic_levels.next()) {
+            ("#", _) => return true,
+            (_, None) => return false,
+            ("+", Some(_)) => (),
+            (filter_level, Some(topic_level)) if filter_level == topic_level => (),
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
 }
 
+/// The pid counter, and the QoS 1 and 2 publishes which are still waiting for an
+/// acknowledgement. The pids of those publishes are skipped until they are acknowledged, so that
+/// an acknowledgement can't be mistaken for one of a newer publish.
 pub struct AtomicPid {
     pid: AtomicU16,
+    in_flight: std::sync::Mutex<HashMap<Pid, InFlightPublish>>,
 }
 
 impl AtomicPid {
     pub fn next_pid(&self) -> Pid {
+        let in_flight = self.in_flight();
+        // Every pid but 0 is valid, so this many tries reach every pid once.
+        for _ in 0..u16::MAX {
+            let pid = loop {
+                if let Ok(pid) = self.pid.fetch_add(1, Ordering::Relaxed).try_into() {
+                    break pid;
+                }
+            };
+            if !in_flight.contains_key(&pid) {
+                return pid;
+            }
+        }
+        // Every pid is waiting for an acknowledgement. Reusing one is all that can be done.
+        eprintln!("All MQTT packet ids are waiting for an acknowledgement, reusing one");
         loop {
             if let Ok(pid) = self.pid.fetch_add(1, Ordering::Relaxed).try_into() {
                 return pid;
             }
         }
     }
+
+    fn in_flight(&self) -> std::sync::MutexGuard<'_, HashMap<Pid, InFlightPublish>> {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 }
 
 impl Default for AtomicPid {
     fn default() -> Self {
-        Self { pid: 1.into() }
+        Self {
+            pid: 1.into(),
+            in_flight: Default::default(),
+        }
     }
 }
 
@@ -126,25 +932,127 @@ pub struct PublishQueueEntry {
     topic: Arc<Path>,
     payload: Arc<[u8]>,
     qospid: QosPid,
+    /// Set when this is a retransmission of a publish from an earlier session.
+    dup: bool,
+    options: PublishOptions,
     response: sync::oneshot::Sender<Result<(), MqttError>>,
 }
 
+#[derive(Debug, Clone)]
+struct InFlightPublish {
+    topic: Arc<Path>,
+    payload: Arc<[u8]>,
+    qospid: QosPid,
+}
+
+/// The QoS 1 and 2 publishes which haven't been acknowledged yet, and the pid counter used for
+/// them. This outlives a [Session], so that a new session can resend what the previous one didn't
+/// get acknowledged.
+#[derive(Default)]
+pub struct PublishStore {
+    pid: Arc<AtomicPid>,
+    has_connected: AtomicBool,
+    link_stats: std::sync::Mutex<LinkStats>,
+}
+
+/// The payload and QoS last published to each state topic.
+type CachedStates = HashMap<Arc<Path>, (Arc<[u8]>, QoS)>;
+
+/// The last payload published to each state topic, which are the topics ending with `state`.
+#[derive(Default)]
+pub struct StateCache {
+    states: std::sync::Mutex<CachedStates>,
+}
+
+impl StateCache {
+    fn states(&self) -> std::sync::MutexGuard<'_, CachedStates> {
+        self.states
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn store(&self, topic: &Arc<Path>, payload: &Arc<[u8]>, qos: QoS) {
+        let state: &str = Topic::State.into();
+        if topic.file_name() == Some(state.as_ref()) {
+            self.states().insert(topic.clone(), (payload.clone(), qos));
+        }
+    }
+
+    /// The number of state topics with a cached payload.
+    pub fn len(&self) -> usize {
+        self.states().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl PublishStore {
+    fn in_flight(&self) -> std::sync::MutexGuard<'_, HashMap<Pid, InFlightPublish>> {
+        self.pid.in_flight()
+    }
+
+    fn stats(&self) -> std::sync::MutexGuard<'_, LinkStats> {
+        self.link_stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// How many sessions have connected with this store after the first one, and the error which
+    /// last stopped a session.
+    pub fn link_stats(&self) -> LinkStats {
+        self.stats().clone()
+    }
+
+    fn record_connect(&self, result: &Result<Session, MqttError>) {
+        match result {
+            Ok(_) => {
+                if self.has_connected.swap(true, Ordering::Relaxed) {
+                    self.stats().reconnects += 1;
+                }
+            }
+            Err(e) => self.stats().record_error(e),
+        }
+    }
+
+    /// The number of publishes waiting for an acknowledgement.
+    pub fn len(&self) -> usize {
+        self.in_flight().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 pub struct Session {
     stream: TcpStream,
-    jobs: JoinSet<Result<(), MqttError>>,
-    buffer: Box<[u8; 4096]>,
-    discovery_topic: Arc<Path>,
+    jobs: SupervisedTasks<(), MqttError>,
+    buffer: Vec<u8>,
+    discovery_topics: Arc<[Arc<Path>]>,
     availability_topic: Option<Arc<str>>,
+    availability_qos: QoS,
+    availability_payloads: AvailabilityPayloads,
     base_topic: Arc<Path>,
     pid: Arc<AtomicPid>,
-    send_queue: mpsc::Receiver<Box<[u8]>>,
-    send_queue_sender: mpsc::Sender<Box<[u8]>>,
+    publish_store: Arc<PublishStore>,
+    state_cache: Option<Arc<StateCache>>,
+    send_queue: mpsc::Receiver<Vec<u8>>,
+    send_queue_sender: mpsc::Sender<Vec<u8>>,
+    send_buffers: Arc<SendBuffers>,
     publish_queue: mpsc::Receiver<PublishQueueEntry>,
     publish_queue_sender: mpsc::Sender<PublishQueueEntry>,
     subscribers: broadcast::Sender<Arc<MqttPacket>>,
+    topic_subscribers: Vec<(Arc<str>, broadcast::Sender<Arc<MqttPacket>>)>,
+    /// Inbound QoS 2 publishes which have been delivered, but not released by the broker yet.
+    received_exactly_once: HashSet<Pid>,
     publish_timeout: time::Duration,
     publish_retries: u8,
     ping_interval: time::Interval,
+    session_present: bool,
+    connected: watch::Sender<bool>,
+    last_publish: Arc<watch::Sender<Option<time::Instant>>>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -168,7 +1076,7 @@ pub enum MqttError {
     #[error("MQTT Subscribe failed: {0:?}")]
     MqttSubscribeFailed(Box<[SubscribeTopic]>),
     #[error("MQTT Send pipe failed: {0}")]
-    PipeSend(#[from] mpsc::error::SendError<Box<[u8]>>),
+    PipeSend(#[from] mpsc::error::SendError<Vec<u8>>),
     #[error("Failed to receive data from MQTT: {0}")]
     MqttRecvError(#[from] broadcast::error::RecvError),
     #[error("MQTT publish send failed: {0}")]
@@ -179,8 +1087,12 @@ pub enum MqttError {
     MqttPublishReply,
     #[error("Publish timeout")]
     PublishTimeout,
+    #[error("At least one discovery topic is required")]
+    NoDiscoveryTopic,
     #[error("Forwarding MQTT packages to subscribers failed")]
     ForwardToSubscribers,
+    #[error("No Connack from the MQTT broker within {0:?}")]
+    ConnectTimeout(time::Duration),
 }
 
 #[derive(strum::IntoStaticStr)]
@@ -194,17 +1106,25 @@ pub enum Topic {
     None,
 }
 
+/// Encoded packets waiting in the send queue. The session returns them here once they are
+/// written, so that [PacketSender]s can reuse them.
+type SendBuffers = SyncBuffers<10, Vec<u8>>;
+
 #[derive(Clone)]
 pub struct PacketSender {
-    sender: mpsc::Sender<Box<[u8]>>,
-    buffer: Box<[u8; 4096]>,
+    sender: mpsc::Sender<Vec<u8>>,
+    buffer: Vec<u8>,
+    buffers: Arc<SendBuffers>,
     pid: Arc<AtomicPid>,
 }
 
 impl PacketSender {
     pub async fn send(&mut self, packet: &Packet<'_>) -> Result<(), MqttError> {
-        let len = encode_slice(&packet, self.buffer.as_mut())?;
-        self.sender.send(self.buffer[..len].into()).await?;
+        let len = encode_growing(&packet, &mut self.buffer)?;
+        let mut encoded = self.buffers.get();
+        encoded.clear();
+        encoded.extend_from_slice(&self.buffer[..len]);
+        self.sender.send(encoded).await?;
         Ok(())
     }
     pub fn next_pid(&self) -> Pid {
@@ -212,10 +1132,19 @@ impl PacketSender {
     }
 }
 
+/// Overrides of the session's `publish_retries` and `publish_timeout` for a single publish.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PublishOptions {
+    pub retries: Option<u8>,
+    pub timeout: Option<time::Duration>,
+}
+
 #[derive(Clone)]
 pub struct PacketPublisher {
     sender: mpsc::Sender<PublishQueueEntry>,
     pid: Arc<AtomicPid>,
+    last_publish: Arc<watch::Sender<Option<time::Instant>>>,
+    state_cache: Option<Arc<StateCache>>,
 }
 
 impl PacketPublisher {
@@ -224,51 +1153,141 @@ impl PacketPublisher {
         topic: impl Into<Arc<Path>>,
         qos: QosPid,
         payload: impl Into<Arc<[u8]>>,
+    ) -> Result<(), MqttError> {
+        self.publish_with(topic, qos, payload, PublishOptions::default())
+            .await
+    }
+
+    /// Like [Self::publish], with the retries and timeout of the session replaced by the ones set
+    /// in `options`.
+    pub async fn publish_with(
+        &mut self,
+        topic: impl Into<Arc<Path>>,
+        qos: QosPid,
+        payload: impl Into<Arc<[u8]>>,
+        options: PublishOptions,
     ) -> Result<(), MqttError> {
         let (tx, rx) = sync::oneshot::channel();
+        let (topic, payload) = (topic.into(), payload.into());
+        let cached = self
+            .state_cache
+            .as_ref()
+            .map(|cache| (cache, topic.clone(), payload.clone()));
         let package = PublishQueueEntry {
-            topic: topic.into(),
-            payload: payload.into(),
+            topic,
+            payload,
             qospid: qos,
+            dup: false,
+            options,
             response: tx,
         };
         self.sender.send(package).await?;
-        Ok(rx.await??)
+        let () = rx.await??;
+        self.last_publish.send_replace(Some(time::Instant::now()));
+        if let Some((cache, topic, payload)) = cached {
+            cache.store(&topic, &payload, qos.qos());
+        }
+        Ok(())
     }
     pub fn next_pid(&self) -> Pid {
         self.pid.next_pid()
     }
 }
 
+#[derive(Clone)]
 pub struct TopicGenerator {
-    discovery_topic: Arc<Path>,
+    discovery_topics: Arc<[Arc<Path>]>,
     base_topic: Arc<Path>,
 }
+
+fn push_topic_level(output: &mut String, level: &str) {
+    if !output.is_empty() && !output.ends_with('/') {
+        output.push('/');
+    }
+    output.push_str(level);
+}
+
 impl TopicGenerator {
+    /// Use `base_topic` instead of the base topic of the session, so that the entities of one spa
+    /// can be kept apart from the others.
+    pub fn with_base_topic(mut self, base_topic: impl AsRef<Path>) -> Self {
+        self.base_topic = base_topic.as_ref().into();
+        self
+    }
+
+    /// Use `discovery_topics` instead of the discovery prefixes of the session. Nothing is changed
+    /// if `discovery_topics` is empty, since config topics need a discovery prefix.
+    pub fn with_discovery_topics<T: AsRef<Path>>(
+        mut self,
+        discovery_topics: impl IntoIterator<Item = T>,
+    ) -> Self {
+        let discovery_topics: Arc<[Arc<Path>]> = discovery_topics
+            .into_iter()
+            .map(|topic| topic.as_ref().into())
+            .collect();
+        if !discovery_topics.is_empty() {
+            self.discovery_topics = discovery_topics;
+        }
+        self
+    }
+
     #[inline(always)]
     pub fn topic(&self, r#type: &str, name: &str, topic: Topic) -> String {
-        match topic {
+        let mut output = String::new();
+        self.write_topic(&mut output, r#type, name, topic);
+        output
+    }
+
+    /// Generate a topic which will be shared, such as the config topic of an entity.
+    pub fn topic_arc(&self, r#type: &str, name: &str, topic: Topic) -> Arc<str> {
+        Arc::from(self.topic(r#type, name, topic))
+    }
+
+    /// The config topic of an entity under every discovery prefix.
+    pub fn config_topics(&self, r#type: &str, name: &str) -> Box<[Arc<str>]> {
+        let mut output = String::new();
+        self.discovery_topics
+            .iter()
+            .map(|root| {
+                Self::write_topic_in(&mut output, root, r#type, name, Topic::Config);
+                Arc::from(output.as_str())
+            })
+            .collect()
+    }
+
+    /// Write a topic into `output`, replacing its previous content. This allows a single buffer
+    /// to be reused when generating many topics. Config topics use the first discovery prefix,
+    /// see [Self::config_topics] for all of them.
+    pub fn write_topic(&self, output: &mut String, r#type: &str, name: &str, topic: Topic) {
+        let root = match topic {
             Topic::Config => self
-                .discovery_topic
-                .join(r#type)
-                .join(name)
-                .join(<&str as From<_>>::from(topic)),
-            Topic::None => self.base_topic.join(r#type).join(name),
-            topic => self
-                .base_topic
-                .join(r#type)
-                .join(name)
-                .join(<&str as From<_>>::from(topic)),
+                .discovery_topics
+                .first()
+                .expect("Sessions always have a discovery prefix"),
+            _ => &self.base_topic,
+        };
+        Self::write_topic_in(output, root, r#type, name, topic)
+    }
+
+    fn write_topic_in(output: &mut String, root: &Path, r#type: &str, name: &str, topic: Topic) {
+        output.clear();
+        push_topic_level(
+            output,
+            root.to_str()
+                .expect("Topics are always created from valid UTF-8"),
+        );
+        push_topic_level(output, r#type);
+        push_topic_level(output, name);
+        if !matches!(topic, Topic::None) {
+            push_topic_level(output, topic.into());
         }
-        .to_string_lossy()
-        .to_string()
     }
 }
 
 impl Session {
     pub fn topic_generator(&self) -> TopicGenerator {
         TopicGenerator {
-            discovery_topic: self.discovery_topic.clone(),
+            discovery_topics: self.discovery_topics.clone(),
             base_topic: self.base_topic.clone(),
         }
     }
@@ -276,18 +1295,106 @@ impl Session {
     pub fn topic(&self, r#type: &str, name: &str, topic: Topic) -> String {
         self.topic_generator().topic(r#type, name, topic)
     }
+    #[inline(always)]
+    pub fn topic_arc(&self, r#type: &str, name: &str, topic: Topic) -> Arc<str> {
+        self.topic_generator().topic_arc(r#type, name, topic)
+    }
+    #[inline(always)]
+    pub fn config_topics(&self, r#type: &str, name: &str) -> Box<[Arc<str>]> {
+        self.topic_generator().config_topics(r#type, name)
+    }
     pub fn next_pid(&self) -> Pid {
         self.pid.next_pid()
     }
 
+    /// Whether the broker resumed a previous session when we connected. If it did, the broker
+    /// still holds our subscriptions, and subscribing again would duplicate them.
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    pub fn availability_payloads(&self) -> &AvailabilityPayloads {
+        &self.availability_payloads
+    }
+
+    /// Subscribe to whether this session is connected to the broker. The value becomes `false`
+    /// when the session is dropped.
+    pub fn subscribe_connected(&self) -> watch::Receiver<bool> {
+        self.connected.subscribe()
+    }
+
+    /// See [PublishStore::link_stats].
+    pub fn link_stats(&self) -> LinkStats {
+        self.publish_store.link_stats()
+    }
+
+    /// Subscribe to when a [PacketPublisher] of this session last got a publish acknowledged.
+    pub fn subscribe_last_publish(&self) -> watch::Receiver<Option<time::Instant>> {
+        self.last_publish.subscribe()
+    }
+
     pub fn subscribe(&mut self) -> broadcast::Receiver<Arc<MqttPacket>> {
         self.subscribers.subscribe()
     }
 
+    /// Subscribe to the publishes whose topic matches `filter`, which may contain the MQTT `+`
+    /// and `#` wildcards. This only filters what the session has already received, use
+    /// [Self::mqtt_subscribe] to get the broker to send the topic.
+    pub fn subscribe_topic(
+        &mut self,
+        filter: impl Into<Arc<str>>,
+    ) -> broadcast::Receiver<Arc<MqttPacket>> {
+        let filter = filter.into();
+        if let Some((_, subscribers)) = self
+            .topic_subscribers
+            .iter()
+            .find(|(existing, _)| *existing == filter)
+        {
+            return subscribers.subscribe();
+        }
+        let subscribers = broadcast::Sender::new(10);
+        let receiver = subscribers.subscribe();
+        self.topic_subscribers.push((filter, subscribers));
+        receiver
+    }
+
+    /// Subscribe to the publishes whose topic matches any of `filters`, see
+    /// [Self::subscribe_topic]. A publish matching more than one filter is only received once.
+    pub fn subscribe_topics<T: Into<Arc<str>>>(
+        &mut self,
+        filters: impl IntoIterator<Item = T>,
+    ) -> broadcast::Receiver<Arc<MqttPacket>> {
+        let subscribers = broadcast::Sender::new(10);
+        let receiver = subscribers.subscribe();
+        self.topic_subscribers.extend(
+            filters
+                .into_iter()
+                .map(|filter| (filter.into(), subscribers.clone())),
+        );
+        receiver
+    }
+
+    fn send_to_topic_subscribers(&mut self, package: &Arc<MqttPacket>) {
+        let Packet::Publish(Publish { topic_name, .. }) = package.packet() else {
+            return;
+        };
+        let mut sent_to: Vec<broadcast::Sender<_>> = vec![];
+        self.topic_subscribers.retain(|(filter, subscribers)| {
+            if topic_matches(filter, topic_name)
+                && !sent_to.iter().any(|sent| sent.same_channel(subscribers))
+            {
+                let _orphan_subscriptions_are_ok = subscribers.send(package.clone());
+                sent_to.push(subscribers.clone());
+            }
+            subscribers.receiver_count() != 0
+        });
+    }
+
     pub fn sender(&self) -> PacketSender {
         PacketSender {
             sender: self.send_queue_sender.clone(),
-            buffer: Box::new([0; 4096]),
+            buffer: vec![0; ENCODE_BUFFER_SIZE],
+            buffers: self.send_buffers.clone(),
             pid: self.pid.clone(),
         }
     }
@@ -296,6 +1403,8 @@ impl Session {
         PacketPublisher {
             sender: self.publish_queue_sender.clone(),
             pid: self.pid.clone(),
+            last_publish: self.last_publish.clone(),
+            state_cache: self.state_cache.clone(),
         }
     }
 
@@ -308,7 +1417,7 @@ impl Session {
             pid: subscribe_pid,
             topics: topics.as_ref().into(),
         });
-        let encoded_len = encode_slice(&packet, self.buffer.as_mut())?;
+        let encoded_len = encode_growing(&packet, &mut self.buffer)?;
         let sleep_duration = self.publish_timeout / self.publish_retries.into();
         for _ in 0..usize::from(self.publish_retries) {
             self.stream.write_all(&self.buffer[..encoded_len]).await?;
@@ -347,7 +1456,10 @@ impl Session {
     }
 
     pub async fn tick(&mut self) -> Result<(), MqttError> {
-        _ = self.recv().await?;
+        if let Err(e) = self.recv().await {
+            self.publish_store.stats().record_error(&e);
+            return Err(e);
+        }
         Ok(())
     }
 
@@ -360,103 +1472,135 @@ impl Session {
                     match package.packet {
                         Packet::Pingreq => {
                             let response = Packet::Pingresp;
-                            let len = encode_slice(&response, self.buffer.as_mut())?;
+                            let len = encode_growing(&response, &mut self.buffer)?;
                             self.stream.write_all(&self.buffer[..len]).await?;
                             continue;
                         },
                         Packet::Pingresp => continue,
+                        Packet::Publish(Publish { qospid: QosPid::AtLeastOnce(pid), .. }) => {
+                            let len = encode_growing(&Packet::Puback(pid), &mut self.buffer)?;
+                            self.stream.write_all(&self.buffer[..len]).await?;
+                        },
+                        Packet::Publish(Publish { qospid: QosPid::ExactlyOnce(pid), .. }) => {
+                            let len = encode_growing(&Packet::Pubrec(pid), &mut self.buffer)?;
+                            self.stream.write_all(&self.buffer[..len]).await?;
+                            // The broker resends the publish until it gets our Pubrec, but it must
+                            // only be delivered once.
+                            if !self.received_exactly_once.insert(pid) {
+                                continue;
+                            }
+                        },
+                        Packet::Pubrel(pid) => {
+                            self.received_exactly_once.remove(&pid);
+                            let len = encode_growing(&Packet::Pubcomp(pid), &mut self.buffer)?;
+                            self.stream.write_all(&self.buffer[..len]).await?;
+                            continue;
+                        },
                         _ => (),
                     }
                     let package = Arc::new(package);
+                    self.send_to_topic_subscribers(&package);
                     let _orphan_subscriptions_are_ok = self.subscribers.send(package.clone());
                     return Ok(package)
                 },
                 _ = self.ping_interval.tick() => {
                     let response = Packet::Pingreq;
-                    let len = encode_slice(&response, self.buffer.as_mut())?;
+                    let len = encode_growing(&response, &mut self.buffer)?;
                     self.stream.write_all(&self.buffer[..len]).await?;
                 },
                 to_send = self.send_queue.recv() => {
                     if let Some(send) = to_send {
                         self.stream.write_all(send.as_ref()).await?;
+                        self.send_buffers.release(send);
                     }
                 },
                 job_result = self.jobs.join_next(), if !self.jobs.is_empty() => {
                     if let Some(job_result) = job_result {
-                        let _: () = job_result??;
+                        let _: () = job_result?;
                     }
                 }
                 to_publish = self.publish_queue.recv() => {
-                    if let Some(PublishQueueEntry { topic, payload, qospid: pid, response }) = to_publish {
-                        let publish_retries = self.publish_retries;
-                        let publish_timeout = self.publish_timeout;
+                    if let Some(PublishQueueEntry { topic, payload, qospid: pid, dup, options, response }) = to_publish {
+                        let publish_retries = options.retries.unwrap_or(self.publish_retries);
+                        let publish_timeout = options.timeout.unwrap_or(self.publish_timeout);
                         let topic_name = topic.display().to_string();
                         if matches!(pid, QosPid::AtMostOnce) {
                             let packet = Packet::Publish(Publish { dup: false, qospid: pid, retain: false, topic_name: &topic_name, payload: &payload });
-                            let len = encode_slice(&packet, self.buffer.as_mut())?;
+                            let len = encode_growing(&packet, &mut self.buffer)?;
                             response.send(self.stream.write_all(&self.buffer[..len]).await.map_err(Into::into)).map_err(|_| MqttError::MqttPublishReply)?;
                         } else {
                             let mut sender = self.sender();
                             let mut receiver = self.subscribe();
+                            let (QosPid::AtLeastOnce(stored_pid) | QosPid::ExactlyOnce(stored_pid)) = pid else {
+                                unreachable!("AtMostOnce is handled above")
+                            };
+                            self.publish_store.in_flight().insert(stored_pid, InFlightPublish { topic, payload: payload.clone(), qospid: pid });
+                            let publish_store = self.publish_store.clone();
                             self.jobs.spawn(async move {
-                                let timeout = match pid {
-                                    QosPid::AtMostOnce => unreachable!(),
-                                    QosPid::AtLeastOnce(_) => publish_timeout / publish_retries.into(),
-                                    QosPid::ExactlyOnce(_) => publish_timeout,
-                                };
-                                let real_timeout = (std::time::Instant::now() + publish_timeout).into();
-                                for attempt in 0 ..= usize::from(publish_retries) {
-                                    let packet = Packet::Publish(Publish { dup: attempt != 0, qospid: pid, retain: false, topic_name: &topic_name, payload: &payload });
-                                    if let Err(e) = sender.send(&packet).await {
-                                        response.send(Err(e)).map_err(|_| MqttError::MqttPublishReply)?;
-                                        return Ok(());
-                                    }
-                                    let timeout = (std::time::Instant::now() + timeout).into();
-                                    'keep_waiting: loop {
-                                        match pid {
-                                            QosPid::AtMostOnce => unreachable!(),
-                                            qos@QosPid::AtLeastOnce(pid) | qos@QosPid::ExactlyOnce(pid) => select! {
-                                                _ = tokio::time::sleep_until(real_timeout) => {
-                                                    response.send(Err(MqttError::PublishTimeout)).map_err(|_| MqttError::MqttPublishReply)?;
-                                                    return Ok(());
-                                                }
-                                                _ = tokio::time::sleep_until(timeout) => {
-                                                    match qos {
-                                                        QosPid::AtLeastOnce(_) => break 'keep_waiting,
-                                                        QosPid::ExactlyOnce(_) => {
-                                                            response.send(Err(MqttError::PublishTimeout)).map_err(|_| MqttError::MqttPublishReply)?;
-                                                            return Ok(());
-                                                        },
-                                                        QosPid::AtMostOnce => unreachable!(),
+                                // The publish stays in the store if this job is aborted because the
+                                // session is dropped, so that the next session can resend it.
+                                let result = async move {
+                                    let timeout = match pid {
+                                        QosPid::AtMostOnce => unreachable!(),
+                                        QosPid::AtLeastOnce(_) => publish_timeout / publish_retries.max(1).into(),
+                                        QosPid::ExactlyOnce(_) => publish_timeout,
+                                    };
+                                    let real_timeout = (std::time::Instant::now() + publish_timeout).into();
+                                    for attempt in 0 ..= usize::from(publish_retries) {
+                                        let packet = Packet::Publish(Publish { dup: dup || attempt != 0, qospid: pid, retain: false, topic_name: &topic_name, payload: &payload });
+                                        if let Err(e) = sender.send(&packet).await {
+                                            response.send(Err(e)).map_err(|_| MqttError::MqttPublishReply)?;
+                                            return Ok(());
+                                        }
+                                        let timeout = (std::time::Instant::now() + timeout).into();
+                                        'keep_waiting: loop {
+                                            match pid {
+                                                QosPid::AtMostOnce => unreachable!(),
+                                                qos@QosPid::AtLeastOnce(pid) | qos@QosPid::ExactlyOnce(pid) => select! {
+                                                    _ = tokio::time::sleep_until(real_timeout) => {
+                                                        response.send(Err(MqttError::PublishTimeout)).map_err(|_| MqttError::MqttPublishReply)?;
+                                                        return Ok(());
                                                     }
-                                                }
-                                                package = receiver.recv() => {
-                                                    let package = match package {
-                                                        Ok(package) => package,
-                                                        Err(e) => {
-                                                            response.send(Err(e.into())).map_err(|_| MqttError::MqttPublishReply)?;
-                                                            return Ok(())
+                                                    _ = tokio::time::sleep_until(timeout) => {
+                                                        match qos {
+                                                            QosPid::AtLeastOnce(_) => break 'keep_waiting,
+                                                            QosPid::ExactlyOnce(_) => {
+                                                                response.send(Err(MqttError::PublishTimeout)).map_err(|_| MqttError::MqttPublishReply)?;
+                                                                return Ok(());
+                                                            },
+                                                            QosPid::AtMostOnce => unreachable!(),
                                                         }
-                                                    };
-                                                    match package.packet {
-                                                        Packet::Puback(ack_pid) if ack_pid == pid => {
-                                                            response.send(Ok(())).map_err(|_| MqttError::MqttPublishReply)?;
-                                                            return Ok(())
-                                                        }
-                                                        Packet::Pubrec(ack_pid) if ack_pid == pid => {
-                                                            sender.send(&Packet::Pubrel(ack_pid)).await?;
-                                                            response.send(Ok(())).map_err(|_| MqttError::MqttPublishReply)?;
-                                                            return Ok(())
+                                                    }
+                                                    package = receiver.recv() => {
+                                                        let package = match package {
+                                                            Ok(package) => package,
+                                                            Err(e) => {
+                                                                response.send(Err(e.into())).map_err(|_| MqttError::MqttPublishReply)?;
+                                                                return Ok(())
+                                                            }
+                                                        };
+                                                        match package.packet {
+                                                            Packet::Puback(ack_pid) if ack_pid == pid => {
+                                                                response.send(Ok(())).map_err(|_| MqttError::MqttPublishReply)?;
+                                                                return Ok(())
+                                                            }
+                                                            Packet::Pubrec(ack_pid) if ack_pid == pid => {
+                                                                sender.send(&Packet::Pubrel(ack_pid)).await?;
+                                                                response.send(Ok(())).map_err(|_| MqttError::MqttPublishReply)?;
+                                                                return Ok(())
+                                                            }
+                                                            _ => (),
                                                         }
-                                                        _ => (),
                                                     }
-                                                }
-                                            },
+                                                },
+                                            }
                                         }
                                     }
-                                }
-                                response.send(Err(MqttError::PublishTimeout)).map_err(|_| MqttError::MqttPublishReply)?;
-                                Ok(())
+                                    response.send(Err(MqttError::PublishTimeout)).map_err(|_| MqttError::MqttPublishReply)?;
+                                    Ok(())
+                                }.await;
+                                publish_store.in_flight().remove(&stored_pid);
+                                result
                             });
                         }
                     }
@@ -471,12 +1615,14 @@ impl Session {
             .as_ref()
             .map(|path| Arc::from(Path::new(&**path)))
         {
+            let qos = match self.availability_qos {
+                QoS::AtMostOnce => QosPid::AtMostOnce,
+                QoS::AtLeastOnce => QosPid::AtLeastOnce(self.next_pid()),
+                QoS::ExactlyOnce => QosPid::ExactlyOnce(self.next_pid()),
+            };
             let mut publisher = self.publisher();
-            let mut publish = pin!(publisher.publish(
-                availability_topic,
-                QosPid::AtLeastOnce(self.next_pid()),
-                *b"online"
-            ));
+            let payload = Arc::<[u8]>::from(self.availability_payloads.available.as_bytes());
+            let mut publish = pin!(publisher.publish(availability_topic, qos, payload));
             loop {
                 select! {
                     publish_result = &mut publish => {
@@ -493,7 +1639,7 @@ impl Session {
     }
 
     pub async fn send(&mut self, packet: &Packet<'_>) -> Result<(), MqttError> {
-        let encoded_len = encode_slice(&packet, self.buffer.as_mut())?;
+        let encoded_len = encode_growing(&packet, &mut self.buffer)?;
         self.stream.write_all(&self.buffer[..encoded_len]).await?;
         Ok(())
     }
@@ -501,11 +1647,21 @@ impl Session {
 
 impl SessionBuilder<'_> {
     pub async fn connect(self) -> Result<Session, MqttError> {
+        let publish_store = self.publish_store.clone();
+        let session = self.open().await;
+        publish_store.record_connect(&session);
+        session
+    }
+
+    async fn open(self) -> Result<Session, MqttError> {
+        if self.discovery_topics.is_empty() {
+            return Err(MqttError::NoDiscoveryTopic);
+        }
         let last_will = if let Some(topic) = self.availability_topic.as_deref() {
             Some(LastWill {
                 topic,
-                message: b"offline",
-                qos: QoS::AtMostOnce,
+                message: self.availability_payloads.not_available.as_bytes(),
+                qos: self.availability_qos,
                 retain: false,
             })
         } else {
@@ -515,7 +1671,7 @@ impl SessionBuilder<'_> {
             protocol: Protocol::MQTT311,
             keep_alive: self.keep_alive,
             client_id: CLIENT_ID.into(),
-            clean_session: true,
+            clean_session: self.clean_session,
             last_will,
             username: None,
             password: None,
@@ -524,16 +1680,21 @@ impl SessionBuilder<'_> {
             connect.username = Some(username);
             connect.password = Some(password.as_bytes());
         }
-        let mut buffer = Box::new([0; 4096]);
+        let mut buffer = vec![0; ENCODE_BUFFER_SIZE];
         let packet = Packet::Connect(connect);
-        let packet_len = encode_slice(&packet, buffer.as_mut())?;
+        let packet_len = encode_growing(&packet, &mut buffer)?;
         let connection = match self.target {
             SocketAddr::V4(_) => TcpSocket::new_v4()?,
             SocketAddr::V6(_) => TcpSocket::new_v6()?,
         };
-        let mut stream = connection.connect(self.target).await?;
-        stream.write_all(&buffer[..packet_len]).await?;
-        let bytes_read = stream.read(buffer.as_mut()).await?;
+        let (stream, bytes_read) = time::timeout(self.connect_timeout, async {
+            let mut stream = connection.connect(self.target).await?;
+            stream.write_all(&buffer[..packet_len]).await?;
+            let bytes_read = stream.read(buffer.as_mut()).await?;
+            Ok::<_, MqttError>((stream, bytes_read))
+        })
+        .await
+        .map_err(|_| MqttError::ConnectTimeout(self.connect_timeout))??;
         let Some(response) = decode_slice(&buffer[..bytes_read])? else {
             return Err(MqttError::NotEnoughData(buffer[..bytes_read].into()))?;
         };
@@ -546,22 +1707,121 @@ impl SessionBuilder<'_> {
                         time::Instant::now(),
                         time::Duration::from_secs((self.keep_alive >> 1).into()),
                     );
+                    let mut jobs = SupervisedTasks::new();
+                    let interrupted: Vec<_> =
+                        self.publish_store.in_flight().values().cloned().collect();
+                    if !interrupted.is_empty() {
+                        let publish_queue_sender = publish_queue_sender.clone();
+                        jobs.spawn(async move {
+                            let mut responses = Vec::with_capacity(interrupted.len());
+                            for InFlightPublish {
+                                topic,
+                                payload,
+                                qospid,
+                            } in interrupted
+                            {
+                                let (response, result) = sync::oneshot::channel();
+                                publish_queue_sender
+                                    .send(PublishQueueEntry {
+                                        topic: topic.clone(),
+                                        payload,
+                                        qospid,
+                                        dup: true,
+                                        options: PublishOptions::default(),
+                                        response,
+                                    })
+                                    .await?;
+                                responses.push((topic, result));
+                            }
+                            for (topic, result) in responses {
+                                if let Err(e) = result.await? {
+                                    eprintln!(
+                                        "Resending publish to {} failed: {e}",
+                                        topic.display()
+                                    );
+                                }
+                            }
+                            Ok(())
+                        });
+                    }
+                    let cached: Vec<_> = self
+                        .state_cache
+                        .iter()
+                        .flat_map(|cache| {
+                            cache
+                                .states()
+                                .iter()
+                                .map(|(topic, (payload, qos))| {
+                                    (topic.clone(), payload.clone(), *qos)
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                        .collect();
+                    if !cached.is_empty() {
+                        let publish_queue_sender = publish_queue_sender.clone();
+                        let pid = self.publish_store.pid.clone();
+                        jobs.spawn(async move {
+                            let mut responses = Vec::with_capacity(cached.len());
+                            for (topic, payload, qos) in cached {
+                                let qospid = match qos {
+                                    QoS::AtMostOnce => QosPid::AtMostOnce,
+                                    QoS::AtLeastOnce => QosPid::AtLeastOnce(pid.next_pid()),
+                                    QoS::ExactlyOnce => QosPid::ExactlyOnce(pid.next_pid()),
+                                };
+                                let (response, result) = sync::oneshot::channel();
+                                publish_queue_sender
+                                    .send(PublishQueueEntry {
+                                        topic: topic.clone(),
+                                        payload,
+                                        qospid,
+                                        dup: false,
+                                        options: PublishOptions::default(),
+                                        response,
+                                    })
+                                    .await?;
+                                responses.push((topic, result));
+                            }
+                            for (topic, result) in responses {
+                                if let Err(e) = result.await? {
+                                    eprintln!(
+                                        "Republishing state to {} failed: {e}",
+                                        topic.display()
+                                    );
+                                }
+                            }
+                            Ok(())
+                        });
+                    }
                     Ok(Session {
                         stream,
                         buffer,
-                        jobs: JoinSet::new(),
+                        jobs,
                         availability_topic: self.availability_topic,
+                        availability_qos: self.availability_qos,
+                        availability_payloads: self.availability_payloads,
                         base_topic: Arc::from(Path::new(&*self.base_topic)),
-                        discovery_topic: Arc::from(Path::new(&*self.discovery_topic)),
-                        pid: Default::default(),
+                        discovery_topics: self
+                            .discovery_topics
+                            .iter()
+                            .map(|topic| Arc::from(Path::new(&**topic)))
+                            .collect(),
+                        pid: self.publish_store.pid.clone(),
+                        publish_store: self.publish_store,
+                        state_cache: self.state_cache,
                         publish_retries: self.publish_retries,
                         publish_timeout: self.publish_timeout,
                         subscribers: tokio::sync::broadcast::Sender::new(100),
+                        topic_subscribers: Default::default(),
+                        received_exactly_once: Default::default(),
                         send_queue,
                         send_queue_sender,
+                        send_buffers: Default::default(),
                         ping_interval,
                         publish_queue,
                         publish_queue_sender,
+                        session_present: ack.session_present,
+                        connected: watch::Sender::new(true),
+                        last_publish: watch::Sender::new(None).into(),
                     })
                 }
                 failed => Err(MqttError::AuthenticationFailed(failed)),
@@ -574,6 +1834,7 @@ impl SessionBuilder<'_> {
 
 impl Drop for Session {
     fn drop(&mut self) {
+        self.connected.send_replace(false);
         // todo!("Disconnect from server")
     }
 }
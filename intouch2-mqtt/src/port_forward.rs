@@ -1,7 +1,7 @@
 use intouch2::{
     composer::compose_network_data,
-    object::{NetworkPackage, NetworkPackageData},
-    parser::parse_network_data,
+    object::{package_data, NetworkPackage, NetworkPackageData, NetworkPackageType},
+    parser::{parse_hello_id_name, parse_network_data, HelloError},
     ToStatic,
 };
 use std::{
@@ -14,13 +14,14 @@ use std::{
 };
 use tokio::{
     net::UdpSocket,
-    sync::{broadcast, mpsc, Mutex, RwLock},
+    sync::{broadcast, mpsc, watch, Mutex, RwLock},
     task::JoinSet,
     time::{self, timeout_at, Instant},
 };
 
 use crate::{
-    port_forward_mapping::{ForwardAddr, ForwardMapping},
+    backoff::Backoff,
+    port_forward_mapping::{ForwardAddr, ForwardMapping, TimedOutPeers},
     unspecified_source_for_taget, Buffers, NoClone, StaticBox,
 };
 
@@ -28,6 +29,11 @@ use crate::{
 pub enum PortForwardError {
     #[error("IO Error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Could not listen on {addr}: {source}{}", bind_hint(source))]
+    Bind {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
     #[error("No DNS match: {0}")]
     NoDnsMatch(String),
     #[error("Tokio join error: {0}")]
@@ -40,22 +46,83 @@ pub enum PortForwardError {
     SpaTimeout,
     #[error("Pipe send error: {0}")]
     PipeSendFailed(#[from] broadcast::error::SendError<NetworkPackage<'static>>),
-    #[error("Invalid spa name: {}", String::from_utf8_lossy(.0))]
-    InvalidSpaName(Box<[u8]>),
-    #[error("Data dump failed: {0}")]
-    DumpFailed(#[from] broadcast::error::SendError<DataDumpType>),
+    #[error("Invalid spa name: {0}")]
+    InvalidSpaName(#[from] HelloError),
+    #[error("{socket} socket error: {source}")]
+    Socket {
+        socket: ForwardSocket,
+        source: std::io::Error,
+    },
+}
+
+impl PortForwardError {
+    fn spa_socket(source: std::io::Error) -> Self {
+        Self::Socket {
+            socket: ForwardSocket::Spa,
+            source,
+        }
+    }
+
+    fn client_socket(source: std::io::Error) -> Self {
+        Self::Socket {
+            socket: ForwardSocket::Clients,
+            source,
+        }
+    }
+}
+
+/// The sockets of the forward, to tell a spa which can't be reached from a problem with the
+/// clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardSocket {
+    /// The socket which talks to the spa.
+    Spa,
+    /// The socket which listens for clients.
+    Clients,
+}
+
+impl std::fmt::Display for ForwardSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardSocket::Spa => f.write_str("Spa"),
+            ForwardSocket::Clients => f.write_str("Client"),
+        }
+    }
+}
+
+/// A suggestion for the most common reason that a listen address can't be bound.
+fn bind_hint(error: &std::io::Error) -> &'static str {
+    match error.kind() {
+        std::io::ErrorKind::AddrInUse => {
+            " (is another forwarder or the spa app already using this port?)"
+        }
+        _ => "",
+    }
 }
 
 const NET_BUFFER_SIZE: usize = 4096;
 
+/// Changes in the forwarded clients, sent to the pipe side next to the packages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardEvent {
+    /// A forwarded client stopped talking to the spa and was removed. Clients are also removed
+    /// when the spa changes its id, since they are addressing the old one.
+    ClientTimedOut(SocketAddr),
+    /// The spa stopped answering the pipe, or changed its id, so the pipe was removed from the
+    /// forwards. A new `Hello` must be sent through the pipe to receive packages again.
+    PipeTimedOut,
+}
+
 #[derive(Debug)]
 pub struct PackagePipe {
     pub rx: mpsc::Receiver<NetworkPackage<'static>>,
     pub tx: Arc<broadcast::Sender<NetworkPackage<'static>>>,
+    pub events: Arc<broadcast::Sender<ForwardEvent>>,
 }
 
 pub struct SpaPipe {
     broadcast_sender: Arc<broadcast::Sender<NetworkPackage<'static>>>,
+    events: Arc<broadcast::Sender<ForwardEvent>>,
     pub tx: mpsc::Sender<NetworkPackage<'static>>,
 }
 
@@ -63,6 +130,10 @@ impl SpaPipe {
     pub fn subscribe(&self) -> broadcast::Receiver<NetworkPackage<'static>> {
         self.broadcast_sender.subscribe()
     }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ForwardEvent> {
+        self.events.subscribe()
+    }
 }
 
 pub struct FullPackagePipe {
@@ -73,28 +144,31 @@ pub struct FullPackagePipe {
 impl FullPackagePipe {
     pub fn new() -> Self {
         let broadcast_sender = Arc::new(broadcast::Sender::new(30));
+        let events = Arc::new(broadcast::Sender::new(10));
         let (mtx, mrx) = mpsc::channel(30);
         FullPackagePipe {
             spa: SpaPipe {
                 broadcast_sender: broadcast_sender.clone(),
+                events: events.clone(),
                 tx: mtx,
             },
             forwarder: PackagePipe {
                 tx: broadcast_sender,
                 rx: mrx,
+                events,
             },
         }
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum Player {
     Local,
     #[serde(untagged)]
     Client(SocketAddr),
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum DataSource {
     To(Player),
     From(Player),
@@ -102,34 +176,194 @@ pub enum DataSource {
 
 pub type DataDumpType = (DataSource, NetworkPackageData<'static>);
 
+/// A package which the parser didn't recognize, and where it came from.
+pub type UnknownPackageType = (DataSource, package_data::Unknown<'static>);
+
+/// The packages passing through the forward which the parser didn't recognize, as returned by
+/// [PortForwardBuilder::unknown_packages].
+pub struct UnknownPackages {
+    receiver: broadcast::Receiver<UnknownPackageType>,
+}
+
+impl UnknownPackages {
+    /// Wait for the next unknown package. Returns `None` once the forward has stopped.
+    pub async fn next(&mut self) -> Option<UnknownPackageType> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(package) => return Some(package),
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    eprintln!("Skipped {count} unknown packages");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// The packages passing through the forward, as returned by [PortForwardBuilder::dump_packages].
+pub struct PackageDump {
+    receiver: broadcast::Receiver<DataDumpType>,
+}
+
+impl PackageDump {
+    /// Wait for the next package. Packages which were dropped because the receiver fell more
+    /// than [PortForwardBuilder::package_dump_capacity] packages behind are logged and skipped.
+    /// Returns `None` once the forward has stopped.
+    pub async fn next(&mut self) -> Option<DataDumpType> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(package) => return Some(package),
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    eprintln!("Skipped dumping {count} packages");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Selects which packages are sent to the package dump pipe.
+#[derive(Debug, Clone, Default)]
+pub enum PackageFilter {
+    #[default]
+    All,
+    Include(Box<[NetworkPackageType]>),
+    Exclude(Box<[NetworkPackageType]>),
+}
+
+impl PackageFilter {
+    pub fn matches(&self, package: &NetworkPackageData) -> bool {
+        match self {
+            PackageFilter::All => true,
+            PackageFilter::Include(types) => types.contains(&package.into()),
+            PackageFilter::Exclude(types) => !types.contains(&package.into()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PortForward {
     send_clients: Option<Arc<Mutex<NoClone<UdpSocket>>>>,
     recv_clients: Option<NoClone<UdpSocket>>,
     send_pipe: Option<Arc<broadcast::Sender<NetworkPackage<'static>>>>,
     recv_pipe: Option<mpsc::Receiver<NetworkPackage<'static>>>,
+    send_events: Option<Arc<broadcast::Sender<ForwardEvent>>>,
     send_spa: Arc<Mutex<NoClone<UdpSocket>>>,
     recv_spa: NoClone<UdpSocket>,
     spa_hello: Vec<u8>,
     handshake_timeout: Duration,
-    udp_timeout: Duration,
+    client_timeout: Duration,
+    reply_timeout: Duration,
+    max_clients: usize,
+    throttle: Option<Arc<Throttle>>,
     forwards: ForwardMapping<()>,
-    package_dump_pipe: Option<Arc<broadcast::Sender<DataDumpType>>>,
+    active_clients: watch::Sender<ActiveClients>,
+    dumps: PackageDumps,
     verbose: bool,
     dump_traffic: bool,
 }
 
+/// The clients forwarded to the spa, sorted by address, with the time the spa last replied to
+/// each of them. The local pipe isn't included.
+pub type ActiveClients = Vec<(SocketAddr, Option<Instant>)>;
+
+/// The subscribers to the packages passing through the forward.
+#[derive(Debug)]
+struct PackageDumps {
+    package_dump_pipe: Option<Arc<broadcast::Sender<DataDumpType>>>,
+    package_dump_filter: PackageFilter,
+    unknown_package_pipe: Option<broadcast::Sender<UnknownPackageType>>,
+}
+
+impl PackageDumps {
+    /// Send a package to the subscribers of [PortForwardBuilder::dump_packages] and
+    /// [PortForwardBuilder::unknown_packages].
+    fn send(&self, source: DataSource, package: &NetworkPackageData) {
+        if let (Some(unknown_pipe), NetworkPackageData::Unknown(unknown)) =
+            (&self.unknown_package_pipe, package)
+        {
+            // Nobody listening for unknown packages is not a reason to stop forwarding.
+            let _ = unknown_pipe.send((source.clone(), unknown.to_static()));
+        }
+        if let Some(dump_pipe) = &self.package_dump_pipe {
+            if self.package_dump_filter.matches(package) {
+                // The dump is best effort, and it is not a reason to stop forwarding.
+                let _ = dump_pipe.send((source, package.to_static()));
+            }
+        }
+    }
+}
+
 pub struct PortForwardBuilder {
     pub listen_addr: Option<SocketAddr>,
     pub target_addr: SocketAddr,
+    /// The local address of the socket which talks to the spa, for spas or firewalls which
+    /// require a fixed source port. An ephemeral port is used when this isn't set.
+    pub source_addr: Option<SocketAddr>,
     pub handshake_timeout: Duration,
-    pub udp_timeout: Duration,
+    /// How long a client is forwarded after it last sent anything.
+    pub client_timeout: Duration,
+    /// How long a client is forwarded after the spa last replied to it.
+    pub reply_timeout: Duration,
+    /// The maximum number of clients forwarded at the same time, not counting the local
+    /// connection. Clients beyond this are ignored until an existing client times out.
+    pub max_clients: usize,
+    /// Limit the data sent to the spa and to the clients to this many bytes per second. This is
+    /// meant for testing how the spa behaves on slow connections.
+    pub throttle: Option<u32>,
     pub local_connection: Option<PackagePipe>,
     pub package_dump_pipe: Option<broadcast::Sender<DataDumpType>>,
+    /// Which packages are sent to the subscribers of [Self::dump_packages]. This applies to all
+    /// of them.
+    pub package_dump_filter: PackageFilter,
+    /// How many packages [Self::dump_packages] buffers for a receiver which falls behind, before
+    /// the oldest ones are skipped.
+    pub package_dump_capacity: usize,
+    pub unknown_package_pipe: Option<broadcast::Sender<UnknownPackageType>>,
     pub verbose: bool,
     pub dump_traffic: bool,
 }
 
+/// A token bucket which holds up to one second of data.
+#[derive(Debug)]
+struct Throttle {
+    bytes_per_second: f64,
+    /// The available bytes, and when they were last counted. The bytes go negative when a send
+    /// is larger than what is available, and the sender then waits until they are back at zero.
+    available: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    fn new(bytes_per_second: u32) -> Self {
+        let bytes_per_second = f64::from(bytes_per_second);
+        Self {
+            bytes_per_second,
+            available: Mutex::new((bytes_per_second, Instant::now())),
+        }
+    }
+
+    /// Wait until `bytes` may be sent. The lock is held while waiting, so senders are let
+    /// through in order.
+    async fn wait(&self, bytes: usize) {
+        let mut available = self.available.lock().await;
+        let (ref mut tokens, ref mut counted_at) = *available;
+        let now = Instant::now();
+        *tokens = (*tokens + (now - *counted_at).as_secs_f64() * self.bytes_per_second)
+            .min(self.bytes_per_second);
+        *counted_at = now;
+        *tokens -= bytes as f64;
+        if *tokens < 0.0 {
+            time::sleep(Duration::from_secs_f64(-*tokens / self.bytes_per_second)).await;
+        }
+    }
+}
+
+async fn throttle(throttle: &Option<Arc<Throttle>>, bytes: usize) {
+    if let Some(throttle) = throttle {
+        throttle.wait(bytes).await
+    }
+}
+
 fn transmute_uninit<T>(arr: &mut [MaybeUninit<T>]) -> &mut [T] {
     unsafe { std::mem::transmute(arr) }
 }
@@ -144,17 +378,8 @@ impl<'a> TryFrom<&'a [u8]> for SpaHello<'a> {
     type Error = PortForwardError;
 
     fn try_from(buf: &'a [u8]) -> Result<Self, Self::Error> {
-        for i in 0..buf.len() {
-            if buf[i] == b'|' {
-                let (before, rest) = buf.split_at(i);
-                let after = &rest[1..];
-                return Ok(SpaHello {
-                    id: before,
-                    name: after,
-                });
-            }
-        }
-        Err(PortForwardError::InvalidSpaName(buf.into()))
+        let (id, name) = parse_hello_id_name(buf)?;
+        Ok(SpaHello { id, name })
     }
 }
 
@@ -165,46 +390,94 @@ impl<'a> SpaHello<'a> {
 }
 
 impl PortForwardBuilder {
-    pub fn dump_packages(&mut self) -> broadcast::Receiver<DataDumpType> {
-        self.package_dump_pipe
-            .get_or_insert_with(|| broadcast::Sender::new(10))
-            .subscribe()
+    /// Subscribe to the packages passing through the forward which match
+    /// [Self::package_dump_filter].
+    pub fn dump_packages(&mut self) -> PackageDump {
+        let capacity = self.package_dump_capacity.max(1);
+        PackageDump {
+            receiver: self
+                .package_dump_pipe
+                .get_or_insert_with(|| broadcast::Sender::new(capacity))
+                .subscribe(),
+        }
+    }
+
+    /// Subscribe to the packages passing through the forward which the parser didn't recognize,
+    /// in either direction.
+    pub fn unknown_packages(&mut self) -> UnknownPackages {
+        UnknownPackages {
+            receiver: self
+                .unknown_package_pipe
+                .get_or_insert_with(|| broadcast::Sender::new(10))
+                .subscribe(),
+        }
     }
 
     pub async fn build(self) -> Result<PortForward, PortForwardError> {
         let PortForwardBuilder {
             listen_addr,
             target_addr,
+            source_addr,
             handshake_timeout,
-            udp_timeout,
+            client_timeout,
+            reply_timeout,
+            max_clients,
+            throttle,
             local_connection,
             package_dump_pipe: package_dump,
+            package_dump_filter,
+            package_dump_capacity: _,
+            unknown_package_pipe,
             verbose,
             dump_traffic,
         } = self;
 
-        let target_bind_addr = unspecified_source_for_taget(target_addr);
         let (send_clients, recv_clients) = if let Some(listen_addr) = listen_addr {
             if self.verbose {
                 eprintln!("Listening on {listen_addr}");
             }
-            let sock_clients = StaticBox::new(UdpSocket::bind(listen_addr).await?);
+            let sock_clients =
+                UdpSocket::bind(listen_addr)
+                    .await
+                    .map_err(|source| PortForwardError::Bind {
+                        addr: listen_addr,
+                        source,
+                    })?;
+            let sock_clients = StaticBox::new(sock_clients);
             let send_clients = Arc::new(Mutex::new(sock_clients.to_no_clone()));
             let recv_clients = sock_clients.to_no_clone();
             (Some(send_clients), Some(recv_clients))
         } else {
             (None, None)
         };
-        let (send_pipe, recv_pipe) = if let Some(pipes) = local_connection {
-            (Some(pipes.tx), Some(pipes.rx))
+        let (send_pipe, recv_pipe, send_events) = if let Some(pipes) = local_connection {
+            (Some(pipes.tx), Some(pipes.rx), Some(pipes.events))
         } else {
-            (None, None)
+            (None, None, None)
+        };
+        let sock_spa = match source_addr {
+            Some(source_addr) => {
+                UdpSocket::bind(source_addr)
+                    .await
+                    .map_err(|source| PortForwardError::Bind {
+                        addr: source_addr,
+                        source,
+                    })?
+            }
+            None => UdpSocket::bind(unspecified_source_for_taget(target_addr))
+                .await
+                .map_err(PortForwardError::spa_socket)?,
         };
-        let sock_spa = UdpSocket::bind(target_bind_addr).await?;
-        sock_spa.connect(self.target_addr).await?;
+        sock_spa
+            .connect(self.target_addr)
+            .await
+            .map_err(PortForwardError::spa_socket)?;
 
         let spa_hello = {
             let mut tries: u8 = 5;
+            // A late answer to an earlier hello is still accepted, so the waits grow to give a
+            // slow spa more time instead of flooding it with hellos.
+            let mut backoff = Backoff::new(Duration::from_millis(250), Duration::from_secs(2), 2.0);
             let mut buf = Box::new([0; 512]);
             'retry: loop {
                 tries -= 1;
@@ -212,13 +485,16 @@ impl PortForwardBuilder {
                     .send(&compose_network_data(&NetworkPackage::Hello(
                         Cow::Borrowed(b"1"),
                     )))
-                    .await?;
-                let timeout = Instant::now() + Duration::from_secs(1);
+                    .await
+                    .map_err(PortForwardError::spa_socket)?;
+                let timeout = Instant::now() + backoff.next_delay();
 
                 'ignore_package: loop {
                     match timeout_at(timeout, sock_spa.recv(buf.as_mut())).await {
                         Err(_) => break 'ignore_package,
-                        Ok(len) => match parse_network_data(&buf[0..len?]) {
+                        Ok(len) => match parse_network_data(
+                            &buf[0..len.map_err(PortForwardError::spa_socket)?],
+                        ) {
                             Err(_) | Ok(NetworkPackage::Addressed { .. }) => {
                                 continue 'ignore_package
                             }
@@ -240,24 +516,104 @@ impl PortForwardBuilder {
 
         Ok(PortForward {
             forwards: Default::default(),
+            active_clients: watch::Sender::new(Default::default()),
             spa_hello,
             send_clients,
             recv_clients,
             send_pipe,
             recv_pipe,
+            send_events,
             send_spa,
             recv_spa,
             handshake_timeout,
-            udp_timeout,
-            package_dump_pipe: package_dump.map(Into::into),
+            client_timeout,
+            reply_timeout,
+            max_clients,
+            throttle: throttle.map(Throttle::new).map(Arc::new),
+            dumps: PackageDumps {
+                package_dump_pipe: package_dump.map(Into::into),
+                package_dump_filter,
+                unknown_package_pipe,
+            },
             verbose,
             dump_traffic,
         })
     }
 }
 
+/// Send the clients in `forwards` to the subscribers of [PortForward::subscribe_active_clients].
+/// The subscribers are only notified if a client was added or removed.
+fn update_active_clients(
+    forwards: &ForwardMapping<()>,
+    active_clients: &watch::Sender<ActiveClients>,
+) {
+    let mut clients: ActiveClients = forwards
+        .iter()
+        .filter_map(|info| match *info.addr() {
+            ForwardAddr::Socket(addr) => Some((addr, info.last_reply())),
+            ForwardAddr::Pipe => None,
+        })
+        .collect();
+    clients.sort_by_key(|(addr, _)| *addr);
+    active_clients.send_if_modified(|active| {
+        let added_or_removed = !active
+            .iter()
+            .map(|(addr, _)| addr)
+            .eq(clients.iter().map(|(addr, _)| addr));
+        *active = clients;
+        added_or_removed
+    });
+}
+
+/// Update the last reply of `addr` in [PortForward::subscribe_active_clients], without notifying
+/// the subscribers since this happens for every package. Returns false if `addr` isn't an active
+/// client.
+fn update_last_reply(
+    active_clients: &watch::Sender<ActiveClients>,
+    addr: SocketAddr,
+    last_reply: Option<Instant>,
+) -> bool {
+    let mut found = false;
+    active_clients.send_if_modified(|active| {
+        if let Ok(index) = active.binary_search_by_key(&addr, |(client, _)| *client) {
+            active[index].1 = last_reply;
+            found = true;
+        }
+        false
+    });
+    found
+}
+
+/// Tell the pipe side about the clients which were removed from the forwards.
+fn send_removed_events(
+    send_events: &Option<Arc<broadcast::Sender<ForwardEvent>>>,
+    removed: &TimedOutPeers<()>,
+) {
+    let Some(send_events) = send_events else {
+        return;
+    };
+    for (client, ()) in removed.iter() {
+        let event = match **client {
+            ForwardAddr::Pipe => ForwardEvent::PipeTimedOut,
+            ForwardAddr::Socket(addr) => ForwardEvent::ClientTimedOut(addr),
+        };
+        // Nobody listening for events is not an error.
+        let _ = send_events.send(event);
+    }
+}
+
 impl PortForward {
+    /// Subscribe to the clients which are currently forwarded. The subscribers are notified while
+    /// the forward [runs](Self::run), whenever a client is added or times out. The time of the
+    /// last reply to each client is kept up to date without a notification, so read it when it's
+    /// needed.
+    pub fn subscribe_active_clients(&self) -> watch::Receiver<ActiveClients> {
+        self.active_clients.subscribe()
+    }
+
     pub async fn run(mut self) -> Result<(), PortForwardError> {
+        // The local connection doesn't count towards `max_clients`.
+        let max_forwards = self.max_clients + usize::from(self.send_pipe.is_some());
         let mut spa_hello = SpaHello::new(&self.spa_hello)?;
         let hello_response = Arc::new(RwLock::new(compose_network_data(&NetworkPackage::Hello(
             Cow::Borrowed(&spa_hello.id),
@@ -338,7 +694,8 @@ impl PortForward {
                             buf.clear();
                             let (len, source_addr) = recv_sock
                                 .recv_from(transmute_uninit(buf.spare_capacity_mut()))
-                                .await?;
+                                .await
+                                .map_err(PortForwardError::client_socket)?;
                             unsafe { buf.set_len(len) };
                             Ok(SocketData::FromClient {
                                 recv_sock: Some(recv_sock),
@@ -359,7 +716,8 @@ impl PortForward {
                             buf.clear();
                             let len = recv_sock
                                 .recv(transmute_uninit(buf.spare_capacity_mut()))
-                                .await?;
+                                .await
+                                .map_err(PortForwardError::spa_socket)?;
                             unsafe { buf.set_len(len) };
                             Ok(SocketData::FromSpa {
                                 recv_sock: Some(recv_sock),
@@ -386,19 +744,29 @@ impl PortForward {
                         });
                     }
                     SocketData::Timeout => {
-                        let (timeouts, next_timeout) = self
-                            .forwards
-                            .clear_timeouts(self.handshake_timeout, self.udp_timeout);
+                        let (timeouts, next_timeout) = self.forwards.clear_timeouts(
+                            self.handshake_timeout,
+                            self.client_timeout,
+                            self.reply_timeout,
+                        );
                         if self.verbose {
-                            for client in timeouts.iter() {
-                                eprintln!("Client {client:?} timed out")
+                            for (client, ()) in timeouts.iter() {
+                                eprintln!("Client {client} timed out")
                             }
                         }
+                        send_removed_events(&self.send_events, &timeouts);
+                        if !timeouts.is_empty() {
+                            update_active_clients(&self.forwards, &self.active_clients);
+                        }
                         workers.spawn(async move {
                             if let Some(next_timeout) = next_timeout {
                                 time::sleep_until(next_timeout).await;
                             } else {
-                                time::sleep(min(self.handshake_timeout, self.udp_timeout)).await;
+                                time::sleep(min(
+                                    self.handshake_timeout,
+                                    min(self.client_timeout, self.reply_timeout),
+                                ))
+                                .await;
                             }
                             Ok(SocketData::Timeout)
                         });
@@ -421,17 +789,18 @@ impl PortForward {
                             {
                                 eprintln!("Self -> {}", package.display());
                             }
-                            if let Some(dump_pipe) = &mut self.package_dump_pipe {
-                                dump_pipe
-                                    .send((DataSource::From(Player::Local), package.to_static()))?;
-                            }
+                            self.dumps.send(DataSource::From(Player::Local), package);
                             let send_spa = self.send_spa.clone();
+                            let throttler = self.throttle.clone();
                             workers.spawn(async move {
+                                let data = compose_network_data(&data);
+                                throttle(&throttler, data.len()).await;
                                 send_spa
                                     .lock()
                                     .await
-                                    .send(&compose_network_data(&data))
-                                    .await?;
+                                    .send(&data)
+                                    .await
+                                    .map_err(PortForwardError::spa_socket)?;
                                 Ok(SocketData::SendCompleted { buf: None })
                             });
                         }
@@ -465,17 +834,22 @@ impl PortForward {
                             {
                                 eprintln!("{source_addr} -> {}", content.display());
                             }
-                            if let Some(dump_pipe) = &mut self.package_dump_pipe {
-                                dump_pipe.send((
-                                    DataSource::From(Player::Client(source_addr)),
-                                    content.to_static(),
-                                ))?;
-                            }
+                            self.dumps
+                                .send(DataSource::From(Player::Client(source_addr)), content);
                             let count_before = self.forwards.len();
-                            let info =
-                                self.forwards
-                                    .insert(ForwardAddr::Socket(source_addr), &**src, ());
+                            let Some(info) = self.forwards.try_insert(
+                                ForwardAddr::Socket(source_addr),
+                                &**src,
+                                (),
+                                max_forwards,
+                            ) else {
+                                if self.verbose {
+                                    eprintln!("Ignoring client at {source_addr}, too many clients");
+                                }
+                                continue;
+                            };
                             info.did_forward();
+                            let last_reply = info.last_reply();
                             if self.verbose && count_before != self.forwards.len() {
                                 eprintln!(
                                     "New client {} at {}",
@@ -483,6 +857,9 @@ impl PortForward {
                                     source_addr
                                 );
                             }
+                            if !update_last_reply(&self.active_clients, source_addr, last_reply) {
+                                update_active_clients(&self.forwards, &self.active_clients);
+                            }
                             let send_spa = self.send_spa.clone();
                             let send_pipe =
                                 if let (Some(pipe), NetworkPackageData::SetStatus { .. }) =
@@ -492,8 +869,15 @@ impl PortForward {
                                 } else {
                                     None
                                 };
+                            let throttler = self.throttle.clone();
                             workers.spawn(async move {
-                                send_spa.lock().await.send(&data).await?;
+                                throttle(&throttler, data.len()).await;
+                                send_spa
+                                    .lock()
+                                    .await
+                                    .send(&data)
+                                    .await
+                                    .map_err(PortForwardError::spa_socket)?;
                                 if let Some((send_pipe, content)) = send_pipe {
                                     eprintln!("Forwarding set command");
                                     send_pipe.send(content)?;
@@ -517,7 +901,8 @@ impl PortForward {
                         Err(package_error) => {
                             if self.verbose {
                                 eprintln!(
-                                    "Invalid package received from {source_addr}: {package_error}"
+                                    "Invalid package received from {source_addr}: {}",
+                                    package_error.display()
                                 )
                             }
                         }
@@ -525,23 +910,35 @@ impl PortForward {
                             let Some(send_clients) = &self.send_clients else {
                                 unreachable!("How can you get messages from clients if you don't have any clients?")
                             };
-                            if self.verbose {
-                                if self
-                                    .forwards
-                                    .get_addr(&ForwardAddr::Socket(source_addr))
-                                    .is_none()
-                                {
+                            if self
+                                .forwards
+                                .get_addr(&ForwardAddr::Socket(source_addr))
+                                .is_none()
+                            {
+                                if self.forwards.len() >= max_forwards {
+                                    if self.verbose {
+                                        eprintln!(
+                                            "Ignoring hello from {source_addr}, too many clients"
+                                        );
+                                    }
+                                    continue;
+                                }
+                                if self.verbose {
                                     eprintln!("New hello received from {source_addr}")
                                 }
                             }
                             let send_clients = send_clients.clone();
                             let hello_response = hello_response.clone();
+                            let throttler = self.throttle.clone();
                             workers.spawn(async move {
+                                let hello_response = hello_response.read().await.clone();
+                                throttle(&throttler, hello_response.len()).await;
                                 send_clients
                                     .lock()
                                     .await
-                                    .send_to(&hello_response.read().await, source_addr)
-                                    .await?;
+                                    .send_to(&hello_response, source_addr)
+                                    .await
+                                    .map_err(PortForwardError::client_socket)?;
                                 Ok(SocketData::SendCompleted { buf: Some(data) })
                             });
                         }
@@ -570,23 +967,19 @@ impl PortForward {
                                         {
                                             eprintln!("Self <- {}", content.display());
                                         }
+                                        self.dumps.send(DataSource::To(Player::Local), content);
                                         let package = package.to_static();
-                                        if let (
-                                            Some(dump_pipe),
-                                            NetworkPackage::Addressed { data, .. },
-                                        ) = (&mut self.package_dump_pipe, &package)
-                                        {
-                                            dump_pipe.send((
-                                                DataSource::To(Player::Local),
-                                                data.into(),
-                                            ))?;
-                                        }
                                         workers.spawn(async move {
                                             sender.send(package)?;
                                             Ok(SocketData::SendCompleted { buf: Some(data) })
                                         });
                                     }
                                     ForwardAddr::Socket(addr) => {
+                                        update_last_reply(
+                                            &self.active_clients,
+                                            addr,
+                                            forward_info.last_reply(),
+                                        );
                                         let Some(send_clients) = &self.send_clients else {
                                             unreachable!("How can you send to clients if there are no clients?")
                                         };
@@ -598,12 +991,8 @@ impl PortForward {
                                         {
                                             eprintln!("{addr} <- {}", content.display());
                                         }
-                                        if let Some(dump_pipe) = &mut self.package_dump_pipe {
-                                            dump_pipe.send((
-                                                DataSource::To(Player::Client(addr)),
-                                                content.to_static(),
-                                            ))?;
-                                        }
+                                        self.dumps
+                                            .send(DataSource::To(Player::Client(addr)), content);
                                         let send_clients = send_clients.clone();
                                         let sender = if let (
                                             Some(sender),
@@ -614,12 +1003,15 @@ impl PortForward {
                                         } else {
                                             None
                                         };
+                                        let throttler = self.throttle.clone();
                                         workers.spawn(async move {
+                                            throttle(&throttler, data.len()).await;
                                             send_clients
                                                 .lock()
                                                 .await
                                                 .send_to(data.as_ref(), addr)
-                                                .await?;
+                                                .await
+                                                .map_err(PortForwardError::client_socket)?;
                                             if let Some((sender, package)) = sender {
                                                 sender.send(package)?;
                                             }
@@ -631,12 +1023,32 @@ impl PortForward {
                         }
                         Err(package_error) => {
                             if self.verbose {
-                                eprintln!("Invalid package received from spa: {package_error}")
+                                eprintln!(
+                                    "Invalid package received from spa: {}",
+                                    package_error.display()
+                                )
                             }
                         }
                         Ok(NetworkPackage::Hello(id)) => {
                             if id[..] != self.spa_hello[..] {
-                                if self.verbose {
+                                // A hello which doesn't parse doesn't tell which spa sent it, so
+                                // the forward keeps using the last valid one.
+                                let new_hello = match SpaHello::new(&id) {
+                                    Ok(new_hello) => new_hello,
+                                    Err(e) => {
+                                        eprintln!("Ignoring invalid hello from spa: {e}");
+                                        continue;
+                                    }
+                                };
+                                let id_changed = new_hello.id != spa_hello.id;
+                                if id_changed {
+                                    eprintln!(
+                                        "Spa changed id from {} to {}, dropping {} forwarded clients",
+                                        String::from_utf8_lossy(spa_hello.id),
+                                        String::from_utf8_lossy(new_hello.id),
+                                        self.forwards.len(),
+                                    );
+                                } else if self.verbose {
                                     eprintln!(
                                         "Spa changed name to {}",
                                         String::from_utf8_lossy(&id)
@@ -647,6 +1059,14 @@ impl PortForward {
                                 *hello_response.write().await = compose_network_data(
                                     &NetworkPackage::Hello(Cow::Borrowed(&self.spa_hello)),
                                 );
+                                if id_changed {
+                                    // The clients are addressing the old id, which the spa no
+                                    // longer answers to. Forget them, so that they are treated as
+                                    // new clients once they have found the spa again.
+                                    let removed = self.forwards.clear();
+                                    send_removed_events(&self.send_events, &removed);
+                                    update_active_clients(&self.forwards, &self.active_clients);
+                                }
                             }
                         }
                         Ok(NetworkPackage::Addressed { dst: None, .. }) => {
@@ -672,3 +1092,321 @@ impl PortForward {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A forward to the spa at `target_addr`, with the settings shared by the tests.
+    fn test_builder(target_addr: SocketAddr) -> PortForwardBuilder {
+        PortForwardBuilder {
+            listen_addr: None,
+            target_addr,
+            source_addr: None,
+            handshake_timeout: Duration::from_secs(1),
+            client_timeout: Duration::from_secs(10),
+            reply_timeout: Duration::from_secs(10),
+            max_clients: 1,
+            throttle: None,
+            local_connection: None,
+            package_dump_pipe: None,
+            package_dump_filter: Default::default(),
+            package_dump_capacity: 10,
+            unknown_package_pipe: None,
+            verbose: false,
+            dump_traffic: false,
+        }
+    }
+
+    /// Answer the hello of a forward being built, like a spa with the id `SPA` would. Returns the
+    /// address the forward sends from.
+    async fn fake_spa_handshake(spa: &UdpSocket) -> Result<SocketAddr, PortForwardError> {
+        let mut buf = [0; 512];
+        let (_, forward_addr) = spa.recv_from(&mut buf).await?;
+        spa.send_to(b"<HELLO>SPA|Fake spa</HELLO>", forward_addr)
+            .await?;
+        Ok(forward_addr)
+    }
+
+    #[test]
+    fn data_dump_round_trip() -> anyhow::Result<()> {
+        let client: SocketAddr = "192.168.0.2:10022".parse()?;
+        let dumps: Vec<DataDumpType> = vec![
+            (DataSource::From(Player::Local), NetworkPackageData::Ping),
+            (
+                DataSource::To(Player::Client(client)),
+                NetworkPackageData::PacksData(intouch2::object::package_data::PacksData(
+                    Cow::Borrowed(&[1, 2, 3]),
+                )),
+            ),
+        ];
+        let serialized = serde_json::to_string(&dumps)?;
+        assert!(serialized.contains(r#"{"To":"192.168.0.2:10022"}"#));
+        let parsed: Vec<DataDumpType> = serde_json::from_str(&serialized)?;
+        assert_eq!(parsed, dumps);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn package_dump_skips_lagged() -> anyhow::Result<()> {
+        let sender = broadcast::Sender::new(2);
+        let mut dump = PackageDump {
+            receiver: sender.subscribe(),
+        };
+        for _ in 0..3 {
+            sender.send((DataSource::From(Player::Local), NetworkPackageData::Ping))?;
+        }
+        sender.send((DataSource::To(Player::Local), NetworkPackageData::Pong))?;
+        assert_eq!(
+            dump.next().await,
+            Some((DataSource::From(Player::Local), NetworkPackageData::Ping))
+        );
+        assert_eq!(
+            dump.next().await,
+            Some((DataSource::To(Player::Local), NetworkPackageData::Pong))
+        );
+        drop(sender);
+        assert_eq!(dump.next().await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_packages_from_spa() -> anyhow::Result<()> {
+        let spa = UdpSocket::bind("127.0.0.1:0").await?;
+        let pipe = FullPackagePipe::new();
+        let mut builder = PortForwardBuilder {
+            local_connection: Some(pipe.forwarder),
+            ..test_builder(spa.local_addr()?)
+        };
+        let mut unknown_packages = builder.unknown_packages();
+        let (forward, forward_addr) = tokio::try_join!(builder.build(), fake_spa_handshake(&spa))?;
+        let _forward = tokio::spawn(forward.run());
+        let mut from_spa = pipe.spa.subscribe();
+        pipe.spa
+            .tx
+            .send(NetworkPackage::Hello(Cow::Borrowed(b"client")))
+            .await?;
+        from_spa.recv().await?;
+        spa.send_to(
+            b"<PACKT><SRCCN>SPA</SRCCN><DESCN>client</DESCN><DATAS>NEWPKG</DATAS></PACKT>",
+            forward_addr,
+        )
+        .await?;
+        let (source, package) = time::timeout(Duration::from_secs(5), unknown_packages.next())
+            .await?
+            .expect("The forward should still be running");
+        assert_eq!(source, DataSource::To(Player::Local));
+        assert_eq!(&*package, b"NEWPKG");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn active_clients() -> anyhow::Result<()> {
+        let spa = UdpSocket::bind("127.0.0.1:0").await?;
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        let listen_addr = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?;
+        let builder = PortForwardBuilder {
+            listen_addr: Some(listen_addr),
+            ..test_builder(spa.local_addr()?)
+        };
+        let mut buf = [0; 512];
+        let (forward, forward_addr) = tokio::try_join!(builder.build(), fake_spa_handshake(&spa))?;
+        let mut active_clients = forward.subscribe_active_clients();
+        assert!(active_clients.borrow_and_update().is_empty());
+        let _forward = tokio::spawn(forward.run());
+
+        client
+            .send_to(
+                b"<PACKT><SRCCN>client</SRCCN><DESCN>SPA</DESCN><DATAS>APING</DATAS></PACKT>",
+                listen_addr,
+            )
+            .await?;
+        time::timeout(Duration::from_secs(5), active_clients.changed()).await??;
+        assert_eq!(
+            *active_clients.borrow_and_update(),
+            [(client.local_addr()?, None)]
+        );
+
+        spa.recv_from(&mut buf).await?;
+        spa.send_to(
+            b"<PACKT><SRCCN>SPA</SRCCN><DESCN>client</DESCN><DATAS>APING\0</DATAS></PACKT>",
+            forward_addr,
+        )
+        .await?;
+        // A reply doesn't notify the subscribers, but the time of it can be read once the client
+        // has received it.
+        time::timeout(Duration::from_secs(5), client.recv(&mut buf)).await??;
+        assert!(!active_clients.has_changed()?);
+        let clients = active_clients.borrow_and_update().clone();
+        assert!(
+            matches!(clients[..], [(addr, Some(_))] if addr == client.local_addr()?),
+            "{clients:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spa_id_change_drops_clients() -> anyhow::Result<()> {
+        let spa = UdpSocket::bind("127.0.0.1:0").await?;
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        let listen_addr = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?;
+        let pipe = FullPackagePipe::new();
+        let builder = PortForwardBuilder {
+            listen_addr: Some(listen_addr),
+            local_connection: Some(pipe.forwarder),
+            ..test_builder(spa.local_addr()?)
+        };
+        let mut buf = [0; 512];
+        let (forward, forward_addr) = tokio::try_join!(builder.build(), fake_spa_handshake(&spa))?;
+        let mut active_clients = forward.subscribe_active_clients();
+        let mut events = pipe.spa.subscribe_events();
+        let _forward = tokio::spawn(forward.run());
+
+        client
+            .send_to(
+                b"<PACKT><SRCCN>client</SRCCN><DESCN>SPA</DESCN><DATAS>APING</DATAS></PACKT>",
+                listen_addr,
+            )
+            .await?;
+        time::timeout(Duration::from_secs(5), active_clients.changed()).await??;
+        assert_eq!(active_clients.borrow_and_update().len(), 1);
+        spa.recv_from(&mut buf).await?;
+
+        // Hellos which don't parse are ignored, and the client is still forwarded.
+        spa.send_to(b"<HELLO>NEWSPA</HELLO>", forward_addr).await?;
+        client
+            .send_to(
+                b"<PACKT><SRCCN>client</SRCCN><DESCN>SPA</DESCN><DATAS>APING</DATAS></PACKT>",
+                listen_addr,
+            )
+            .await?;
+        time::timeout(Duration::from_secs(5), spa.recv_from(&mut buf)).await??;
+        assert_eq!(active_clients.borrow_and_update().len(), 1);
+
+        spa.send_to(b"<HELLO>NEWSPA|Fake spa</HELLO>", forward_addr)
+            .await?;
+        time::timeout(Duration::from_secs(5), active_clients.changed()).await??;
+        assert!(active_clients.borrow_and_update().is_empty());
+        assert_eq!(
+            time::timeout(Duration::from_secs(5), events.recv()).await??,
+            ForwardEvent::ClientTimedOut(client.local_addr()?)
+        );
+
+        // The client finds the spa again, and is forwarded with the new id.
+        client.send_to(b"<HELLO>1</HELLO>", listen_addr).await?;
+        let len = time::timeout(Duration::from_secs(5), client.recv(&mut buf)).await??;
+        assert_eq!(&buf[..len], b"<HELLO>NEWSPA|Fake spa</HELLO>");
+        client
+            .send_to(
+                b"<PACKT><SRCCN>client</SRCCN><DESCN>NEWSPA</DESCN><DATAS>APING</DATAS></PACKT>",
+                listen_addr,
+            )
+            .await?;
+        let len = time::timeout(Duration::from_secs(5), spa.recv(&mut buf)).await??;
+        assert!(matches!(
+            parse_network_data(&buf[..len]),
+            Ok(NetworkPackage::Addressed {
+                dst: Some(dst),
+                data: NetworkPackageData::Ping,
+                ..
+            }) if *dst == *b"NEWSPA"
+        ));
+        time::timeout(Duration::from_secs(5), active_clients.changed()).await??;
+        assert_eq!(active_clients.borrow_and_update().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spa_hello_timeout() -> anyhow::Result<()> {
+        let spa = UdpSocket::bind("127.0.0.1:0").await?;
+        let builder = PortForwardBuilder {
+            local_connection: Some(FullPackagePipe::new().forwarder),
+            ..test_builder(spa.local_addr()?)
+        };
+        // Answer every hello with a package which isn't a hello, which the handshake ignores.
+        let spa = tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let mut hellos = 0;
+            loop {
+                let (len, forward_addr) = spa.recv_from(&mut buf).await?;
+                if let Ok(NetworkPackage::Hello(_)) = parse_network_data(&buf[..len]) {
+                    hellos += 1;
+                }
+                let reply = compose_network_data(&NetworkPackage::Addressed {
+                    src: Some(Cow::Borrowed(b"SPA")),
+                    dst: None,
+                    data: NetworkPackageData::Pong,
+                });
+                spa.send_to(&reply, forward_addr).await?;
+                if hellos == 5 {
+                    break anyhow::Ok(hellos);
+                }
+            }
+        });
+        let started = Instant::now();
+        let result = time::timeout(Duration::from_secs(10), builder.build()).await?;
+        assert!(matches!(result, Err(PortForwardError::SpaTimeout)));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(4), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(7), "{elapsed:?}");
+        assert_eq!(spa.await??, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fixed_source_address() -> anyhow::Result<()> {
+        let spa = UdpSocket::bind("127.0.0.1:0").await?;
+        let source_addr = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?;
+        let builder = PortForwardBuilder {
+            source_addr: Some(source_addr),
+            local_connection: Some(FullPackagePipe::new().forwarder),
+            ..test_builder(spa.local_addr()?)
+        };
+        let (_forward, forward_addr) = tokio::try_join!(builder.build(), fake_spa_handshake(&spa))?;
+        assert_eq!(forward_addr, source_addr);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spa_socket_error() -> anyhow::Result<()> {
+        // Nothing listens on the address once the socket is dropped, so the spa socket is
+        // refused.
+        let target_addr = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?;
+        let builder = PortForwardBuilder {
+            local_connection: Some(FullPackagePipe::new().forwarder),
+            ..test_builder(target_addr)
+        };
+        let Err(error) = time::timeout(Duration::from_secs(10), builder.build()).await? else {
+            panic!("An unreachable spa should fail");
+        };
+        let PortForwardError::Socket { socket, source } = &error else {
+            panic!("Expected a socket error, got {error}");
+        };
+        assert_eq!(*socket, ForwardSocket::Spa);
+        assert_eq!(source.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert!(error.to_string().starts_with("Spa socket error"), "{error}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listen_address_in_use() -> anyhow::Result<()> {
+        let taken = UdpSocket::bind("127.0.0.1:0").await?;
+        let spa = UdpSocket::bind("127.0.0.1:0").await?;
+        let builder = PortForwardBuilder {
+            listen_addr: Some(taken.local_addr()?),
+            ..test_builder(spa.local_addr()?)
+        };
+        let Err(error) = builder.build().await else {
+            panic!("Listening on a used address should fail");
+        };
+        let PortForwardError::Bind { addr, source } = &error else {
+            panic!("Expected a bind error, got {error}");
+        };
+        assert_eq!(*addr, taken.local_addr()?);
+        assert_eq!(source.kind(), std::io::ErrorKind::AddrInUse);
+        let message = error.to_string();
+        assert!(message.contains(&addr.to_string()), "{message}");
+        assert!(message.contains("already using this port"), "{message}");
+        Ok(())
+    }
+}
@@ -36,6 +36,7 @@ impl From<SocketAddr> for ForwardAddr {
 
 pub type PeerIdType = [u8];
 pub type PeerAddrType = ForwardAddr;
+pub type TimedOutPeers<T> = Box<[(Arc<PeerAddrType>, T)]>;
 
 #[derive(Debug)]
 pub struct ForwardMappingInfo<T> {
@@ -65,13 +66,21 @@ impl<T: Send + Sync> ForwardMappingInfo<T> {
             None => unreachable!("Context is never called on a dead ForwardMappingInfo, and context is always Some for a valid ForwardMappingInfo")
         }
     }
-    pub fn timeout(&self, handshake_timeout: Duration, timeout: Duration) -> Instant {
+    /// The mapping is alive until both the client has stopped sending for `client_timeout` and
+    /// the spa has stopped replying for `reply_timeout`. A client which never got a reply times
+    /// out after `handshake_timeout` instead of `reply_timeout`.
+    pub fn timeout(
+        &self,
+        handshake_timeout: Duration,
+        client_timeout: Duration,
+        reply_timeout: Duration,
+    ) -> Instant {
         let reply_timeout = if let Some(last_reply) = self.last_reply {
-            last_reply + timeout
+            last_reply + reply_timeout
         } else {
             self.last_forward + handshake_timeout
         };
-        let forward_timeout = self.last_forward + timeout;
+        let forward_timeout = self.last_forward + client_timeout;
         max(reply_timeout, forward_timeout)
     }
     pub fn did_forward(&mut self) {
@@ -80,6 +89,9 @@ impl<T: Send + Sync> ForwardMappingInfo<T> {
     pub fn got_reply(&mut self) {
         self.last_reply = Instant::now().into()
     }
+    pub fn last_reply(&self) -> Option<Instant> {
+        self.last_reply
+    }
 }
 
 #[derive(Default, Debug)]
@@ -126,6 +138,9 @@ impl<T: Send + Sync> ForwardMapping<T> {
     pub fn get_addr(&self, addr: &PeerAddrType) -> Option<&ForwardMappingInfo<T>> {
         self.addrs.get(addr).map(unpack_cell)
     }
+    pub fn iter(&self) -> impl Iterator<Item = &ForwardMappingInfo<T>> {
+        self.addrs.values().map(unpack_cell)
+    }
     pub fn len(&self) -> usize {
         let len = self.ids.len();
         debug_assert_eq!(self.addrs.len(), len);
@@ -134,14 +149,15 @@ impl<T: Send + Sync> ForwardMapping<T> {
     pub fn clear_timeouts(
         &mut self,
         handshake_timeout: Duration,
-        timeout: Duration,
-    ) -> (Box<[T]>, Option<Instant>) {
+        client_timeout: Duration,
+        reply_timeout: Duration,
+    ) -> (TimedOutPeers<T>, Option<Instant>) {
         let mut to_remove = Vec::with_capacity(self.addrs.len());
         let cutoff = Instant::now();
         let mut lowest = None;
         for info in self.addrs.values() {
             let cell = unsafe { &*info.get() };
-            let timeout = cell.timeout(handshake_timeout, timeout);
+            let timeout = cell.timeout(handshake_timeout, client_timeout, reply_timeout);
             if timeout < cutoff {
                 to_remove.push(cell.id());
             } else if lowest.map(|old| timeout > old) != Some(true) {
@@ -150,12 +166,26 @@ impl<T: Send + Sync> ForwardMapping<T> {
         }
         let mut removed = Vec::with_capacity(to_remove.len());
         for id in to_remove {
-            if let Some(x) = self.remove_id(&id) {
-                removed.push(x);
+            if let Some((context, addr, _)) = self._remove_id(&id) {
+                removed.push((addr, context));
             }
         }
         (removed.into(), lowest)
     }
+    /// Remove every peer, such as when the ids they are talking to are no longer valid.
+    pub fn clear(&mut self) -> TimedOutPeers<T> {
+        self.ids.clear();
+        self.addrs
+            .drain()
+            .map(|(addr, info)| {
+                let mapping = unpack_owned_cell_mut(info);
+                let context = std::mem::take(&mut mapping.context).expect(
+                    "This invalidates the mapping. It was valid before, so context is Some.",
+                );
+                (addr, context)
+            })
+            .collect()
+    }
     pub fn remove_and_reuse_arcs(
         &mut self,
         addr: impl Borrow<PeerAddrType> + Into<Arc<PeerAddrType>>,
@@ -214,6 +244,23 @@ impl<T: Send + Sync> ForwardMapping<T> {
         };
         reply
     }
+    /// Like [Self::insert], but refuses new peers when `max_len` peers are already mapped. Peers
+    /// which are already mapped by `addr` or `id` can always be refreshed.
+    pub fn try_insert<'a, 's: 'a>(
+        &'s mut self,
+        addr: impl Borrow<PeerAddrType> + Into<Arc<PeerAddrType>>,
+        id: impl Borrow<PeerIdType> + Into<Arc<PeerIdType>>,
+        context: T,
+        max_len: usize,
+    ) -> Option<&'a mut ForwardMappingInfo<T>> {
+        if self.len() >= max_len
+            && !self.addrs.contains_key(addr.borrow())
+            && !self.ids.contains_key(id.borrow())
+        {
+            return None;
+        }
+        Some(self.insert(addr, id, context))
+    }
     pub fn remove_id(&mut self, id: &PeerIdType) -> Option<T> {
         self._remove_id(id).map(|x| x.0)
     }
@@ -252,3 +299,146 @@ impl<T: Send + Sync> ForwardMapping<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clear_timeouts_reports_addr() {
+        let mut mapping = ForwardMapping::default();
+        let client: SocketAddr = "127.0.0.1:10022".parse().unwrap();
+        mapping.insert(ForwardAddr::Pipe, &b"pipe"[..], 1);
+        mapping.insert(ForwardAddr::Socket(client), &b"client"[..], 2);
+        std::thread::sleep(Duration::from_millis(1));
+        let (mut removed, next_timeout) =
+            mapping.clear_timeouts(Duration::ZERO, Duration::ZERO, Duration::ZERO);
+        removed.sort_by_key(|(_, context)| *context);
+        assert_eq!(
+            removed
+                .iter()
+                .map(|(addr, context)| (&**addr, *context))
+                .collect::<Vec<_>>(),
+            [(&ForwardAddr::Pipe, 1), (&ForwardAddr::Socket(client), 2)]
+        );
+        assert_eq!(next_timeout, None);
+        assert_eq!(mapping.len(), 0);
+    }
+
+    #[test]
+    fn reply_timeout_is_separate() {
+        let mut mapping = ForwardMapping::default();
+        let info = mapping.insert(ForwardAddr::Pipe, &b"pipe"[..], ());
+        info.got_reply();
+        let last_forward = info.last_forward;
+        let last_reply = info.last_reply.unwrap();
+        let (short, long) = (Duration::from_secs(10), Duration::from_secs(60));
+        assert_eq!(info.timeout(Duration::ZERO, short, long), last_reply + long);
+        assert_eq!(
+            info.timeout(Duration::ZERO, long, short),
+            last_forward + long
+        );
+    }
+
+    #[test]
+    fn try_insert_refuses_new_clients_when_full() {
+        let mut mapping = ForwardMapping::default();
+        let client = |port| ForwardAddr::Socket(SocketAddr::from(([127, 0, 0, 1], port)));
+        for port in 0..3 {
+            let id = format!("client{port}");
+            assert!(mapping
+                .try_insert(client(port), id.as_bytes(), (), 3)
+                .is_some());
+        }
+        assert!(mapping
+            .try_insert(client(3), &b"client3"[..], (), 3)
+            .is_none());
+        assert_eq!(mapping.len(), 3);
+        // Known clients can still refresh, even from a new address.
+        assert!(mapping
+            .try_insert(client(0), &b"client0"[..], (), 3)
+            .is_some());
+        assert!(mapping
+            .try_insert(client(4), &b"client1"[..], (), 3)
+            .is_some());
+        assert_eq!(mapping.len(), 3);
+        assert!(mapping.get_addr(&client(1)).is_none());
+    }
+    /// Check that `addr` and `id` resolve to the same mapping, and that the mapping points back
+    /// at them.
+    fn assert_mapped<T: Send + Sync + Copy + PartialEq + std::fmt::Debug>(
+        mapping: &ForwardMapping<T>,
+        addr: &ForwardAddr,
+        id: &[u8],
+        context: T,
+    ) {
+        let by_addr = mapping.get_addr(addr).expect("addr should be mapped");
+        let by_id = mapping.get_id(id).expect("id should be mapped");
+        assert!(std::ptr::eq(by_addr, by_id));
+        assert_eq!(&*by_addr.addr(), addr);
+        assert_eq!(&*by_addr.id(), id);
+        assert_eq!(*by_addr.context(), context);
+    }
+
+    #[test]
+    fn insert_same_addr_new_id() {
+        let mut mapping = ForwardMapping::default();
+        let client = || ForwardAddr::Socket(SocketAddr::from(([127, 0, 0, 1], 10022)));
+        mapping.insert(client(), &b"one"[..], 1);
+        let addr = mapping.get_addr(&client()).unwrap().addr();
+        mapping.insert(client(), &b"two"[..], 2);
+        assert_eq!(mapping.len(), 1);
+        assert!(mapping.get_id(b"one").is_none());
+        assert_mapped(&mapping, &client(), b"two", 2);
+        assert!(Arc::ptr_eq(
+            &mapping.get_addr(&client()).unwrap().addr(),
+            &addr
+        ));
+    }
+
+    #[test]
+    fn insert_same_id_new_addr() {
+        let mut mapping = ForwardMapping::default();
+        let client = |port| ForwardAddr::Socket(SocketAddr::from(([127, 0, 0, 1], port)));
+        mapping.insert(client(1), &b"one"[..], 1);
+        let id = mapping.get_id(b"one").unwrap().id();
+        mapping.insert(client(2), &b"one"[..], 2);
+        assert_eq!(mapping.len(), 1);
+        assert!(mapping.get_addr(&client(1)).is_none());
+        assert_mapped(&mapping, &client(2), b"one", 2);
+        assert!(Arc::ptr_eq(&mapping.get_id(b"one").unwrap().id(), &id));
+    }
+
+    #[test]
+    fn insert_both_new() {
+        let mut mapping = ForwardMapping::default();
+        let client = |port| ForwardAddr::Socket(SocketAddr::from(([127, 0, 0, 1], port)));
+        mapping.insert(client(1), &b"one"[..], 1);
+        mapping.insert(client(2), &b"two"[..], 2);
+        assert_eq!(mapping.len(), 2);
+        assert_mapped(&mapping, &client(1), b"one", 1);
+        assert_mapped(&mapping, &client(2), b"two", 2);
+
+        // An addr and an id from two different mappings replace both of them.
+        mapping.insert(client(1), &b"two"[..], 3);
+        assert_eq!(mapping.len(), 1);
+        assert!(mapping.get_id(b"one").is_none());
+        assert!(mapping.get_addr(&client(2)).is_none());
+        assert_mapped(&mapping, &client(1), b"two", 3);
+    }
+
+    #[test]
+    fn insert_identical_pair_keeps_mapping() {
+        let mut mapping = ForwardMapping::default();
+        let info = mapping.insert(ForwardAddr::Pipe, &b"pipe"[..], 1);
+        info.got_reply();
+        let last_reply = info.last_reply;
+        let info: *const _ = info;
+        let again = mapping.insert(ForwardAddr::Pipe, &b"pipe"[..], 2);
+        assert!(std::ptr::eq(info, again));
+        assert_eq!(again.last_reply, last_reply);
+        assert_eq!(mapping.len(), 1);
+        // The existing mapping is returned as is, so the new context is dropped.
+        assert_mapped(&mapping, &ForwardAddr::Pipe, b"pipe", 1);
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     borrow::Cow,
     collections::HashMap,
+    future::Future,
     ops::{Index, Range},
     sync::{
         atomic::{AtomicU8, Ordering},
@@ -10,19 +11,27 @@ use std::{
 };
 
 use intouch2::{
-    datas::GeckoDatas,
-    generate_uuid,
-    object::{package_data, NetworkPackage, NetworkPackageData, StatusChange},
-    parser::ParseError,
+    composer::compose_network_data,
+    datas::{GeckoDatas, Snapshot},
+    generate_uuid, known_datas,
+    object::{
+        package_data, NetworkPackage, NetworkPackageData, SetStatusTooLong, StatusChange,
+        WatercareSchedule,
+    },
+    parser::{parse_hello_id_name, HelloError, ParseError},
 };
 use tokio::{
     select,
     sync::{self, Mutex},
-    task::JoinSet,
     time::{self, timeout},
 };
 
-use crate::{port_forward::SpaPipe, WithBuffer};
+use crate::{
+    health::LinkStats,
+    port_forward::{ForwardEvent, SpaPipe},
+    tasks::SupervisedTasks,
+    WithBuffer,
+};
 
 pub struct SpaConnection {
     pipe: Arc<SpaPipe>,
@@ -30,17 +39,222 @@ pub struct SpaConnection {
     dst: Arc<[u8]>,
     name: Arc<[u8]>,
     watercare_mode: Arc<Mutex<sync::watch::Sender<Option<u8>>>>,
+    watercare_state: Arc<sync::watch::Sender<WatercareState>>,
+    packs: Arc<sync::watch::Sender<Option<Box<[u8]>>>>,
     ping_interval: Arc<Mutex<time::Interval>>,
     get_watercare_mode_interval: Arc<Mutex<time::Interval>>,
-    full_state_download_interval: Arc<Mutex<time::Interval>>,
+    get_channel_interval: Arc<Mutex<time::Interval>>,
+    channel: Arc<sync::watch::Sender<Option<package_data::ChannelCurrent>>>,
+    full_state_download_interval: Arc<Mutex<Option<time::Interval>>>,
+    refresh: Arc<sync::Notify>,
     state: Arc<sync::Mutex<GeckoDatas>>,
     state_valid: Arc<sync::watch::Sender<bool>>,
-    jobs: Option<Mutex<JoinSet<Result<(), SpaError>>>>,
+    priority_ranges: Arc<[Range<usize>]>,
+    priority_valid: Arc<sync::watch::Sender<bool>>,
+    connected: Arc<sync::watch::Sender<bool>>,
+    link_stats: Arc<sync::watch::Sender<LinkStats>>,
+    last_update: Arc<sync::watch::Sender<Option<time::Instant>>>,
+    jobs: Option<Mutex<SupervisedTasks<(), SpaError>>>,
     state_subscribers: Arc<sync::Mutex<HashMap<Range<usize>, sync::watch::Sender<Box<[u8]>>>>>,
+    u8_subscribers: ValueSubscribers<u8>,
+    u16_subscribers: ValueSubscribers<u16>,
+    u16_le_subscribers: ValueSubscribers<u16>,
+    change_subscribers: ChangeSubscribers,
     commanders: Arc<sync::Mutex<sync::mpsc::Receiver<SpaCommand>>>,
     new_commander: Arc<sync::mpsc::Sender<SpaCommand>>,
     seq: Arc<AtomicU8>,
     version: package_data::Version,
+    notify_dirty: Arc<sync::Notify>,
+    status_requester: StatusRequester,
+    temperature_unit_addr: Option<usize>,
+    dry_run: bool,
+    verbose: bool,
+}
+
+type ValueSubscribers<T> = Arc<sync::Mutex<HashMap<usize, sync::watch::Sender<Option<T>>>>>;
+
+/// A receiver of every change to a range of the spa memory, see
+/// [SpaConnection::subscribe_changes].
+struct ChangeSubscriber {
+    range: Range<usize>,
+    last: Box<[u8]>,
+    sender: sync::mpsc::UnboundedSender<Box<[u8]>>,
+}
+
+type ChangeSubscribers = Arc<sync::Mutex<Vec<ChangeSubscriber>>>;
+
+/// Send the subscribed ranges which overlap `written` and were changed by it. This is called
+/// after every write to the spa memory, while the memory is still locked, so that no change is
+/// coalesced with the next one. Subscribers whose receiver has been dropped are removed.
+fn send_changes(
+    subscribers: &mut Vec<ChangeSubscriber>,
+    state: &GeckoDatas,
+    written: Range<usize>,
+) {
+    subscribers.retain_mut(|subscriber| {
+        if subscriber.range.start < written.end && written.start < subscriber.range.end {
+            let data = &state[subscriber.range.clone()];
+            if *data != *subscriber.last {
+                subscriber.last = data.into();
+                return subscriber.sender.send(data.into()).is_ok();
+            }
+        }
+        !subscriber.sender.is_closed()
+    });
+}
+
+fn read_value<T, const N: usize>(state: &GeckoDatas, addr: usize, decode: fn([u8; N]) -> T) -> T {
+    let bytes: &[u8; N] = state[addr..addr + N]
+        .try_into()
+        .expect("The range is N bytes long");
+    decode(*bytes)
+}
+
+/// Whether a package to `dst` is meant for neither this connection nor the spa.
+fn is_unexpected_destination(dst: Option<&[u8]>, my_id: &[u8], spa_id: &[u8]) -> bool {
+    dst.is_none_or(|dst| dst != my_id && dst != spa_id)
+}
+
+/// Whether `range` lies within one of `ranges`.
+fn is_covered(ranges: &[Range<usize>], range: &Range<usize>) -> bool {
+    ranges
+        .iter()
+        .any(|known| known.start <= range.start && range.end <= known.end)
+}
+
+/// Send the new value to the subscribers which overlap `changed`. If `known` is set, only the
+/// subscribers within those ranges are updated, since the rest of the memory isn't downloaded yet.
+fn update_values<T: Copy + PartialEq, const N: usize>(
+    subscribers: &HashMap<usize, sync::watch::Sender<Option<T>>>,
+    state: &GeckoDatas,
+    changed: &Range<usize>,
+    known: Option<&[Range<usize>]>,
+    decode: fn([u8; N]) -> T,
+) {
+    for (addr, subscriber) in subscribers.iter() {
+        if *addr < changed.end
+            && changed.start < addr + N
+            && known.is_none_or(|known| is_covered(known, &(*addr..addr + N)))
+        {
+            let value = Some(read_value(state, *addr, decode));
+            subscriber.send_if_modified(|old_value| {
+                if *old_value != value {
+                    *old_value = value;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+    }
+}
+
+/// Requests parts of the spa memory, and writes the answers into the local copy of it.
+#[derive(Clone)]
+struct StatusRequester {
+    pipe: Arc<SpaPipe>,
+    src: Arc<[u8]>,
+    dst: Arc<[u8]>,
+    seq: Arc<AtomicU8>,
+    state: Arc<sync::Mutex<GeckoDatas>>,
+    notify_dirty: Arc<sync::Notify>,
+    change_subscribers: ChangeSubscribers,
+    /// The answers don't say which request they belong to, so only one request can be active at
+    /// a time.
+    active: Arc<Mutex<()>>,
+}
+
+impl StatusRequester {
+    /// Returns `false` if the spa didn't send the whole range in time.
+    async fn request(&self, start: u16, length: u16) -> Result<bool, SpaError> {
+        let _active = self.active.lock().await;
+        let mut data_read = usize::from(start);
+        let end = data_read + usize::from(length);
+        if end > self.state.lock().await.len() {
+            return Err(SpaError::InvalidData(
+                "Requested status is outside of the spa memory",
+            ));
+        }
+        let mut rx = self.pipe.subscribe();
+        self.pipe
+            .tx
+            .send(
+                NetworkPackage::Addressed {
+                    src: Some((*self.src).into()),
+                    dst: Some((*self.dst).into()),
+                    data: package_data::RequestStatus {
+                        seq: self.seq.fetch_add(1, Ordering::Relaxed),
+                        start,
+                        length,
+                    }
+                    .into(),
+                }
+                .to_static(),
+            )
+            .await?;
+        let mut expected = 0;
+        let timeout_at = time::Instant::now() + Duration::from_secs(5);
+        loop {
+            let Ok(recv) = time::timeout_at(timeout_at, rx.recv()).await else {
+                return Ok(false);
+            };
+            match recv? {
+                NetworkPackage::Addressed {
+                    data:
+                        NetworkPackageData::Status(package_data::Status {
+                            seq,
+                            next,
+                            length,
+                            data,
+                        }),
+                    ..
+                } if seq == expected => {
+                    if usize::from(length) != data.len() {
+                        return Err(SpaError::InvalidData("Invalid Status length field"));
+                    }
+                    let read_end = data_read + data.len();
+                    if read_end > end {
+                        return Err(SpaError::InvalidData("Status is longer than requested"));
+                    }
+                    let mut state = self.state.lock().await;
+                    state[data_read..read_end].copy_from_slice(&data);
+                    send_changes(
+                        &mut *self.change_subscribers.lock().await,
+                        &state,
+                        data_read..read_end,
+                    );
+                    drop(state);
+                    if read_end == end {
+                        self.notify_dirty.notify_waiters();
+                        return Ok(true);
+                    }
+                    data_read = read_end;
+                    expected = next;
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+async fn poll_range(
+    requester: StatusRequester,
+    range: Range<usize>,
+    interval: Duration,
+) -> Result<(), SpaError> {
+    let (Ok(start), Ok(length)) = (u16::try_from(range.start), u16::try_from(range.len())) else {
+        return Err(SpaError::InvalidData(
+            "Polled range is outside of the spa memory",
+        ));
+    };
+    let mut interval = time::interval(interval);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        if !requester.request(start, length).await? {
+            eprintln!("Spa did not answer poll of {range:?}");
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -51,8 +265,12 @@ pub enum SpaError {
     Io(#[from] std::io::Error),
     #[error("Parse error: {0}")]
     Parse(#[from] ParseError),
+    #[error("Invalid hello: {0}")]
+    InvalidHello(#[from] HelloError),
     #[error("Spa timed out")]
     SpaConnectionLost,
+    #[error("Spa did not answer {0}")]
+    NoAnswer(&'static str),
     #[error("Spa pipe error: {0}")]
     PipeSendFailed(#[from] tokio::sync::mpsc::error::SendError<NetworkPackage<'static>>),
     #[error("Spa pipe error: {0}")]
@@ -67,12 +285,43 @@ pub enum SpaError {
     JoinError(#[from] tokio::task::JoinError),
     #[error("Invalid data received: {0}")]
     InvalidData(&'static str),
+    #[error("Invalid command: {0}")]
+    SetStatusTooLong(#[from] SetStatusTooLong),
     #[error("Deadlock: {0}")]
     Deadlock(&'static str),
     #[error("Spa object not initialized")]
     NotInitialized,
 }
 
+impl SpaError {
+    /// Whether connecting to the spa again may make the error go away. This is meant for a spa
+    /// reconnect loop to choose between backing off and giving up, and has no caller until there
+    /// is one.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            // The spa or the network stopped responding, which may be temporary.
+            SpaError::SpaConnectionLost | SpaError::NoAnswer(_) | SpaError::Io(_) => true,
+            // The port forward went away or fell behind. A new forward gives a new pipe.
+            SpaError::PipeSendFailed(_) | SpaError::PipeReceiveFailed(_) => true,
+            // The spa sent something which doesn't match what we expect, such as when the target
+            // isn't a spa or the memory size is wrong. It will send the same thing next time.
+            SpaError::UnexpectedAnswer(_)
+            | SpaError::Parse(_)
+            | SpaError::InvalidHello(_)
+            | SpaError::InvalidData(_) => false,
+            // The command is rejected before it is sent, and will be rejected again.
+            SpaError::SetStatusTooLong(_) => false,
+            // These are bugs in this program, and reconnecting won't fix them.
+            SpaError::KeypressSendFailed(_)
+            | SpaError::WatchFailed(_)
+            | SpaError::SendWatchFailed(_)
+            | SpaError::JoinError(_)
+            | SpaError::Deadlock(_)
+            | SpaError::NotInitialized => false,
+        }
+    }
+}
+
 impl WithBuffer for SpaConnection {
     type Buffer = [u8; 4096];
 
@@ -81,47 +330,230 @@ impl WithBuffer for SpaConnection {
     }
 }
 
+/// How often the [SpaConnection] requests data which the spa doesn't push by itself.
+#[derive(Debug, Clone)]
+pub struct SpaIntervals {
+    /// Interval between downloads of the full spa memory. When this is `None`, the memory is
+    /// only downloaded when the spa connects, and when [SpaConnection::refresh] is called.
+    pub full_state_download: Option<Duration>,
+    /// Interval between requests for the current watercare mode.
+    pub watercare: Duration,
+    /// Interval between requests for the radio channel and its signal strength.
+    pub channel: Duration,
+}
+
+impl Default for SpaIntervals {
+    fn default() -> Self {
+        Self {
+            full_state_download: Some(Duration::from_secs(1800)),
+            watercare: Duration::from_secs(1800),
+            channel: Duration::from_secs(300),
+        }
+    }
+}
+
+/// The watercare mode together with the rules of the watercare modes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct WatercareState {
+    /// The active watercare mode, or `None` if the spa hasn't reported it yet.
+    pub mode: Option<u8>,
+    /// The rules of every watercare mode, or `None` if they haven't been downloaded yet.
+    pub schedules: Option<Vec<WatercareSchedule>>,
+}
+
+/// Request the watercare rules, and wait for the answer.
+async fn request_watercare_schedules(
+    pipe: &SpaPipe,
+    src: &[u8],
+    dst: &[u8],
+    seq: &AtomicU8,
+) -> Result<Vec<WatercareSchedule>, SpaError> {
+    let mut rx = pipe.subscribe();
+    pipe.tx
+        .send(
+            NetworkPackage::Addressed {
+                src: Some(src.into()),
+                dst: Some(dst.into()),
+                data: package_data::RequestWatercare {
+                    remainder: seq.fetch_add(1, Ordering::Relaxed),
+                }
+                .into(),
+            }
+            .to_static(),
+        )
+        .await?;
+    let timeout_at = time::Instant::now() + Duration::from_secs(5);
+    loop {
+        let Ok(recv) = time::timeout_at(timeout_at, rx.recv()).await else {
+            return Err(SpaError::NoAnswer("the watercare request"));
+        };
+        if let NetworkPackage::Addressed {
+            data: NetworkPackageData::WatercareRequest(reply),
+            ..
+        } = recv?
+        {
+            return Ok(reply.schedules()?);
+        }
+    }
+}
+
+/// The temperature unit the spa is configured to display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    /// The unit of the temperature unit flag in the spa memory.
+    pub fn from_flag(flag: u8) -> Self {
+        match flag {
+            0 => TemperatureUnit::Celsius,
+            _ => TemperatureUnit::Fahrenheit,
+        }
+    }
+
+    /// The unit of a Home Assistant `temperature_unit` field.
+    pub fn from_home_assistant_unit(unit: &str) -> Option<Self> {
+        match unit {
+            "C" => Some(TemperatureUnit::Celsius),
+            "F" => Some(TemperatureUnit::Fahrenheit),
+            _ => None,
+        }
+    }
+
+    /// Convert `temperature` from this unit to `unit`.
+    pub fn convert(&self, temperature: f64, unit: TemperatureUnit) -> f64 {
+        match (self, unit) {
+            (TemperatureUnit::Celsius, TemperatureUnit::Fahrenheit) => {
+                temperature * 9.0 / 5.0 + 32.0
+            }
+            (TemperatureUnit::Fahrenheit, TemperatureUnit::Celsius) => {
+                (temperature - 32.0) * 5.0 / 9.0
+            }
+            _ => temperature,
+        }
+    }
+
+    /// The unit as written in Home Assistant `temperature_unit` fields.
+    pub fn home_assistant_unit(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "C",
+            TemperatureUnit::Fahrenheit => "F",
+        }
+    }
+
+    /// The unit as written in Home Assistant `unit_of_measurement` fields.
+    pub fn unit_of_measurement(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "°C",
+            TemperatureUnit::Fahrenheit => "°F",
+        }
+    }
+}
+
+/// A readable name for `position` in the spa memory when it's in [known_datas], such as
+/// "secondary_color_type", or "primary_color_1" for the second byte of a longer value.
+pub fn address_name(position: usize) -> Option<String> {
+    let known = known_datas::known_datas()
+        .iter()
+        .find(|known| known.range().contains(&position))?;
+    let mut address_name = String::with_capacity(known.name.len() + 4);
+    for (i, c) in known.name.char_indices() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                address_name.push('_');
+            }
+            address_name.push(c.to_ascii_lowercase());
+        } else {
+            address_name.push(c);
+        }
+    }
+    if known.length > 1 {
+        address_name.push_str(&format!("_{}", position - known.range().start));
+    }
+    Some(address_name)
+}
+
+/// Receives the result of a [SpaCommand], once it has been sent to the spa or rejected.
+pub type SpaCommandResponse = sync::oneshot::Sender<Result<(), SpaError>>;
+
 #[derive(Debug)]
 pub enum SpaCommand {
     SetStatus {
         config_version: u8,
         log_version: u8,
         pack_type: u8,
+        /// See [package_data::SetStatus::framing].
+        framing: u8,
         pos: u16,
         data: Box<[u8]>,
+        response: Option<SpaCommandResponse>,
+    },
+    SetWatercare {
+        mode: u8,
+        response: Option<SpaCommandResponse>,
+    },
+    KeyPress {
+        pack_type: u8,
+        key: u8,
+        response: Option<SpaCommandResponse>,
     },
-    SetWatercare(u8),
 }
 
-impl SpaConnection {
-    pub async fn subscribe(&self, index: Range<usize>) -> sync::watch::Receiver<Box<[u8]>> {
-        let mut subscribers = self.state_subscribers.lock().await;
-        match subscribers.entry(index) {
-            std::collections::hash_map::Entry::Occupied(subscribers) => {
-                subscribers.get().subscribe()
-            }
-            std::collections::hash_map::Entry::Vacant(new) => {
-                let state = self.state.lock().await;
-                let current_value = state.index(new.key().clone()).into();
-                new.insert(sync::watch::Sender::new(current_value))
-                    .subscribe()
-            }
+fn respond(response: Option<SpaCommandResponse>, result: Result<(), SpaError>) {
+    match (response, result) {
+        (Some(response), result) => {
+            // The sender may have stopped waiting for the result, which is fine.
+            let _ = response.send(result);
         }
+        (None, Err(e)) => eprintln!("Dropped command: {e}"),
+        (None, Ok(())) => (),
     }
+}
 
-    pub fn version(&self) -> &package_data::Version {
-        &self.version
-    }
-
-    pub async fn subscribe_watercare_mode(&self) -> sync::watch::Receiver<Option<u8>> {
-        self.watercare_mode.lock().await.subscribe()
-    }
+/// The options of a [SpaConnection]. [Self::new] sets the defaults, which can be changed
+/// before calling [Self::connect].
+#[derive(Debug, Clone)]
+pub struct SpaConnectionBuilder {
+    /// The size of the spa memory.
+    pub memory_size: usize,
+    pub intervals: SpaIntervals,
+    /// Ranges of the spa memory which are downloaded before the rest of it, so that the values
+    /// in them are available sooner.
+    pub priority_ranges: Vec<Range<usize>>,
+    /// See [SpaConnection::set_temperature_unit_addr].
+    pub temperature_unit_addr: Option<u16>,
+    /// See [SpaConnection::set_dry_run].
+    pub dry_run: bool,
+    /// See [SpaConnection::set_verbose].
+    pub verbose: bool,
+}
 
-    pub async fn len(&self) -> usize {
-        self.state.lock().await.len()
+impl SpaConnectionBuilder {
+    pub fn new(memory_size: usize) -> Self {
+        Self {
+            memory_size,
+            intervals: Default::default(),
+            priority_ranges: vec![],
+            temperature_unit_addr: None,
+            dry_run: false,
+            verbose: false,
+        }
     }
 
-    pub async fn new(memory_size: usize, pipe: SpaPipe) -> Result<Self, SpaError> {
+    /// Connect to the spa through `pipe`.
+    pub async fn connect(self, pipe: SpaPipe) -> Result<SpaConnection, SpaError> {
+        if self
+            .priority_ranges
+            .iter()
+            .any(|range| range.is_empty() || range.end > self.memory_size)
+        {
+            return Err(SpaError::InvalidData(
+                "Priority range is empty or outside of the spa memory",
+            ));
+        }
         pipe.tx
             .send(NetworkPackage::Hello(Cow::Borrowed(b"1")))
             .await?;
@@ -134,11 +566,8 @@ impl SpaConnection {
             msg => Err(SpaError::UnexpectedAnswer(msg.to_static())),
         }?;
         let (dst, name): (Arc<[u8]>, Box<[u8]>) = {
-            let pos = receiver
-                .iter()
-                .position(|x| *x == '|' as u8)
-                .unwrap_or(receiver.len());
-            (receiver[0..pos].into(), receiver[pos + 1..].into())
+            let (id, name) = parse_hello_id_name(&receiver)?;
+            (id.into(), name.into())
         };
         let src: Arc<[u8]> = generate_uuid().into();
         pipe.tx
@@ -158,17 +587,24 @@ impl SpaConnection {
                 .to_static(),
             )
             .await?;
-        let state = GeckoDatas::new(memory_size);
-        let mut full_state_download_interval =
-            time::interval_at(time::Instant::now(), Duration::from_secs(1800));
+        let state = GeckoDatas::new(self.memory_size);
+        let mut full_state_download_interval = self
+            .intervals
+            .full_state_download
+            .map(|period| time::interval_at(time::Instant::now(), period));
         let mut ping_interval = time::interval_at(time::Instant::now(), Duration::from_secs(3));
         let mut get_watercare_mode_interval =
-            time::interval_at(time::Instant::now(), Duration::from_secs(1800));
+            time::interval_at(time::Instant::now(), self.intervals.watercare);
+        let mut get_channel_interval =
+            time::interval_at(time::Instant::now(), self.intervals.channel);
         for interval in [
-            &mut full_state_download_interval,
             &mut ping_interval,
             &mut get_watercare_mode_interval,
-        ] {
+            &mut get_channel_interval,
+        ]
+        .into_iter()
+        .chain(full_state_download_interval.as_mut())
+        {
             interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
         }
 
@@ -186,24 +622,58 @@ impl SpaConnection {
                         version
                     );
                     let (new_commander, commanders) = sync::mpsc::channel(10);
-                    break Ok(Self {
-                        seq: seq.into(),
+                    let seq = Arc::new(seq);
+                    let pipe = Arc::new(pipe);
+                    let state = Arc::new(Mutex::new(state));
+                    let notify_dirty = Arc::new(sync::Notify::new());
+                    let change_subscribers = ChangeSubscribers::default();
+                    let status_requester = StatusRequester {
+                        pipe: pipe.clone(),
+                        src: src.clone(),
+                        dst: dst.clone(),
+                        seq: seq.clone(),
+                        state: state.clone(),
+                        notify_dirty: notify_dirty.clone(),
+                        change_subscribers: change_subscribers.clone(),
+                        active: Default::default(),
+                    };
+                    break Ok(SpaConnection {
+                        seq,
                         name: name.into(),
-                        pipe: pipe.into(),
+                        pipe,
                         src,
                         jobs: None,
                         dst,
+                        notify_dirty,
+                        status_requester,
+                        temperature_unit_addr: self.temperature_unit_addr.map(usize::from),
+                        dry_run: self.dry_run,
+                        verbose: self.verbose,
                         version,
                         new_commander: new_commander.into(),
                         state_valid: tokio::sync::watch::Sender::new(false).into(),
+                        priority_ranges: self.priority_ranges.into(),
+                        priority_valid: sync::watch::Sender::new(false).into(),
+                        connected: sync::watch::Sender::new(false).into(),
+                        link_stats: sync::watch::Sender::new(Default::default()).into(),
+                        last_update: sync::watch::Sender::new(None).into(),
                         commanders: Mutex::new(commanders).into(),
                         watercare_mode: Mutex::new(sync::watch::Sender::new(None)).into(),
+                        watercare_state: sync::watch::Sender::new(Default::default()).into(),
+                        packs: sync::watch::Sender::new(None).into(),
                         ping_interval: Mutex::new(ping_interval).into(),
                         get_watercare_mode_interval: Mutex::new(get_watercare_mode_interval).into(),
+                        get_channel_interval: Mutex::new(get_channel_interval).into(),
+                        channel: sync::watch::Sender::new(None).into(),
                         full_state_download_interval: Mutex::new(full_state_download_interval)
                             .into(),
-                        state: Arc::new(state.into()),
+                        refresh: Default::default(),
+                        state,
                         state_subscribers: Default::default(),
+                        u8_subscribers: Default::default(),
+                        u16_subscribers: Default::default(),
+                        u16_le_subscribers: Default::default(),
+                        change_subscribers,
                     });
                 }
                 NetworkPackage::Hello(_) => continue,
@@ -212,6 +682,319 @@ impl SpaConnection {
         }?;
         Ok(spa_object)
     }
+}
+
+impl SpaConnection {
+    /// Subscribe to a range of the spa memory. The receiver starts out with the current value of
+    /// the range.
+    pub async fn subscribe(&self, index: Range<usize>) -> sync::watch::Receiver<Box<[u8]>> {
+        let state = self.state.lock().await;
+        self.subscribe_locked(&state, index).await
+    }
+
+    /// Read the current value of a range of the spa memory and subscribe to it, without the
+    /// chance of missing a change in between. Fails if the range hasn't been downloaded from the
+    /// spa yet.
+    pub async fn snapshot_and_subscribe(
+        &self,
+        index: Range<usize>,
+    ) -> Result<(Box<[u8]>, sync::watch::Receiver<Box<[u8]>>), SpaError> {
+        let state = self.state.lock().await;
+        if index.start > index.end || index.end > state.len() {
+            return Err(SpaError::InvalidData("Range is outside of the spa memory"));
+        }
+        if !self.is_downloaded(&index) {
+            return Err(SpaError::NotInitialized);
+        }
+        let snapshot = state.index(index.clone()).into();
+        Ok((snapshot, self.subscribe_locked(&state, index).await))
+    }
+
+    async fn subscribe_locked(
+        &self,
+        state: &GeckoDatas,
+        index: Range<usize>,
+    ) -> sync::watch::Receiver<Box<[u8]>> {
+        // The state is locked before the subscribers, like in the job which updates the
+        // subscribers.
+        let mut subscribers = self.state_subscribers.lock().await;
+        let current_value = state.index(index.clone());
+        match subscribers.entry(index) {
+            std::collections::hash_map::Entry::Occupied(subscriber) => {
+                let subscriber = subscriber.get();
+                subscriber.send_if_modified(|old_value| {
+                    if current_value != old_value.as_ref() {
+                        old_value.copy_from_slice(current_value);
+                        true
+                    } else {
+                        false
+                    }
+                });
+                subscriber.subscribe()
+            }
+            std::collections::hash_map::Entry::Vacant(new) => new
+                .insert(sync::watch::Sender::new(current_value.into()))
+                .subscribe(),
+        }
+    }
+
+    /// Receive the data in `index` every time it is changed, unlike [Self::subscribe] which only
+    /// keeps the latest value. Every package from the spa which changes the range is received
+    /// separately, including the packages of the memory download. Nothing is received until the
+    /// range changes. Changes are queued until they are received, so the receiver should be
+    /// drained continuously.
+    pub async fn subscribe_changes(
+        &self,
+        index: Range<usize>,
+    ) -> sync::mpsc::UnboundedReceiver<Box<[u8]>> {
+        let state = self.state.lock().await;
+        let (sender, receiver) = sync::mpsc::unbounded_channel();
+        self.change_subscribers.lock().await.push(ChangeSubscriber {
+            last: state.index(index.clone()).into(),
+            range: index,
+            sender,
+        });
+        receiver
+    }
+
+    async fn subscribe_value<T: Copy + PartialEq, const N: usize>(
+        &self,
+        subscribers: &ValueSubscribers<T>,
+        addr: usize,
+        decode: fn([u8; N]) -> T,
+    ) -> sync::watch::Receiver<Option<T>> {
+        let state = self.state.lock().await;
+        let mut subscribers = subscribers.lock().await;
+        let value = self
+            .is_downloaded(&(addr..addr + N))
+            .then(|| read_value(&state, addr, decode));
+        let subscriber = subscribers
+            .entry(addr)
+            .or_insert_with(|| sync::watch::Sender::new(None));
+        subscriber.send_if_modified(|old_value| {
+            if value.is_some() && *old_value != value {
+                *old_value = value;
+                true
+            } else {
+                false
+            }
+        });
+        subscriber.subscribe()
+    }
+
+    /// Whether `range` of the spa memory has been downloaded, either as a part of the full memory
+    /// or of a priority range.
+    fn is_downloaded(&self, range: &Range<usize>) -> bool {
+        *self.state_valid.borrow()
+            || (*self.priority_valid.borrow() && is_covered(&self.priority_ranges, range))
+    }
+
+    /// Subscribe to the byte at `addr`. The value is `None` until the spa memory has been
+    /// downloaded.
+    pub async fn subscribe_u8(&self, addr: usize) -> sync::watch::Receiver<Option<u8>> {
+        self.subscribe_value(&self.u8_subscribers, addr, u8::from_be_bytes)
+            .await
+    }
+
+    /// Subscribe to the big endian `u16` at `addr`. The value is `None` until the spa memory has
+    /// been downloaded.
+    pub async fn subscribe_u16(&self, addr: usize) -> sync::watch::Receiver<Option<u16>> {
+        self.subscribe_value(&self.u16_subscribers, addr, u16::from_be_bytes)
+            .await
+    }
+
+    /// Subscribe to the little endian `u16` at `addr`, for the values which the spa doesn't
+    /// store as big endian. The value is `None` until the spa memory has been downloaded.
+    pub async fn subscribe_u16_le(&self, addr: usize) -> sync::watch::Receiver<Option<u16>> {
+        self.subscribe_value(&self.u16_le_subscribers, addr, u16::from_le_bytes)
+            .await
+    }
+
+    /// Download the full spa memory again, without waiting for [SpaIntervals::full_state_download].
+    pub fn refresh(&self) {
+        self.refresh.notify_one();
+    }
+
+    /// Subscribe to whether the spa answers pings.
+    pub fn subscribe_connected(&self) -> sync::watch::Receiver<bool> {
+        self.connected.subscribe()
+    }
+
+    /// Subscribe to how often the spa stopped answering pings and started again, and to the error
+    /// which last interrupted the connection.
+    pub fn subscribe_link_stats(&self) -> sync::watch::Receiver<LinkStats> {
+        self.link_stats.subscribe()
+    }
+
+    /// Whether the full spa memory has been downloaded since the spa connected. Values read while
+    /// this is `false` may be outdated.
+    pub fn is_state_valid(&self) -> bool {
+        *self.state_valid.borrow()
+    }
+
+    /// Subscribe to whether the full spa memory has been downloaded. This becomes `false` when
+    /// the spa stops answering pings, and `true` again once the memory has been downloaded after
+    /// it answers again.
+    pub fn subscribe_state_valid(&self) -> sync::watch::Receiver<bool> {
+        self.state_valid.subscribe()
+    }
+
+    /// Subscribe to when the spa memory was last updated with valid data.
+    pub fn subscribe_last_update(&self) -> sync::watch::Receiver<Option<time::Instant>> {
+        self.last_update.subscribe()
+    }
+
+    /// Set the address of the temperature unit flag in the spa memory. The address depends on the
+    /// spa pack, and a byte of `0` means Celsius while anything else means Fahrenheit.
+    pub fn set_temperature_unit_addr(&mut self, addr: Option<u16>) {
+        self.temperature_unit_addr = addr.map(usize::from);
+    }
+
+    /// Log the commands instead of sending them to the spa, so that the commands of a mapping can
+    /// be tested safely. This must be set before [Self::init] is called.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Log the packages from the spa which are addressed to neither this connection nor the spa,
+    /// such as answers to an unexpected address. This must be set before [Self::init] is called.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// The temperature unit the spa is configured for. This is `None` if the address of the flag
+    /// isn't known, or if the spa memory hasn't been downloaded yet.
+    pub async fn temperature_unit(&self) -> Option<TemperatureUnit> {
+        let addr = self.temperature_unit_addr?;
+        let state = self.state.lock().await;
+        if !self.is_downloaded(&(addr..addr + 1)) || addr >= state.len() {
+            return None;
+        }
+        Some(TemperatureUnit::from_flag(state[addr]))
+    }
+
+    /// Subscribe to the temperature unit flag of the spa, which is read with
+    /// [TemperatureUnit::from_flag]. This is `None` if the address of the flag isn't known.
+    pub async fn subscribe_temperature_unit(&self) -> Option<sync::watch::Receiver<Option<u8>>> {
+        Some(self.subscribe_u8(self.temperature_unit_addr?).await)
+    }
+
+    pub fn version(&self) -> &package_data::Version {
+        &self.version
+    }
+
+    /// The firmware versions of [Self::version] as a JSON object, with the EN and CO versions
+    /// separate, such as `{"en": {"build": 1, "major": 2, "minor": 3}, "co": {...}}`.
+    pub fn version_json(&self) -> serde_json::Value {
+        let package_data::Version {
+            en_build,
+            en_major,
+            en_minor,
+            co_build,
+            co_major,
+            co_minor,
+        } = self.version;
+        serde_json::json!({
+            "en": {"build": en_build, "major": en_major, "minor": en_minor},
+            "co": {"build": co_build, "major": co_major, "minor": co_minor},
+        })
+    }
+
+    pub async fn subscribe_watercare_mode(&self) -> sync::watch::Receiver<Option<u8>> {
+        self.watercare_mode.lock().await.subscribe()
+    }
+
+    /// Subscribe to the radio channel of the spa and its signal strength, which are requested
+    /// every [SpaIntervals::channel]. This is `None` until the spa has answered.
+    pub fn subscribe_channel(&self) -> sync::watch::Receiver<Option<package_data::ChannelCurrent>> {
+        self.channel.subscribe()
+    }
+
+    /// Subscribe to the watercare mode and the watercare rules. The rules are downloaded when the
+    /// connection is initialized, and again whenever a rule is added, changed or deleted.
+    pub fn subscribe_watercare_state(&self) -> sync::watch::Receiver<WatercareState> {
+        self.watercare_state.subscribe()
+    }
+
+    /// Request the rules of all watercare modes from the spa.
+    pub async fn get_watercare_schedules(&self) -> Result<Vec<WatercareSchedule>, SpaError> {
+        request_watercare_schedules(&self.pipe, &self.src, &self.dst, &self.seq).await
+    }
+
+    /// Subscribe to the pack information reported by the spa. This is only available on firmware
+    /// which includes a payload in `PACKS`, and the value is the raw payload since its layout is
+    /// not known yet.
+    pub fn subscribe_packs(&self) -> sync::watch::Receiver<Option<Box<[u8]>>> {
+        self.packs.subscribe()
+    }
+
+    /// Subscribe to changes in the clients forwarded next to this connection, such as the spa app
+    /// timing out.
+    pub fn subscribe_forward_events(&self) -> sync::broadcast::Receiver<ForwardEvent> {
+        self.pipe.subscribe_events()
+    }
+
+    /// Request `range` of the spa memory every `interval`, for values which aren't pushed by the
+    /// spa when they change. The requests are only sent while the returned future is polled.
+    pub fn poll_range(
+        &self,
+        range: Range<usize>,
+        interval: Duration,
+    ) -> impl Future<Output = Result<(), SpaError>> + Send + 'static {
+        poll_range(self.status_requester.clone(), range, interval)
+    }
+
+    /// The sequence number to use in the next package to the spa.
+    #[cfg(feature = "experimental")]
+    pub fn next_seq(&self) -> u8 {
+        self.seq.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Send any package to the spa. Packages which need a sequence number should use
+    /// [Self::next_seq]. The returned receiver is subscribed before the package is sent, so it
+    /// will include the answer.
+    #[cfg(feature = "experimental")]
+    pub async fn send_raw(
+        &self,
+        data: NetworkPackageData<'static>,
+    ) -> Result<sync::broadcast::Receiver<NetworkPackage<'static>>, SpaError> {
+        let receiver = self.pipe.subscribe();
+        self.pipe
+            .tx
+            .send(NetworkPackage::Addressed {
+                src: Some(Cow::Owned((*self.src).into())),
+                dst: Some(Cow::Owned((*self.dst).into())),
+                data,
+            })
+            .await?;
+        Ok(receiver)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.state.lock().await.len()
+    }
+
+    /// A copy of the spa memory, which can be compared to a later copy with `snapshot-diff`.
+    pub async fn snapshot(&self) -> Snapshot {
+        self.state.lock().await.snapshot()
+    }
+
+    /// Connect to the spa with the default options, except for `intervals` and
+    /// `priority_ranges`. See [SpaConnectionBuilder] for the rest of the options.
+    pub async fn new(
+        memory_size: usize,
+        pipe: SpaPipe,
+        intervals: SpaIntervals,
+        priority_ranges: &[Range<usize>],
+    ) -> Result<Self, SpaError> {
+        SpaConnectionBuilder {
+            intervals,
+            priority_ranges: priority_ranges.into(),
+            ..SpaConnectionBuilder::new(memory_size)
+        }
+        .connect(pipe)
+        .await
+    }
 
     pub fn name(&self) -> &[u8] {
         self.name.as_ref()
@@ -229,7 +1012,7 @@ impl SpaConnection {
         select! {
             result = jobs.join_next(), if !jobs.is_empty() => {
                 if let Some(result) = result {
-                    let _: () = result??;
+                    let _: () = result?;
                 }
             },
             _ = time::sleep(time::Duration::from_millis(1000)), if jobs.is_empty() => {},
@@ -237,6 +1020,19 @@ impl SpaConnection {
         Ok(())
     }
 
+    /// Resolves once the full spa memory has been downloaded, like [Self::wait_for_valid_data],
+    /// but without borrowing the connection or driving [Self::tick]. The download runs in the
+    /// jobs started by [Self::init], so the future may be created before the connection is
+    /// initialized. Fails if the connection is dropped before the memory is downloaded. Failures
+    /// of the jobs themselves are still returned by [Self::tick].
+    pub fn on_ready(&self) -> impl Future<Output = Result<(), SpaError>> + Send + 'static {
+        let mut state_valid = self.state_valid.subscribe();
+        async move {
+            state_valid.wait_for(|valid| *valid).await?;
+            Ok(())
+        }
+    }
+
     pub async fn wait_for_valid_data(&self) -> Result<(), SpaError> {
         let mut subscriber = self.state_valid.subscribe();
         loop {
@@ -258,28 +1054,94 @@ impl SpaConnection {
         let gecko_data_len = u16::try_from(self.state.lock().await.len()).expect(
             "If this isn't u16, then the data types are incorrect, and we should not keep going",
         );
-        let mut jobs = JoinSet::new();
-        let notify_dirty = Arc::new(tokio::sync::Notify::new());
+        let mut jobs = SupervisedTasks::new();
+        let notify_dirty = self.notify_dirty.clone();
         {
             let gecko_datas = self.state.clone();
             let subscribers = self.state_subscribers.clone();
+            let u8_subscribers = self.u8_subscribers.clone();
+            let u16_subscribers = self.u16_subscribers.clone();
+            let u16_le_subscribers = self.u16_le_subscribers.clone();
             let mut state_valid = self.state_valid.subscribe();
+            let mut priority_valid = self.priority_valid.subscribe();
+            let priority_ranges = self.priority_ranges.clone();
+            let last_update = self.last_update.clone();
             let dirty = notify_dirty.clone();
             jobs.spawn(async move {
+                // Every subscriber is updated as soon as the state becomes valid, without waiting
+                // for the next change. Until the full state is valid, only the subscribers within
+                // the priority ranges are updated.
+                let mut wait_for_change = false;
+                let mut was_valid = false;
                 loop {
-                    if !*state_valid.borrow_and_update() {
-                        state_valid.changed().await?;
-                        continue;
+                    let valid = (
+                        *state_valid.borrow_and_update(),
+                        *priority_valid.borrow_and_update(),
+                    );
+                    let known = match valid {
+                        (true, _) => None,
+                        (false, true) => Some(&*priority_ranges),
+                        (false, false) => {
+                            select! {
+                                changed = state_valid.changed() => changed?,
+                                changed = priority_valid.changed() => changed?,
+                            }
+                            wait_for_change = false;
+                            continue;
+                        }
+                    };
+                    if was_valid != known.is_none() {
+                        was_valid = known.is_none();
+                        wait_for_change = false;
+                    }
+                    if wait_for_change {
+                        select! {
+                            _ = dirty.notified() => (),
+                            changed = state_valid.changed(), if !was_valid => {
+                                let _: () = changed?;
+                                continue;
+                            }
+                        }
                     }
-                    dirty.notified().await;
                     let mut gecko_datas = gecko_datas.lock().await;
                     let subscribers = subscribers.lock().await;
-                    while let Some(dirty_range) = gecko_datas.peek_dirty() {
+                    let u8_subscribers = u8_subscribers.lock().await;
+                    let u16_subscribers = u16_subscribers.lock().await;
+                    let u16_le_subscribers = u16_le_subscribers.lock().await;
+                    let mut dirty_ranges = gecko_datas.drain_dirty();
+                    if !wait_for_change {
+                        dirty_ranges.clear();
+                        dirty_ranges.push(0..gecko_datas.len());
+                    }
+                    wait_for_change = true;
+                    for dirty_range in &dirty_ranges {
+                        update_values(
+                            &u8_subscribers,
+                            &gecko_datas,
+                            dirty_range,
+                            known,
+                            u8::from_be_bytes,
+                        );
+                        update_values(
+                            &u16_subscribers,
+                            &gecko_datas,
+                            dirty_range,
+                            known,
+                            u16::from_be_bytes,
+                        );
+                        update_values(
+                            &u16_le_subscribers,
+                            &gecko_datas,
+                            dirty_range,
+                            known,
+                            u16::from_le_bytes,
+                        );
                         for (range, subscriber) in subscribers.iter() {
-                            if range.contains(&dirty_range.start)
+                            if (range.contains(&dirty_range.start)
                                 || range.contains(&dirty_range.end)
                                 || dirty_range.contains(&range.start)
-                                || dirty_range.contains(&range.end)
+                                || dirty_range.contains(&range.end))
+                                && known.is_none_or(|known| is_covered(known, range))
                             {
                                 let data = gecko_datas.index(range.clone());
                                 subscriber.send_if_modified(|old_data| match old_data {
@@ -291,8 +1153,8 @@ impl SpaConnection {
                                 });
                             }
                         }
-                        gecko_datas.pop_dirty();
                     }
+                    last_update.send_replace(Some(time::Instant::now()));
                 }
             });
         }
@@ -302,21 +1164,39 @@ impl SpaConnection {
             let dst = self.dst.clone();
             let tx = self.pipe.tx.clone();
             let mut listener = self.pipe.subscribe();
+            let connected = self.connected.clone();
+            let state_valid = self.state_valid.clone();
+            let link_stats = self.link_stats.clone();
             jobs.spawn(async move {
                 let mut pinger = timeout(Duration::from_secs(1), pinger.lock()).await.map_err(|_| SpaError::Deadlock("pinger"))?;
                 let mut unanswered_pings = 0;
+                let mut lost = false;
                 loop {
                     select! {
                         _ = pinger.tick() => {
                             tx.send(NetworkPackage::Addressed { src: Some((*src).into()), dst: Some((*dst).into()), data: package_data::Ping.into() }.to_static()).await?;
                             unanswered_pings += 1;
+                            if unanswered_pings > 1 {
+                                // The previous ping wasn't answered before this one was sent, so
+                                // the memory has to be downloaded again once the spa answers.
+                                if connected.send_replace(false) {
+                                    state_valid.send_replace(false);
+                                    lost = true;
+                                    link_stats.send_modify(|stats| stats.record_error("The spa stopped answering pings"));
+                                }
+                            }
                             if unanswered_pings > 10 {
+                                link_stats.send_modify(|stats| stats.record_error(SpaError::SpaConnectionLost));
                                 return Err(SpaError::SpaConnectionLost)
                             }
                         }
                         new_data = listener.recv() => {
                             if let NetworkPackage::Addressed { data: NetworkPackageData::Pong, .. } = new_data? {
                                 unanswered_pings = 0;
+                                if !connected.send_replace(true) && lost {
+                                    lost = false;
+                                    link_stats.send_modify(|stats| stats.reconnects += 1);
+                                }
                             }
                         }
                     }
@@ -329,58 +1209,72 @@ impl SpaConnection {
             let dst = self.src.clone();
             let tx = self.pipe.tx.clone();
             let seq = self.seq.clone();
+            let dry_run = self.dry_run;
             jobs.spawn(async move {
                 let mut commanders = commanders.lock().await;
                 loop {
-                    match commanders.recv().await {
+                    let (data, response): (NetworkPackageData, _) = match commanders.recv().await {
                         None => break Ok(()),
-                        Some(SpaCommand::SetWatercare(mode)) => {
-                            tx.send(
-                                NetworkPackage::Addressed {
-                                    src: Some((*src).into()),
-                                    dst: Some((*dst).into()),
-                                    data: package_data::SetWatercare {
-                                        seq: seq.fetch_add(1, Ordering::Relaxed),
-                                        mode,
-                                    }
-                                    .into(),
-                                }
-                                .to_static(),
-                            )
-                            .await?;
-                        }
+                        Some(SpaCommand::SetWatercare { mode, response }) => (
+                            package_data::SetWatercare {
+                                seq: seq.fetch_add(1, Ordering::Relaxed),
+                                mode,
+                            }
+                            .into(),
+                            response,
+                        ),
+                        Some(SpaCommand::KeyPress {
+                            pack_type,
+                            key,
+                            response,
+                        }) => (
+                            package_data::KeyPress {
+                                seq: seq.fetch_add(1, Ordering::Relaxed),
+                                pack_type,
+                                key,
+                            }
+                            .into(),
+                            response,
+                        ),
                         Some(SpaCommand::SetStatus {
                             config_version,
                             log_version,
                             pack_type,
+                            framing,
                             pos,
                             data,
-                        }) => match (data.len() + 5).try_into() {
-                            Ok(len) => {
-                                tx.send(
-                                    NetworkPackage::Addressed {
-                                        src: Some((*src).into()),
-                                        dst: Some((*dst).into()),
-                                        data: package_data::SetStatus {
-                                            seq: seq.fetch_add(1, Ordering::Relaxed),
-                                            pack_type,
-                                            len,
-                                            config_version,
-                                            log_version,
-                                            pos,
-                                            data: Cow::Owned(data.into()),
-                                        }
-                                        .into(),
-                                    }
-                                    .to_static(),
-                                )
-                                .await?;
-                            }
+                            response,
+                        }) => match package_data::SetStatus::new(
+                            seq.fetch_add(1, Ordering::Relaxed),
+                            pack_type,
+                            framing,
+                            config_version,
+                            log_version,
+                            pos,
+                            Cow::Owned(data.into()),
+                        ) {
+                            Ok(set_status) => (set_status.into(), response),
                             Err(e) => {
-                                eprintln!("Length is not 8 bits: {e}");
+                                respond(response, Err(e.into()));
+                                continue;
                             }
                         },
+                    };
+                    let package = NetworkPackage::Addressed {
+                        src: Some((*src).into()),
+                        dst: Some((*dst).into()),
+                        data,
+                    }
+                    .to_static();
+                    if dry_run {
+                        eprintln!(
+                            "Dry run, not sending {package:?}: {}",
+                            compose_network_data(&package).escape_ascii()
+                        );
+                    } else {
+                        tx.send(package).await?;
                     }
+                    respond(response, Ok(()));
                 }
             });
         }
@@ -428,74 +1322,148 @@ impl SpaConnection {
             });
         }
         {
-            let interval = self.full_state_download_interval.clone();
+            let channel_interval = self.get_channel_interval.clone();
+            let src = self.src.clone();
+            let dst = self.dst.clone();
             let tx = self.pipe.tx.clone();
+            let channel = self.channel.clone();
+            let seq = self.seq.clone();
+            let mut listener = self.pipe.subscribe();
+            jobs.spawn(async move {
+                let mut channel_interval = channel_interval.lock().await;
+                loop {
+                    select! {
+                        _ = channel_interval.tick() => {
+                            tx.send(NetworkPackage::Addressed {
+                                src: Some(src.as_ref().into()),
+                                dst: Some(dst.as_ref().into()),
+                                data: package_data::GetChannel {
+                                    seq: seq.fetch_add(1, Ordering::Relaxed)
+                                }.into()
+                            }.to_static()).await?;
+                        }
+                        new_data = listener.recv() => {
+                            if let NetworkPackage::Addressed { data: NetworkPackageData::ChannelCurrent(current), .. } = new_data? {
+                                channel.send_if_modified(|old_value| {
+                                    if old_value.as_ref() != Some(&current) {
+                                        *old_value = Some(current);
+                                        true
+                                    } else {
+                                        false
+                                    }
+                                });
+                            }
+                        }
+                    }
+                }
+            });
+        }
+        {
             let pipe = self.pipe.clone();
             let src = self.src.clone();
             let dst = self.dst.clone();
             let seq = self.seq.clone();
-            let gecko_data = self.state.clone();
-            let notify_dirty = notify_dirty.clone();
-            let mut state_valid = Some(self.state_valid.clone());
+            let watercare_state = self.watercare_state.clone();
+            let mut watercare_mode = self.watercare_mode.lock().await.subscribe();
+            let mut listener = self.pipe.subscribe();
             jobs.spawn(async move {
+                let mut refresh_schedules = true;
                 loop {
-                    interval.lock().await.tick().await;
-                    let seq = seq.fetch_add(1, Ordering::Relaxed);
-                    let req = NetworkPackage::Addressed {
-                        src: Some((*src).into()),
-                        dst: Some((*dst).into()),
-                        data: package_data::RequestStatus {
-                            seq,
-                            start: 0,
-                            length: gecko_data_len,
-                        }
-                        .into(),
-                    };
-                    let mut rx = pipe.subscribe();
-                    'retry: loop {
-                        tx.send(req.to_static()).await?;
-                        let mut expected = 0;
-                        let mut data_read = 0;
-                        let timeout = Duration::from_secs(5);
-                        let timeout_at = time::Instant::now() + timeout;
-                        loop {
-                            match time::timeout_at(timeout_at.clone(), rx.recv()).await {
-                                Ok(recv) => match recv? {
-                                    NetworkPackage::Addressed {
-                                        data:
-                                            NetworkPackageData::Status(package_data::Status {
-                                                seq,
-                                                next,
-                                                length,
-                                                data,
-                                            }),
-                                        ..
-                                    } if seq == expected => {
-                                        if usize::from(length) != data.len() {
-                                            return Err(SpaError::InvalidData(
-                                                "Invalid Status length field",
-                                            ))?;
-                                        }
-                                        let end = data_read + data.len();
-                                        let mut gecko_data = gecko_data.lock().await;
-                                        gecko_data[data_read..end].copy_from_slice(&*data);
-                                        if end == usize::from(gecko_data_len) {
-                                            notify_dirty.notify_waiters();
-                                            break 'retry;
-                                        }
-                                        data_read = end;
-                                        expected = next;
+                    if refresh_schedules {
+                        refresh_schedules = false;
+                        match request_watercare_schedules(&pipe, &src, &dst, &seq).await {
+                            Ok(schedules) => {
+                                watercare_state.send_if_modified(|state| {
+                                    if state.schedules.as_ref() != Some(&schedules) {
+                                        state.schedules = Some(schedules);
+                                        true
+                                    } else {
+                                        false
                                     }
-                                    _ => continue,
-                                },
-                                Err(_timeout) => continue 'retry,
+                                });
+                            }
+                            // The rules are downloaded again on the next change.
+                            Err(SpaError::NoAnswer(request)) => {
+                                eprintln!("No answer from the spa to {request}")
+                            }
+                            // The layout of the reply is a guess, so a reply which doesn't match
+                            // it leaves the rules unknown instead of stopping the connection.
+                            Err(SpaError::Parse(e)) => {
+                                eprintln!("Failed to parse the watercare rules of the spa: {e}")
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    select! {
+                        changed = watercare_mode.changed() => {
+                            let _: () = changed?;
+                            let mode = *watercare_mode.borrow_and_update();
+                            watercare_state.send_if_modified(|state| {
+                                if state.mode != mode {
+                                    state.mode = mode;
+                                    true
+                                } else {
+                                    false
+                                }
+                            });
+                        }
+                        new_data = listener.recv() => {
+                            if let NetworkPackage::Addressed {
+                                data:
+                                    NetworkPackageData::WatercareAdded(_)
+                                    | NetworkPackageData::WatercareDeleted(_)
+                                    | NetworkPackageData::ModifyWatercareResponse(_),
+                                ..
+                            } = new_data?
+                            {
+                                refresh_schedules = true;
                             }
                         }
                     }
-                    if let Some(state_valid) = std::mem::take(&mut state_valid) {
-                        state_valid.send(true)?;
+                }
+            });
+        }
+        {
+            let interval = self.full_state_download_interval.clone();
+            let refresh = self.refresh.clone();
+            let requester = self.status_requester.clone();
+            let state_valid = self.state_valid.clone();
+            let mut invalidated = self.state_valid.subscribe();
+            let mut priority_valid = Some(self.priority_valid.clone());
+            let priority_ranges = self.priority_ranges.clone();
+            jobs.spawn(async move {
+                loop {
+                    if *invalidated.borrow_and_update() {
+                        let mut interval = interval.lock().await;
+                        let tick = async {
+                            match interval.as_mut() {
+                                Some(interval) => interval.tick().await,
+                                None => std::future::pending().await,
+                            }
+                        };
+                        select! {
+                            _ = tick => (),
+                            () = refresh.notified() => (),
+                            changed = invalidated.wait_for(|valid| !valid) => {
+                                changed?;
+                            }
+                        }
+                    }
+                    if let Some(priority_valid) = std::mem::take(&mut priority_valid) {
+                        for range in priority_ranges.iter() {
+                            let start =
+                                u16::try_from(range.start).expect("The range is within the memory");
+                            let length =
+                                u16::try_from(range.len()).expect("The range is within the memory");
+                            while !requester.request(start, length).await? {}
+                        }
+                        priority_valid.send(true)?;
+                    }
+                    while !requester.request(0, gecko_data_len).await? {}
+                    state_valid.send_replace(true);
+                    if let Some(interval) = interval.lock().await.as_mut() {
+                        interval.reset();
                     }
-                    interval.lock().await.reset();
                 }
             });
         }
@@ -507,10 +1475,37 @@ impl SpaConnection {
             let seq = self.seq.clone();
             let notify_dirty = notify_dirty.clone();
             let gecko_data = self.state.clone();
+            let packs = self.packs.clone();
+            let change_subscribers = self.change_subscribers.clone();
+            let verbose = self.verbose;
             jobs.spawn(async move {
                 loop {
                     let package = rx.recv().await?;
                     match package {
+                        NetworkPackage::Addressed {
+                            data:
+                                data @ (NetworkPackageData::Packs
+                                | NetworkPackageData::PacksData(_)),
+                            dst,
+                            src,
+                        } => {
+                            if let NetworkPackageData::PacksData(packs_data) = data {
+                                packs.send_replace(Some(Box::from(&*packs_data)));
+                            }
+                            // The spa sends PACKS as a keepalive, and expects it to be
+                            // acknowledged like a pushed status.
+                            if matches!(dst, Some(ref dst) if *dst == my_id.as_ref()) {
+                                let rsp = NetworkPackage::Addressed {
+                                    src: dst,
+                                    dst: src,
+                                    data: package_data::PushStatusAck {
+                                        seq: seq.fetch_add(1, Ordering::Relaxed),
+                                    }
+                                    .into(),
+                                };
+                                tx.send(rsp.to_static()).await?;
+                            }
+                        }
                         NetworkPackage::Addressed {
                             data:
                                 NetworkPackageData::SetStatus(package_data::SetStatus {
@@ -523,8 +1518,15 @@ impl SpaConnection {
                         } if matches!(dst, Some(ref dst) if *dst == spa_id.as_ref()) => {
                             let mut data = gecko_data.lock().await;
                             let pos = usize::from(pos);
-                            let old_data: &mut [u8] = &mut data[pos..pos + new_data.len()];
-                            old_data.copy_from_slice(new_data.as_ref());
+                            if let Err(e) = data.write(pos, &new_data) {
+                                eprintln!("Ignoring SetStatus: {e}");
+                                continue;
+                            }
+                            send_changes(
+                                &mut *change_subscribers.lock().await,
+                                &data,
+                                pos..pos + new_data.len(),
+                            );
                             notify_dirty.notify_waiters();
                         }
                         NetworkPackage::Addressed {
@@ -558,12 +1560,43 @@ impl SpaConnection {
                                     change: pos,
                                     data: new_data,
                                 } = change;
-                                let pos = usize::from(*pos);
-                                let old_data: &mut [u8] = &mut data[pos..pos + 2];
-                                old_data.copy_from_slice(new_data.as_ref());
+                                if let Err(e) = data.write(usize::from(*pos), &new_data[..]) {
+                                    eprintln!("Ignoring pushed status: {e}");
+                                }
+                            }
+                            // The changes of one push are applied together, so a range spanning
+                            // several of them is only sent once.
+                            let mut change_subscribers = change_subscribers.lock().await;
+                            for change in changes.iter() {
+                                let pos = usize::from(change.change);
+                                send_changes(
+                                    &mut change_subscribers,
+                                    &data,
+                                    pos..pos + change.data.len(),
+                                );
                             }
                             notify_dirty.notify_waiters();
                         }
+                        NetworkPackage::Addressed { src, dst, data }
+                            if verbose
+                                && is_unexpected_destination(
+                                    dst.as_deref(),
+                                    &my_id,
+                                    &spa_id,
+                                ) =>
+                        {
+                            let id = |id: Option<Cow<[u8]>>| {
+                                id.map_or("nobody".into(), |id| {
+                                    String::from_utf8_lossy(&id).into_owned()
+                                })
+                            };
+                            eprintln!(
+                                "Ignoring package from {} to {}: {}",
+                                id(src),
+                                id(dst),
+                                data.display()
+                            );
+                        }
                         _ => (),
                     }
                 }
@@ -573,3 +1606,341 @@ impl SpaConnection {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_spa::FakeSpa;
+
+    #[tokio::test]
+    async fn download_from_fake_spa() -> anyhow::Result<()> {
+        let mut memory = vec![0; 300];
+        memory[0x100..0x102].copy_from_slice(&[0x12, 0x34]);
+        memory[299] = 7;
+        let (mut spa, _fake_spa) = FakeSpa::new(memory).connect().await?;
+        assert_eq!(spa.name(), b"Fake spa");
+        assert_eq!(spa.version().en_build, 1);
+        assert!(!spa.is_state_valid());
+        spa.init().await?;
+        timeout(Duration::from_secs(5), spa.wait_for_valid_data()).await??;
+        assert!(spa.is_state_valid());
+        assert_eq!(*spa.subscribe_u16(0x100).await.borrow(), Some(0x1234));
+        assert_eq!(*spa.subscribe_u16_le(0x100).await.borrow(), Some(0x3412));
+        assert_eq!(**spa.subscribe(298..300).await.borrow(), [0, 7]);
+        assert_eq!(spa.snapshot().await.data[298..], [0, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn address_names() {
+        assert_eq!(address_name(0x25c).as_deref(), Some("primary_color_0"));
+        assert_eq!(address_name(0x25e).as_deref(), Some("primary_color_2"));
+        assert_eq!(address_name(0x260).as_deref(), Some("secondary_color_type"));
+        assert_eq!(address_name(0x25f), None);
+    }
+
+    #[tokio::test]
+    async fn priority_ranges_first() -> anyhow::Result<()> {
+        let mut memory = vec![0; 300];
+        memory[0x100..0x102].copy_from_slice(&[0x12, 0x34]);
+        memory[299] = 7;
+        let fake_spa = FakeSpa::new(memory);
+        let status_requests = fake_spa.status_requests.clone();
+        let (pipe, _fake_spa) = fake_spa.spawn();
+        let priority_range = 0x100..0x102;
+        let mut spa = SpaConnection::new(
+            300,
+            pipe,
+            SpaIntervals::default(),
+            std::slice::from_ref(&priority_range),
+        )
+        .await?;
+        let mut priority = spa.subscribe_u16(0x100).await;
+        let mut other = spa.subscribe_u8(299).await;
+        spa.init().await?;
+        timeout(Duration::from_secs(5), priority.changed()).await??;
+        assert_eq!(*priority.borrow(), Some(0x1234));
+        assert_eq!(status_requests.lock().await[0], 0x100..0x102);
+        timeout(Duration::from_secs(5), spa.wait_for_valid_data()).await??;
+        timeout(Duration::from_secs(5), other.changed()).await??;
+        assert_eq!(*other.borrow(), Some(7));
+        assert_eq!(status_requests.lock().await[1], 0..300);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_without_interval() -> anyhow::Result<()> {
+        let fake_spa = FakeSpa::new([0; 10]);
+        let status_requests = fake_spa.status_requests.clone();
+        let (pipe, _fake_spa) = fake_spa.spawn();
+        let intervals = SpaIntervals {
+            full_state_download: None,
+            ..Default::default()
+        };
+        let mut spa = SpaConnection::new(10, pipe, intervals, &[]).await?;
+        spa.init().await?;
+        timeout(Duration::from_secs(5), spa.wait_for_valid_data()).await??;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*status_requests.lock().await, [0..10]);
+        spa.refresh();
+        timeout(Duration::from_secs(5), async {
+            while status_requests.lock().await.len() < 2 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(status_requests.lock().await[1], 0..10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dry_run_commands_not_sent() -> anyhow::Result<()> {
+        for dry_run in [true, false] {
+            let fake_spa = FakeSpa::new([0; 10]);
+            let memory = fake_spa.memory.clone();
+            let (pipe, _fake_spa) = fake_spa.spawn();
+            let mut spa = SpaConnectionBuilder {
+                dry_run,
+                ..SpaConnectionBuilder::new(10)
+            }
+            .connect(pipe)
+            .await?;
+            spa.init().await?;
+            let (response, result) = sync::oneshot::channel();
+            spa.sender()
+                .send(SpaCommand::SetStatus {
+                    config_version: 1,
+                    log_version: 2,
+                    pack_type: 3,
+                    framing: intouch2::object::DEFAULT_SET_STATUS_FRAMING,
+                    pos: 4,
+                    data: Box::new([5]),
+                    response: Some(response),
+                })
+                .await?;
+            timeout(Duration::from_secs(5), result).await???;
+            // The fake spa answers in order, so the command has been handled once this returns.
+            spa.get_watercare_schedules().await?;
+            let expected = if dry_run { 0 } else { 5 };
+            assert_eq!(memory.lock().await[4], expected, "dry_run: {dry_run}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribe_changes_keeps_every_change() -> anyhow::Result<()> {
+        let mut fake_spa = FakeSpa::new([0; 10]);
+        fake_spa.push_changes = true;
+        let (spa, _fake_spa) = fake_spa.connect_ready().await?;
+        let mut changes = spa.subscribe_changes(4..6).await;
+        let mut latest = spa.subscribe(4..6).await;
+        for value in 1..=3 {
+            spa.sender()
+                .send(SpaCommand::SetStatus {
+                    config_version: 1,
+                    log_version: 2,
+                    pack_type: 3,
+                    framing: intouch2::object::DEFAULT_SET_STATUS_FRAMING,
+                    pos: 4,
+                    data: Box::new([value, value]),
+                    response: None,
+                })
+                .await?;
+        }
+        for value in 1..=3 {
+            let change = timeout(Duration::from_secs(5), changes.recv()).await?;
+            assert_eq!(change.as_deref(), Some([value, value].as_slice()));
+        }
+        timeout(
+            Duration::from_secs(5),
+            latest.wait_for(|latest| **latest == [3, 3]),
+        )
+        .await??;
+        assert!(changes.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn on_ready() -> anyhow::Result<()> {
+        let (mut spa, _fake_spa) = FakeSpa::new([0; 10]).connect().await?;
+        let ready = tokio::spawn(spa.on_ready());
+        spa.init().await?;
+        timeout(Duration::from_secs(5), ready).await???;
+        assert!(spa.is_state_valid());
+        // Resolves right away once the memory is downloaded.
+        timeout(Duration::from_secs(5), spa.on_ready()).await??;
+
+        let (spa, _fake_spa) = FakeSpa::new([0; 10]).connect().await?;
+        let ready = spa.on_ready();
+        drop(spa);
+        assert!(matches!(
+            timeout(Duration::from_secs(5), ready).await?,
+            Err(SpaError::WatchFailed(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_and_subscribe() -> anyhow::Result<()> {
+        let mut fake_spa = FakeSpa::new([1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        fake_spa.push_changes = true;
+        let (mut spa, _fake_spa) = fake_spa.connect().await?;
+        assert!(matches!(
+            spa.snapshot_and_subscribe(4..6).await,
+            Err(SpaError::NotInitialized)
+        ));
+        spa.init().await?;
+        timeout(Duration::from_secs(5), spa.wait_for_valid_data()).await??;
+        assert!(matches!(
+            spa.snapshot_and_subscribe(8..12).await,
+            Err(SpaError::InvalidData(_))
+        ));
+        let (snapshot, mut receiver) = spa.snapshot_and_subscribe(4..6).await?;
+        assert_eq!(*snapshot, [5, 6]);
+        assert_eq!(**receiver.borrow_and_update(), [5, 6]);
+        spa.sender()
+            .send(SpaCommand::SetStatus {
+                config_version: 1,
+                log_version: 2,
+                pack_type: 3,
+                framing: intouch2::object::DEFAULT_SET_STATUS_FRAMING,
+                pos: 4,
+                data: Box::new([0, 0]),
+                response: None,
+            })
+            .await?;
+        timeout(Duration::from_secs(5), receiver.changed()).await??;
+        assert_eq!(**receiver.borrow_and_update(), [0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn unexpected_destination() {
+        assert!(!super::is_unexpected_destination(
+            Some(b"me"),
+            b"me",
+            b"SPA"
+        ));
+        assert!(!super::is_unexpected_destination(
+            Some(b"SPA"),
+            b"me",
+            b"SPA"
+        ));
+        assert!(super::is_unexpected_destination(
+            Some(b"other"),
+            b"me",
+            b"SPA"
+        ));
+        assert!(super::is_unexpected_destination(None, b"me", b"SPA"));
+    }
+
+    #[tokio::test]
+    async fn packs_is_acknowledged() -> anyhow::Result<()> {
+        let mut fake_spa = FakeSpa::new([0; 10]);
+        fake_spa.packs_on_ping = true;
+        let acks = fake_spa.push_status_acks.clone();
+        let (mut spa, _fake_spa) = fake_spa.connect().await?;
+        spa.init().await?;
+        timeout(Duration::from_secs(5), async {
+            while acks.lock().await.is_empty() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(acks.lock().await.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watercare_state_from_fake_spa() -> anyhow::Result<()> {
+        let mut fake_spa = FakeSpa::new([0; 10]);
+        fake_spa.watercare_mode = 2;
+        fake_spa.watercare_schedules = Box::from(*b"\x01\x01\x00\x00\x00\x16\x1e\x06\x00");
+        let (mut spa, _fake_spa) = fake_spa.connect().await?;
+        let mut state = spa.subscribe_watercare_state();
+        spa.init().await?;
+        let state = timeout(
+            Duration::from_secs(5),
+            state.wait_for(|state| state.mode.is_some() && state.schedules.is_some()),
+        )
+        .await??
+        .clone();
+        assert_eq!(state.mode, Some(2));
+        let schedules = state.schedules.expect("Checked above");
+        assert_eq!(schedules.len(), 1);
+        assert_eq!((schedules[0].end_hour, schedules[0].end_minute), (6, 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_watercare_schedules() -> anyhow::Result<()> {
+        let mut fake_spa = FakeSpa::new([0; 10]);
+        fake_spa.watercare_mode = 2;
+        fake_spa.watercare_schedules = Box::from(*b"\x07\x01\x00");
+        let (mut spa, _fake_spa) = fake_spa.connect().await?;
+        let mut state = spa.subscribe_watercare_state();
+        spa.init().await?;
+        timeout(
+            Duration::from_secs(5),
+            state.wait_for(|state| state.mode.is_some()),
+        )
+        .await??;
+        // The jobs keep running after the reply to the watercare request failed to parse, so
+        // tick doesn't return.
+        assert!(timeout(Duration::from_secs(1), spa.tick()).await.is_err());
+        assert_eq!(state.borrow().schedules, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn version_json_from_fake_spa() -> anyhow::Result<()> {
+        let (spa, _fake_spa) = FakeSpa::new([0; 10]).connect().await?;
+        assert_eq!(
+            spa.version_json(),
+            serde_json::json!({
+                "en": {"build": 1, "major": 2, "minor": 3},
+                "co": {"build": 4, "major": 5, "minor": 6},
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn channel_from_fake_spa() -> anyhow::Result<()> {
+        let mut fake_spa = FakeSpa::new([0; 10]);
+        fake_spa.channel = 12;
+        fake_spa.signal_strength = 80;
+        let (mut spa, _fake_spa) = fake_spa.connect().await?;
+        let mut channel = spa.subscribe_channel();
+        spa.init().await?;
+        let current = timeout(
+            Duration::from_secs(5),
+            channel.wait_for(|current| current.is_some()),
+        )
+        .await??
+        .clone();
+        assert_eq!(
+            current,
+            Some(package_data::ChannelCurrent {
+                channel: 12,
+                signal_strength: 80
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watercare_schedules_from_fake_spa() -> anyhow::Result<()> {
+        let mut fake_spa = FakeSpa::new([0; 10]);
+        fake_spa.watercare_schedules = Box::from(*b"\x01\x01\x00\x00\x00\x16\x1e\x06\x00");
+        let (spa, _fake_spa) = fake_spa.connect().await?;
+        let schedules = spa.get_watercare_schedules().await?;
+        assert_eq!(schedules.len(), 1);
+        assert_eq!(schedules[0].mode, 1);
+        assert_eq!(
+            (schedules[0].start_hour, schedules[0].start_minute),
+            (22, 30)
+        );
+        Ok(())
+    }
+}
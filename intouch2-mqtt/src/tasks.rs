@@ -0,0 +1,130 @@
+use std::future::Future;
+
+use tokio::{select, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+
+/// A group of jobs which stop together. The first job to fail cancels the rest of the group, and
+/// the group waits for them to stop before the error is returned, so that no job outlives the
+/// group it belongs to.
+#[derive(Debug)]
+pub struct SupervisedTasks<T, E> {
+    jobs: JoinSet<Option<Result<T, E>>>,
+    cancel: CancellationToken,
+}
+
+impl<T: Send + 'static, E: Send + 'static> SupervisedTasks<T, E> {
+    pub fn new() -> Self {
+        Self {
+            jobs: JoinSet::new(),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Spawn a job in the group. The job is dropped at its next await point once the group is
+    /// cancelled.
+    pub fn spawn(&mut self, job: impl Future<Output = Result<T, E>> + Send + 'static) {
+        let cancel = self.cancel.clone();
+        self.jobs.spawn(async move {
+            select! {
+                _ = cancel.cancelled() => None,
+                result = job => Some(result),
+            }
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Wait for the next job to finish. If it failed, the rest of the group is shut down before
+    /// the error is returned. Returns `None` when there are no jobs left.
+    pub async fn join_next(&mut self) -> Option<Result<T, E>>
+    where
+        E: From<tokio::task::JoinError>,
+    {
+        loop {
+            let result = match self.jobs.join_next().await? {
+                Ok(Some(Ok(value))) => return Some(Ok(value)),
+                // Jobs stopped by a cancellation are not results of their own.
+                Ok(None) => continue,
+                Err(e) if e.is_cancelled() => continue,
+                Ok(Some(Err(e))) => Err(e),
+                Err(e) => Err(e.into()),
+            };
+            self.shutdown().await;
+            return Some(result);
+        }
+    }
+
+    /// Cancel all jobs and wait for them to stop. New jobs can be spawned afterwards.
+    pub async fn shutdown(&mut self) {
+        self.cancel.cancel();
+        while self.jobs.join_next().await.is_some() {}
+        self.cancel = CancellationToken::new();
+    }
+}
+
+impl<T: Send + 'static, E: Send + 'static> Default for SupervisedTasks<T, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::{
+        sync::Notify,
+        task::JoinError,
+        time::{sleep, timeout},
+    };
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum TestError {
+        Failed,
+        Join,
+    }
+
+    impl From<JoinError> for TestError {
+        fn from(_: JoinError) -> Self {
+            Self::Join
+        }
+    }
+
+    #[tokio::test]
+    async fn first_error_cancels_siblings() -> anyhow::Result<()> {
+        let mut tasks = SupervisedTasks::<(), TestError>::new();
+        let sibling_stopped = Arc::new(Notify::new());
+        {
+            let sibling_stopped = sibling_stopped.clone();
+            tasks.spawn(async move {
+                struct NotifyOnDrop(Arc<Notify>);
+                impl Drop for NotifyOnDrop {
+                    fn drop(&mut self) {
+                        self.0.notify_one();
+                    }
+                }
+                let _notify = NotifyOnDrop(sibling_stopped);
+                sleep(Duration::from_secs(3600)).await;
+                Ok(())
+            });
+        }
+        tasks.spawn(async { Err(TestError::Failed) });
+        let result = timeout(Duration::from_secs(5), tasks.join_next()).await?;
+        assert_eq!(result, Some(Err(TestError::Failed)));
+        assert!(tasks.is_empty());
+        timeout(Duration::from_secs(5), sibling_stopped.notified()).await?;
+
+        tasks.spawn(async { Ok(()) });
+        assert_eq!(tasks.join_next().await, Some(Ok(())));
+        assert_eq!(tasks.join_next().await, None);
+        Ok(())
+    }
+}
@@ -13,16 +13,26 @@ pub fn compose_network_data(input: &NetworkPackage) -> Box<[u8]> {
             src,
             dst,
             data: datas,
-        } => [
-            b"<PACKT>",
-            compose_option(b"<SRCCN>", src, b"</SRCCN>").as_slice(),
-            compose_option(b"<DESCN>", dst, b"</DESCN>").as_slice(),
-            b"<DATAS>",
-            datas.compose().as_ref(),
-            b"</DATAS>",
-            b"</PACKT>",
-        ]
-        .concat()
-        .into(),
+        } => {
+            if let NetworkPackageData::SetStatus(set_status) = datas {
+                debug_assert!(
+                    set_status.len_is_consistent(),
+                    "SetStatus len {} doesn't match {} bytes of data, use SetStatus::new",
+                    set_status.len,
+                    set_status.data.len()
+                );
+            }
+            [
+                b"<PACKT>",
+                compose_option(b"<SRCCN>", src, b"</SRCCN>").as_slice(),
+                compose_option(b"<DESCN>", dst, b"</DESCN>").as_slice(),
+                b"<DATAS>",
+                datas.compose().as_ref(),
+                b"</DATAS>",
+                b"</PACKT>",
+            ]
+            .concat()
+            .into()
+        }
     }
 }
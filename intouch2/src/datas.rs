@@ -5,6 +5,39 @@ use std::{
     slice::SliceIndex,
 };
 
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("Writing {len} bytes at {pos} does not fit in {size} bytes of memory")]
+pub struct OutOfBounds {
+    pub pos: usize,
+    pub len: usize,
+    pub size: usize,
+}
+
+/// A copy of the spa memory, which can be stored and compared to other copies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    pub data: Box<[u8]>,
+}
+
+/// A position where two snapshots differ. A value is `None` if the position is outside of that
+/// snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotDifference {
+    pub pos: usize,
+    pub old: Option<u8>,
+    pub new: Option<u8>,
+}
+
+impl Snapshot {
+    pub fn diff<'a>(&'a self, new: &'a Snapshot) -> impl Iterator<Item = SnapshotDifference> + 'a {
+        (0..self.data.len().max(new.data.len())).filter_map(|pos| {
+            let (old, new) = (self.data.get(pos).copied(), new.data.get(pos).copied());
+            (old != new).then_some(SnapshotDifference { pos, old, new })
+        })
+    }
+}
+
 pub struct GeckoDatas {
     data: Box<[u8]>,
     dirty: VecDeque<Range<usize>>,
@@ -15,6 +48,12 @@ impl GeckoDatas {
         self.data.len()
     }
 
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            data: self.data.clone(),
+        }
+    }
+
     pub fn peek_dirty(&self) -> Option<&Range<usize>> {
         self.dirty.front()
     }
@@ -22,6 +61,35 @@ impl GeckoDatas {
     pub fn pop_dirty(&mut self) -> Option<Range<usize>> {
         self.dirty.pop_front()
     }
+
+    /// Take every dirty range, sorted and with overlapping or adjacent ranges merged.
+    pub fn drain_dirty(&mut self) -> Vec<Range<usize>> {
+        let mut dirty: Vec<_> = self.dirty.drain(..).collect();
+        dirty.sort_unstable_by_key(|range| range.start);
+        let mut coalesced: Vec<Range<usize>> = Vec::with_capacity(dirty.len());
+        for range in dirty {
+            match coalesced.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => coalesced.push(range),
+            }
+        }
+        coalesced
+    }
+
+    /// Copy `data` into the memory at `pos`. Nothing is written if it doesn't fit.
+    pub fn write(&mut self, pos: usize, data: &[u8]) -> Result<(), OutOfBounds> {
+        match pos.checked_add(data.len()) {
+            Some(end) if end <= self.len() => {
+                self[pos..end].copy_from_slice(data);
+                Ok(())
+            }
+            _ => Err(OutOfBounds {
+                pos,
+                len: data.len(),
+                size: self.len(),
+            }),
+        }
+    }
 }
 
 impl GeckoDatas {
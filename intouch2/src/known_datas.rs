@@ -0,0 +1,93 @@
+use std::ops::Range;
+
+/// A value at a fixed position in the spa memory. The positions are the ones used by the packs
+/// which have been reverse engineered so far, and may differ on other packs.
+pub trait KnownData {
+    const NAME: &'static str;
+    const POSITION: u16;
+    const LENGTH: u16;
+
+    fn range() -> Range<usize> {
+        let start = usize::from(Self::POSITION);
+        start..start + usize::from(Self::LENGTH)
+    }
+}
+
+/// The name, position and length of a [KnownData], for listing them at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KnownDataInfo {
+    pub name: &'static str,
+    pub position: u16,
+    pub length: u16,
+}
+
+impl KnownDataInfo {
+    pub const fn of<T: KnownData>() -> Self {
+        Self {
+            name: T::NAME,
+            position: T::POSITION,
+            length: T::LENGTH,
+        }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        let start = usize::from(self.position);
+        start..start + usize::from(self.length)
+    }
+}
+
+macro_rules! known_datas {
+    ($($(#[$meta:meta])* $name:ident($position:literal, $length:literal)),* $(,)?) => {
+        $(
+            $(#[$meta])*
+            #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+            pub struct $name;
+
+            impl KnownData for $name {
+                const NAME: &'static str = stringify!($name);
+                const POSITION: u16 = $position;
+                const LENGTH: u16 = $length;
+            }
+        )*
+
+        /// Every [KnownData] in this module, in the order they are defined.
+        pub fn known_datas() -> &'static [KnownDataInfo] {
+            const KNOWN_DATAS: &[KnownDataInfo] = &[$(KnownDataInfo::of::<$name>()),*];
+            KNOWN_DATAS
+        }
+    };
+}
+
+known_datas! {
+    /// The red, green and blue values of the primary light zone.
+    PrimaryColor(0x25c, 3),
+    /// How the secondary light zone changes between colors.
+    SecondaryColorType(0x260, 1),
+    /// The red, green and blue values of the secondary light zone.
+    SecondaryColor(0x263, 3),
+}
+
+/// The keypad buttons which can be pressed with a
+/// [KeyPress](crate::object::package_data::KeyPress). The codes are the ones used by the in.touch
+/// 2 app. Packs without the matching accessory ignore the key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::FromRepr)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[repr(u8)]
+pub enum KeyCode {
+    Pump1 = 1,
+    Pump2 = 2,
+    Pump3 = 3,
+    Pump4 = 4,
+    Pump5 = 5,
+    Blower = 6,
+    Light = 16,
+    TempUp = 21,
+    TempDown = 22,
+}
+
+impl KeyCode {
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
@@ -5,6 +5,7 @@ use rand::*;
 
 pub mod composer;
 pub mod datas;
+pub mod known_datas;
 pub mod object;
 mod object_macro;
 mod object_traits;
@@ -6,10 +6,17 @@ pub use crate::object_traits::*;
 
 pub use package_data::NetworkPackageData;
 
+/// The byte between the length and the config version in a
+/// [SetStatus](package_data::SetStatus), for packs which don't use another one.
+pub const DEFAULT_SET_STATUS_FRAMING: u8 = 0x46;
+
+/// The variant of a [NetworkPackageData], without its content.
+pub type NetworkPackageType = package_data::NetworkPackageDataDiscriminants;
+
 use crate::{static_cow, ToStatic};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct StatusChange<'a> {
     pub change: u16,
     pub data: Cow<'a, [u8; 2]>,
@@ -46,7 +53,7 @@ impl ToStatic for ReminderInfo {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, strum::FromRepr)]
-#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum ReminderIndex {
     Invalid = 0,
@@ -59,7 +66,7 @@ pub enum ReminderIndex {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReminderInfo {
     pub index: ReminderIndex,
     pub data: u16,
@@ -67,13 +74,37 @@ pub struct ReminderInfo {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, strum::FromRepr)]
-#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum WatercareType {
     Economy = 1,
     FilterCycle = 2,
 }
 
+/// A rule of a watercare mode, as listed by
+/// [WatercareRequest](package_data::WatercareRequest). The layout is the one used to add and
+/// modify rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct WatercareSchedule {
+    pub mode: u8,
+    pub r#type: WatercareType,
+    pub index: u8,
+    pub unknown: [u8; 2],
+    pub start_hour: u8,
+    pub start_minute: u8,
+    pub end_hour: u8,
+    pub end_minute: u8,
+}
+
+impl ToStatic for WatercareSchedule {
+    type Static = Self;
+
+    fn to_static(&self) -> Self {
+        self.clone()
+    }
+}
+
 pub struct StatusChangePlaceholder;
 
 impl<'a, const LENGTH: usize> ActualType for &'a [u8; LENGTH] {
@@ -116,6 +147,9 @@ pub mod package_data {
             seq: u8,
         },
         Packs( b"PACKS": Simple),
+        /// Some firmware versions append information about the installed packs to `PACKS`. The
+        /// layout of this data is not known yet, so it is kept as raw bytes.
+        PacksData(b"PACKS": Tailing),
         RadioError(b"RFERR": Simple),
         WaterQualityError(b"WCERR": Simple),
         Version {
@@ -137,9 +171,12 @@ pub mod package_data {
             seq: u8,
             pack_type: u8,
             /// This includes the length of all fields below, meaning it should be `5 +
-            /// data.len()`.
+            /// data.len()`. [SetStatus::new] sets it from the data, and composing a package
+            /// where it doesn't match panics in debug builds.
             len: u8,
-            b"\x46": Tag,
+            /// The byte between `len` and `config_version`, which is
+            /// [DEFAULT_SET_STATUS_FRAMING] on most packs.
+            framing: u8,
             config_version: u8,
             log_version: u8,
             pos: u16,
@@ -277,6 +314,44 @@ impl NetworkPackageData<'_> {
     }
 }
 
+/// The data doesn't fit in a [SetStatus](package_data::SetStatus).
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("SetStatus data of {0} bytes exceeds the maximum of {max} bytes", max = package_data::SetStatus::MAX_DATA_LEN)]
+pub struct SetStatusTooLong(pub usize);
+
+impl<'a> package_data::SetStatus<'a> {
+    /// The largest `data` which fits, since `len` also counts the five bytes before it.
+    pub const MAX_DATA_LEN: usize = u8::MAX as usize - 5;
+
+    /// Create a `SetStatus` with `len` set from `data`.
+    pub fn new(
+        seq: u8,
+        pack_type: u8,
+        framing: u8,
+        config_version: u8,
+        log_version: u8,
+        pos: u16,
+        data: Cow<'a, [u8]>,
+    ) -> Result<Self, SetStatusTooLong> {
+        let len = u8::try_from(data.len() + 5).map_err(|_| SetStatusTooLong(data.len()))?;
+        Ok(Self {
+            seq,
+            pack_type,
+            len,
+            framing,
+            config_version,
+            log_version,
+            pos,
+            data,
+        })
+    }
+
+    /// Whether `len` matches the length of `data`.
+    pub fn len_is_consistent(&self) -> bool {
+        usize::from(self.len) == self.data.len() + 5
+    }
+}
+
 impl ToStatic for NetworkPackageData<'_> {
     type Static = NetworkPackageData<'static>;
     fn to_static(&self) -> Self::Static {
@@ -291,7 +366,7 @@ impl ToStatic for NetworkPackage<'_> {
 }
 
 #[derive(Eq, Debug, PartialEq, Clone)]
-#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub enum NetworkPackage<'a> {
     Addressed {
         src: Option<Cow<'a, [u8]>>,
@@ -29,7 +29,7 @@ macro_rules! gen_packages {
   (FINISH_BUILD_STRUCT_ARGS $enum:ident $($struct_lifetime:lifetime)? $(#[$meta:meta])* $struct:ident { $($current:tt)* }) => {
       #[derive(Debug, PartialEq, Eq, Clone)]
       $(#[$meta])*
-      #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+      #[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
       pub struct $struct $(<$struct_lifetime>)? {
           $($current)*
       }
@@ -193,7 +193,7 @@ macro_rules! gen_packages {
   (WITH_TYPES_LIST $enum:ident [$($const:ident)*] [$($($life:lifetime)? $arg:ident)*] => $(#[$meta:meta])* $tailing:ident ( $verb:literal : Tailing ) $(,$($rest:tt)*)?) => {
     #[derive(Debug, PartialEq, Eq, Clone)]
     $(#[$meta])*
-    #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+    #[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
     pub struct $tailing<'a>(pub std::borrow::Cow<'a, [u8]>);
     impl $crate :: object :: dispatch :: DatasType for $tailing<'_> {
       type Group = $crate :: object :: dispatch :: Tailing;
@@ -241,7 +241,7 @@ macro_rules! gen_packages {
     #[derive(Default)]
     #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     $(#[$meta])*
-    #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+    #[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
     pub struct $simple;
     impl $crate::ToStatic for $simple {
         type Static = $simple;
@@ -266,8 +266,9 @@ macro_rules! gen_packages {
 
   // All members added, generate enum
   (WITH_TYPES_LIST $enum_name:ident [$($const:ident)*] [$($($life:lifetime)? $arg:ident)*] => $(,)?) => {
-    #[derive(Debug, PartialEq, Eq, Clone)]
-    #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+    #[derive(Debug, PartialEq, Eq, Clone, strum::EnumDiscriminants)]
+    #[strum_discriminants(derive(Hash, strum::EnumString, strum::IntoStaticStr))]
+    #[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
     pub enum $enum_name<'a> {
       $($const,)*
       $($arg($arg$(<$life>)?),)*
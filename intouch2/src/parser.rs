@@ -20,6 +20,36 @@ pub enum ParseError {
     },
 }
 
+/// A spa answer to `<HELLO>1</HELLO>` which isn't `{id}|{name}`.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum HelloError {
+    #[error("No '|' between the spa id and name: {}", String::from_utf8_lossy(.0))]
+    MissingDelimiter(Box<[u8]>),
+    #[error("Empty spa id: {}", String::from_utf8_lossy(.0))]
+    EmptyId(Box<[u8]>),
+}
+
+impl ParseError {
+    /// Describe the error for logs. Tailing data is parsed again, so that a package following the
+    /// first one is shown as a package, and anything else is shown as an `Unknown` package.
+    pub fn display(&self) -> String {
+        match self {
+            ParseError::Parser(e) => e.to_string(),
+            ParseError::TailingData { msg, tail } => {
+                let tail = match parse_network_data(tail) {
+                    Ok(package) => package.to_string(),
+                    Err(e @ ParseError::TailingData { .. }) => e.display(),
+                    Err(ParseError::Parser(_)) => {
+                        NetworkPackageData::Unknown(package_data::Unknown(Cow::Borrowed(tail)))
+                            .display()
+                    }
+                };
+                format!("Unexpected tailing data: {msg} followed by {tail}")
+            }
+        }
+    }
+}
+
 fn surrounded<'a>(
     before: &'a [u8],
     after: &'a [u8],
@@ -239,6 +269,65 @@ impl<'a> DatasContent<'a> for ReminderInfo {
     }
 }
 
+impl<'a> DatasContent<'a> for WatercareSchedule {
+    fn parse(input: &'a [u8]) -> nom::IResult<&'a [u8], Self> {
+        let (input, mode) = u8::parse(input)?;
+        let (input, r#type) = WatercareType::parse(input)?;
+        let (input, index) = u8::parse(input)?;
+        let (input, unknown) = take::<_, _, InnerNomError<'a>>(2usize)(input)?;
+        let (input, start_hour) = u8::parse(input)?;
+        let (input, start_minute) = u8::parse(input)?;
+        let (input, end_hour) = u8::parse(input)?;
+        let (input, end_minute) = u8::parse(input)?;
+        Ok((
+            input,
+            Self {
+                mode,
+                r#type,
+                index,
+                unknown: [unknown[0], unknown[1]],
+                start_hour,
+                start_minute,
+                end_hour,
+                end_minute,
+            },
+        ))
+    }
+
+    fn compose(&self) -> Cow<'a, [u8]> {
+        Cow::Owned(vec![
+            self.mode,
+            self.r#type as u8,
+            self.index,
+            self.unknown[0],
+            self.unknown[1],
+            self.start_hour,
+            self.start_minute,
+            self.end_hour,
+            self.end_minute,
+        ])
+    }
+}
+
+impl package_data::WatercareRequest<'_> {
+    /// Parse the watercare rules in the reply. The reply is assumed to be a list of rules, since
+    /// its layout hasn't been confirmed yet.
+    pub fn schedules(&self) -> Result<Vec<WatercareSchedule>, ParseError> {
+        let (_, schedules) = <Cow<[WatercareSchedule]> as DatasContent>::parse(&self.0)
+            .map_err(|x| ParseError::from(x.to_owned()))?;
+        Ok(schedules.into_owned())
+    }
+}
+
+impl package_data::ChannelCurrent {
+    /// The quality of the radio link between the spa and the in.touch2 module, in percent. The
+    /// raw `signal_strength` is the percentage which the app shows, so anything above 100 is
+    /// treated as a perfect signal.
+    pub fn signal_quality(&self) -> u8 {
+        self.signal_strength.min(100)
+    }
+}
+
 impl<'a, T: DatasContent<'a> + Clone> DatasContent<'a> for Cow<'a, [T]>
 where
     [T]: ToOwned,
@@ -277,3 +366,16 @@ pub fn parse_network_data<'a>(input: &'a [u8]) -> Result<NetworkPackage<'a>, Par
         }),
     }
 }
+
+/// Split the answer of a spa to `<HELLO>1</HELLO>` on the first `|`, into the id which the spa is
+/// addressed with and its name. The name may be empty.
+pub fn parse_hello_id_name(hello: &[u8]) -> Result<(&[u8], &[u8]), HelloError> {
+    let Some(pos) = hello.iter().position(|x| *x == b'|') else {
+        return Err(HelloError::MissingDelimiter(hello.into()));
+    };
+    let (id, name) = (&hello[..pos], &hello[pos + 1..]);
+    if id.is_empty() {
+        return Err(HelloError::EmptyId(hello.into()));
+    }
+    Ok((id, name))
+}
@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 
-use super::{composer::*, object::*, parser::*};
+use super::{composer::*, datas::*, object::*, parser::*};
 
 #[test]
 fn parse_hello() {
@@ -30,6 +30,18 @@ fn parse_new_ping() {
     ));
 }
 
+#[test]
+fn parse_packs() {
+    assert!(matches!(
+    NetworkPackageData::parse(b"PACKS"),
+    Ok(package) if package == (&[], NetworkPackageData::Packs),
+    ));
+    assert!(matches!(
+    NetworkPackageData::parse(b"PACKS\x01\x02"),
+    Ok(package) if package == (&[], package_data::PacksData(Cow::Borrowed(b"\x01\x02")).into()),
+    ));
+}
+
 #[test]
 fn parse_ping_and_pong() {
     let data = b"<PACKT><SRCCN>sender-id</SRCCN><DATAS>APING</DATAS></PACKT>";
@@ -157,3 +169,260 @@ fn id_packets() {
         //}
     }
 }
+
+#[test]
+fn tailing_data_display() {
+    let error = parse_network_data(b"<HELLO>1</HELLO><PACKT><DATAS>APING</DATAS></PACKT>")
+        .expect_err("Two packages should not parse as one");
+    assert_eq!(
+        error.display(),
+        "Unexpected tailing data: Hello(1) followed by Addressed(NULL, NULL, Ping)"
+    );
+    let error = parse_network_data(b"<HELLO>1</HELLO>NEWPKG")
+        .expect_err("Tailing data should not be accepted");
+    assert_eq!(
+        error.display(),
+        "Unexpected tailing data: Hello(1) followed by Unknown: NEWPKG"
+    );
+}
+
+#[test]
+fn set_status_framing() {
+    for framing in [DEFAULT_SET_STATUS_FRAMING, 0x47] {
+        let package = NetworkPackage::Addressed {
+            src: Some(b"client".as_slice().into()),
+            dst: Some(b"spa".as_slice().into()),
+            data: package_data::SetStatus::new(3, 10, framing, 1, 2, 0x100, Cow::Borrowed(&[4, 5]))
+                .unwrap()
+                .into(),
+        };
+        let composed = compose_network_data(&package);
+        assert!(matches!(parse_network_data(&composed), Ok(parsed) if parsed == package));
+    }
+}
+
+#[test]
+fn set_status_len() {
+    let set_status = package_data::SetStatus::new(
+        0,
+        10,
+        DEFAULT_SET_STATUS_FRAMING,
+        1,
+        2,
+        0,
+        Cow::Owned(vec![0; package_data::SetStatus::MAX_DATA_LEN]),
+    )
+    .unwrap();
+    assert_eq!(set_status.len, 255);
+    assert!(set_status.len_is_consistent());
+    assert!(!package_data::SetStatus {
+        len: 7,
+        ..set_status
+    }
+    .len_is_consistent());
+    assert_eq!(
+        package_data::SetStatus::new(
+            0,
+            10,
+            DEFAULT_SET_STATUS_FRAMING,
+            1,
+            2,
+            0,
+            Cow::Owned(vec![0; 251])
+        ),
+        Err(SetStatusTooLong(251))
+    );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "doesn't match")]
+fn set_status_inconsistent_len() {
+    let set_status = package_data::SetStatus::new(
+        0,
+        10,
+        DEFAULT_SET_STATUS_FRAMING,
+        1,
+        2,
+        0,
+        Cow::Borrowed(&[1, 2]),
+    )
+    .unwrap();
+    compose_network_data(&NetworkPackage::Addressed {
+        src: None,
+        dst: None,
+        data: package_data::SetStatus {
+            len: 5,
+            ..set_status
+        }
+        .into(),
+    });
+}
+
+#[test]
+fn watercare_schedules() {
+    let reply = package_data::WatercareRequest(Cow::Borrowed(
+        b"\x00\x02\x00\x00\x00\x08\x00\x0a\x00\x01\x01\x01\x00\x00\x16\x1e\x06\x00",
+    ));
+    assert_eq!(
+        reply.schedules().unwrap(),
+        [
+            WatercareSchedule {
+                mode: 0,
+                r#type: WatercareType::FilterCycle,
+                index: 0,
+                unknown: [0, 0],
+                start_hour: 8,
+                start_minute: 0,
+                end_hour: 10,
+                end_minute: 0,
+            },
+            WatercareSchedule {
+                mode: 1,
+                r#type: WatercareType::Economy,
+                index: 1,
+                unknown: [0, 0],
+                start_hour: 22,
+                start_minute: 30,
+                end_hour: 6,
+                end_minute: 0,
+            },
+        ]
+    );
+    assert!(
+        package_data::WatercareRequest(Cow::Borrowed(b"\x00\x02\x00"))
+            .schedules()
+            .is_err()
+    );
+    assert_eq!(
+        package_data::WatercareRequest(Cow::Borrowed(b""))
+            .schedules()
+            .unwrap(),
+        []
+    );
+}
+
+#[test]
+fn write_datas() {
+    let mut datas = GeckoDatas::new(4);
+    assert_eq!(datas.write(2, &[1, 2]), Ok(()));
+    assert_eq!(&datas[..], &[0, 0, 1, 2]);
+    assert_eq!(datas.pop_dirty(), Some(2..4));
+    assert_eq!(
+        datas.write(3, &[3, 4]),
+        Err(OutOfBounds {
+            pos: 3,
+            len: 2,
+            size: 4
+        })
+    );
+    assert_eq!(&datas[..], &[0, 0, 1, 2]);
+    assert_eq!(datas.pop_dirty(), None);
+}
+
+#[test]
+fn drain_dirty_coalesces() {
+    let mut datas = GeckoDatas::new(32);
+    datas[10..12].copy_from_slice(&[1, 2]);
+    datas[11..15].copy_from_slice(&[3, 4, 5, 6]);
+    assert_eq!(datas.drain_dirty(), vec![10..15]);
+    datas[11..15].copy_from_slice(&[3, 4, 5, 6]);
+    datas[20] = 7;
+    datas[15] = 8;
+    assert_eq!(datas.drain_dirty(), vec![11..16, 20..21]);
+    assert_eq!(datas.peek_dirty(), None);
+    assert_eq!(datas.drain_dirty(), vec![]);
+}
+
+#[test]
+fn package_type() {
+    assert_eq!(
+        NetworkPackageType::from(&NetworkPackageData::Ping),
+        NetworkPackageType::Ping
+    );
+    assert_eq!(
+        NetworkPackageType::from(&NetworkPackageData::from(package_data::GetWatercare {
+            seq: 1
+        })),
+        NetworkPackageType::GetWatercare
+    );
+    assert_eq!("PushStatus".parse(), Ok(NetworkPackageType::PushStatus));
+    assert_eq!(<&str>::from(NetworkPackageType::SetStatus), "SetStatus");
+}
+
+#[test]
+fn diff_snapshots() {
+    let mut datas = GeckoDatas::new(3);
+    let before = datas.snapshot();
+    datas[1] = 5;
+    let mut after = datas.snapshot();
+    assert_eq!(
+        before.diff(&after).collect::<Vec<_>>(),
+        [SnapshotDifference {
+            pos: 1,
+            old: Some(0),
+            new: Some(5)
+        }]
+    );
+    after.data = Box::from([0, 0, 0, 7]);
+    assert_eq!(
+        before.diff(&after).collect::<Vec<_>>(),
+        [SnapshotDifference {
+            pos: 3,
+            old: None,
+            new: Some(7)
+        }]
+    );
+}
+
+#[test]
+fn channel_signal() {
+    for (signal_strength, quality) in [(0, 0), (60, 60), (100, 100), (255, 100)] {
+        let current = package_data::ChannelCurrent {
+            channel: 10,
+            signal_strength,
+        };
+        assert_eq!(current.signal_quality(), quality);
+    }
+}
+
+#[test]
+fn list_known_datas() {
+    use super::known_datas::{known_datas, KnownData, KnownDataInfo, PrimaryColor};
+    let known = known_datas();
+    assert_eq!(known.len(), 3);
+    assert_eq!(known[0], KnownDataInfo::of::<PrimaryColor>());
+    assert_eq!(known[0].name, "PrimaryColor");
+    assert_eq!(known[0].range(), PrimaryColor::range());
+    for pair in known.windows(2) {
+        assert!(pair[0].range().end <= pair[1].range().start, "{pair:?}");
+    }
+}
+
+#[test]
+fn hello_id_name() {
+    assert_eq!(
+        parse_hello_id_name(b"SPA00:11|My spa"),
+        Ok((b"SPA00:11".as_slice(), b"My spa".as_slice()))
+    );
+    assert_eq!(
+        parse_hello_id_name(b"SPA|name|with|bars"),
+        Ok((b"SPA".as_slice(), b"name|with|bars".as_slice()))
+    );
+    assert_eq!(
+        parse_hello_id_name(b"SPA|"),
+        Ok((b"SPA".as_slice(), b"".as_slice()))
+    );
+    assert_eq!(
+        parse_hello_id_name(b"SPA"),
+        Err(HelloError::MissingDelimiter(Box::from(*b"SPA")))
+    );
+    assert_eq!(
+        parse_hello_id_name(b""),
+        Err(HelloError::MissingDelimiter(Box::from([])))
+    );
+    assert_eq!(
+        parse_hello_id_name(b"|name"),
+        Err(HelloError::EmptyId(Box::from(*b"|name")))
+    );
+}